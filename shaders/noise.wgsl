@@ -32,6 +32,8 @@ struct Locals {
 };
 @group(0) @binding(2) var<uniform> r_locals: Locals;
 @group(0) @binding(3) var<uniform> lights: array<Light, 32>;
+// How every cell of the world absorbs light, tint in rgb and opacity in a, see OcclusionMask
+@group(0) @binding(4) var r_occlusion: texture_2d<f32>;
 
 let tau: f32 = 6.283185307179586476925286766559;
 let bias: f32 = 0.2376; // Offset the circular time input so it is never 0
@@ -54,6 +56,38 @@ let glow_colour: vec3<f32> = vec3<f32>(1.0, 0.0, 0.0);
 let glow_intensity: f32 = 1.0;
 let glow_threshold: f32 = 0.5;
 
+// Has to match MAX_OCCLUSION_STEPS in occlusion.rs
+let MAX_OCCLUSION_STEPS: i32 = 128;
+
+// Part of the light of every color that passes the texel, the same as OcclusionMask::get_transmission
+fn load_transmission(texel: vec2<i32>) -> vec3<f32> {
+    let size = vec2<i32>(textureDimensions(r_occlusion));
+    let occlusion = textureLoad(r_occlusion, clamp(texel, vec2<i32>(0), size - 1), 0);
+    return mix(vec3<f32>(1.0), occlusion.rgb, occlusion.a);
+}
+
+// How much of every color of the light at light_pos reaches pixel (both texture coordinates), the same as OcclusionMask::get_visibility
+fn occlusion_visibility(pixel: vec2<f32>, light_pos: vec2<f32>) -> vec3<f32> {
+    let size = vec2<f32>(textureDimensions(r_occlusion));
+    let start = pixel * size;
+    let end = light_pos * size;
+    let start_texel = vec2<i32>(floor(start));
+    let end_texel = vec2<i32>(floor(end));
+    let steps = min(i32(ceil(distance(start, end))), MAX_OCCLUSION_STEPS);
+    var visibility = vec3<f32>(1.0);
+    for(var i: i32 = 1; i < steps; i++) {
+        let texel = vec2<i32>(floor(mix(start, end, f32(i) / f32(steps))));
+        if all(texel == start_texel) || all(texel == end_texel) {
+            continue;
+        };
+        visibility *= load_transmission(texel);
+        if all(visibility <= vec3<f32>(0.0)) {
+            break;
+        };
+    };
+    return visibility;
+}
+
 @fragment
 fn fs_main(@location(0) tex_coord: vec2<f32>) -> @location(0) vec4<f32> {
     var sampled_color = textureSample(r_tex_color, r_tex_sampler, tex_coord);
@@ -73,8 +107,12 @@ fn fs_main(@location(0) tex_coord: vec2<f32>) -> @location(0) vec4<f32> {
         // Linear Blending
         //light_color += light.color * (1.0 - light_dist / fall) * light.intensity;
 
-        // Max Blending
-        light_color = max(light_color, light.color * (1.0 - dist / fall) * light.intensity);
+        // Max Blending, the shadow march is only worth it where the light would show
+        let contribution = light.color * (1.0 - dist / fall) * light.intensity;
+        if all(contribution <= light_color) {
+            continue;
+        };
+        light_color = max(light_color, contribution * occlusion_visibility(tex_coord, light.position));
     };
     return vec4<f32>(sampled_color.rgb * light_color, sampled_color.a);
 }
\ No newline at end of file
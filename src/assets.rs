@@ -117,7 +117,11 @@ impl Assets {
         if let Some(tex) = tex {
             return self.get_color_from_texture_wrapped(pos, tex);
         };
-        COLOR_EMPTY
+        // Custom materials don't come with a texture
+        match material {
+            Material::Custom(_) => material.get_color(),
+            _ => COLOR_EMPTY,
+        }
     }
 
     fn get_texturepath_from_material(material: Material) -> PathBuf {
@@ -128,6 +132,12 @@ impl Assets {
             Material::Rock => "rock.png",
             Material::Smoke => "smoke.png",
             Material::Wood => "wood.png",
+            Material::Oil => "oil.png",
+            Material::BlackSmoke => "black_smoke.png",
+            Material::Ash => "ash.png",
+            Material::Ember => "ember.png",
+            Material::Emitter => "emitter.png",
+            Material::Drain => "drain.png",
             _ => "",
         };
         let mut path = std::env::current_dir().unwrap();
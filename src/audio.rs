@@ -0,0 +1,353 @@
+use std::{fs::File, io::{self, BufWriter, Seek, SeekFrom, Write}, path::Path};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::{Matrix, Material, MaterialType, SimEvent, SimEventKind, Rng};
+use crate::storage::{FREE_FALLING, ON_FIRE};
+
+
+pub const SAMPLE_RATE: u32 = 44100;
+/// Number of simulation updates per second the synthesizer assumes when rendering one tick
+pub const TICK_RATE: u32 = 60;
+/// Impact sounds playing at the same time, further impacts are dropped
+const MAX_IMPACT_VOICES: usize = 32;
+
+
+/// How much is going on in the world, gathered after an update
+#[derive(Clone, Default, Debug)]
+pub struct SoundActivity {
+    pub burning_cells: usize,
+    pub ignitions: usize,
+    pub flowing_liquid_cells: usize,
+    /// Speed of every impact of the last update
+    pub impacts: Vec<f32>,
+}
+
+impl SoundActivity {
+    /// Reads the state and the events of the last update. The events are not drained
+    pub fn from_matrix(matrix: &Matrix) -> Self {
+        let mut activity = SoundActivity::default();
+        let cells = matrix.get_cell_storage();
+        for (idx, material) in cells.material.iter().enumerate() {
+            if *material == Material::Empty {
+                continue;
+            };
+            let flags = cells.flags[idx];
+            if flags & ON_FIRE != 0 {
+                activity.burning_cells += 1;
+            };
+            if material.get_type() == MaterialType::Liquid && (flags & FREE_FALLING != 0 || cells.velocity[idx].length_squared() > 0.01) {
+                activity.flowing_liquid_cells += 1;
+            };
+        };
+        for event in matrix.get_events() {
+            match event {
+                SimEvent::Impact { speed, .. } => activity.impacts.push(*speed),
+                event if event.kind() == SimEventKind::Ignited => activity.ignitions += 1,
+                _ => {},
+            };
+        };
+        activity
+    }
+}
+
+/// A decaying sine wave used for impact thumps
+struct Voice {
+    phase: f32,
+    frequency: f32,
+    amplitude: f32,
+    decay: f32,
+}
+
+/// Turns simulation activity into a mono audio stream: fire crackle, flowing water and impact thumps
+pub struct SoundSynth {
+    sample_rate: u32,
+    rng: Rng,
+    pub volume: f32,
+
+    crackle_target: f32,
+    crackle_level: f32,
+    crackle_envelope: f32,
+
+    flow_target: f32,
+    flow_level: f32,
+    flow_filter: f32,
+    flow_wobble: f32,
+
+    voices: Vec<Voice>,
+}
+
+impl SoundSynth {
+    pub fn new(sample_rate: u32) -> Self {
+        SoundSynth {
+            sample_rate,
+            rng: Rng::with_seed(0),
+            volume: 0.8,
+
+            crackle_target: 0.0,
+            crackle_level: 0.0,
+            crackle_envelope: 0.0,
+
+            flow_target: 0.0,
+            flow_level: 0.0,
+            flow_filter: 0.0,
+            flow_wobble: 0.5,
+
+            voices: vec![],
+        }
+    }
+
+    pub fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Sets how loud the sound sources should be from now on and starts a thump for every impact
+    pub fn set_activity(&mut self, activity: &SoundActivity) {
+        self.crackle_target = ((activity.burning_cells as f32).ln_1p() / 8.0 + activity.ignitions as f32 * 0.01).min(1.0);
+        self.flow_target = ((activity.flowing_liquid_cells as f32).sqrt() / 60.0).min(1.0);
+
+        // Loud impacts first, in case there are too many to play all of them
+        let mut impacts = activity.impacts.clone();
+        impacts.sort_by(|a, b| b.total_cmp(a));
+        for speed in impacts {
+            if self.voices.len() >= MAX_IMPACT_VOICES {
+                break;
+            };
+            self.voices.push(Voice {
+                phase: 0.0,
+                frequency: 60.0 + self.rng.f32() * 140.0,
+                amplitude: (speed / 10.0).min(1.0) * 0.05,
+                decay: 0.9985,
+            });
+        };
+    }
+
+    /// Fills the buffer with the next samples (in -1.0..=1.0)
+    pub fn render(&mut self, out: &mut [f32]) {
+        let dt = 1.0 / self.sample_rate as f32;
+        for sample in out.iter_mut() {
+            // Smooth the levels so changes between updates don't click
+            self.crackle_level += (self.crackle_target - self.crackle_level) * 0.0005;
+            self.flow_level += (self.flow_target - self.flow_level) * 0.0005;
+
+            // Fire: sparse, short bursts of noise. More burning cells make them more frequent
+            if self.rng.f32() < self.crackle_level * 0.003 {
+                self.crackle_envelope = 0.3 + self.rng.f32() * 0.7;
+            };
+            let crackle = (self.rng.f32() * 2.0 - 1.0) * self.crackle_envelope;
+            self.crackle_envelope *= 0.995;
+
+            // Water: low passed noise with a slowly wandering cutoff
+            self.flow_wobble = (self.flow_wobble + (self.rng.f32() - 0.5) * 0.002).clamp(0.2, 1.0);
+            let noise = self.rng.f32() * 2.0 - 1.0;
+            self.flow_filter += (noise - self.flow_filter) * 0.08 * self.flow_wobble;
+            let flow = self.flow_filter * self.flow_level * 3.0;
+
+            // Impacts
+            let mut thumps = 0.0;
+            for voice in self.voices.iter_mut() {
+                thumps += (voice.phase * std::f32::consts::TAU).sin() * voice.amplitude;
+                voice.phase = (voice.phase + voice.frequency * dt).fract();
+                voice.amplitude *= voice.decay;
+            };
+
+            let mix = (crackle * 0.4 * self.crackle_level + flow + thumps) * self.volume;
+            *sample = mix.tanh();
+        };
+        self.voices.retain(|voice| voice.amplitude > 0.0005);
+    }
+
+    /// Renders the samples for one simulation update (1 / TICK_RATE seconds)
+    pub fn render_tick(&mut self) -> Vec<f32> {
+        let mut samples = vec![0.0; (self.sample_rate / TICK_RATE) as usize];
+        self.render(&mut samples);
+        samples
+    }
+}
+
+/// Somewhere the synthesized samples can be sent to
+pub trait AudioOutput {
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()>;
+}
+
+/// Writes the samples into a 16 bit mono WAV file
+pub struct WavOutput<W: Write + Seek> {
+    writer: W,
+    num_samples: u32,
+}
+
+impl WavOutput<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Self> {
+        WavOutput::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavOutput<W> {
+    /// Writes the WAV header. The sizes in it get filled in by WavOutput::finish
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<Self> {
+        writer.write_all(b"RIFF")?;
+        writer.write_u32::<LittleEndian>(0)?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_u32::<LittleEndian>(16)?;
+        // PCM, mono
+        writer.write_u16::<LittleEndian>(1)?;
+        writer.write_u16::<LittleEndian>(1)?;
+        writer.write_u32::<LittleEndian>(sample_rate)?;
+        writer.write_u32::<LittleEndian>(sample_rate * 2)?;
+        writer.write_u16::<LittleEndian>(2)?;
+        writer.write_u16::<LittleEndian>(16)?;
+        writer.write_all(b"data")?;
+        writer.write_u32::<LittleEndian>(0)?;
+        Ok(WavOutput {
+            writer,
+            num_samples: 0,
+        })
+    }
+
+    /// Fills in the sizes in the header and returns the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.num_samples * 2;
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_u32::<LittleEndian>(36 + data_size)?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_u32::<LittleEndian>(data_size)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write + Seek> AudioOutput for WavOutput<W> {
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            self.writer.write_i16::<LittleEndian>((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+        };
+        self.num_samples += samples.len() as u32;
+        Ok(())
+    }
+}
+
+/// Plays the samples on the default output device
+#[cfg(feature = "live-audio")]
+pub struct LiveOutput {
+    _stream: cpal::Stream,
+    sample_rate: u32,
+    queue: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<f32>>>,
+}
+
+#[cfg(feature = "live-audio")]
+impl LiveOutput {
+    pub fn new() -> Result<Self, String> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+        let device = cpal::default_host().default_output_device().ok_or("no output device")?;
+        let config = device.default_output_config().map_err(|e| e.to_string())?;
+        let sample_rate = config.sample_rate().0;
+        let channels = config.channels() as usize;
+        let queue = std::sync::Arc::new(std::sync::Mutex::new(std::collections::VecDeque::new()));
+
+        let stream_queue = queue.clone();
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => device.build_output_stream(
+                &config.into(),
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                    let mut queue = stream_queue.lock().unwrap();
+                    for frame in data.chunks_mut(channels) {
+                        frame.fill(queue.pop_front().unwrap_or(0.0));
+                    };
+                },
+                |err| log::error!("audio stream failed: {err}"),
+                None,
+            ),
+            cpal::SampleFormat::I16 => device.build_output_stream(
+                &config.into(),
+                move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
+                    let mut queue = stream_queue.lock().unwrap();
+                    for frame in data.chunks_mut(channels) {
+                        frame.fill((queue.pop_front().unwrap_or(0.0) * i16::MAX as f32) as i16);
+                    };
+                },
+                |err| log::error!("audio stream failed: {err}"),
+                None,
+            ),
+            format => return Err(format!("unsupported sample format {format}")),
+        }.map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+
+        Ok(LiveOutput {
+            _stream: stream,
+            sample_rate,
+            queue,
+        })
+    }
+
+    pub fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+#[cfg(feature = "live-audio")]
+impl AudioOutput for LiveOutput {
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(samples);
+        // Never lag behind more than a fifth of a second, drop the oldest samples instead
+        let max_len = self.sample_rate as usize / 5;
+        if queue.len() > max_len {
+            let excess = queue.len() - max_len;
+            queue.drain(..excess);
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use byteorder::ReadBytesExt;
+    use glam::IVec2;
+
+    use super::*;
+
+    #[test]
+    fn wav_header_has_the_sizes_and_sample_rate() {
+        let mut output = WavOutput::new(Cursor::new(Vec::new()), 22050).unwrap();
+        output.write_samples(&[0.0, 0.5, -0.5]).unwrap();
+        output.write_samples(&[1.0, -2.0]).unwrap();
+        let bytes = output.finish().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 5 * 2);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(&bytes[36..40], b"data");
+        let read_u32 = |offset: usize| (&bytes[offset..offset + 4]).read_u32::<LittleEndian>().unwrap();
+        assert_eq!(read_u32(4), 36 + 5 * 2);
+        assert_eq!(read_u32(24), 22050);
+        assert_eq!(read_u32(28), 22050 * 2);
+        assert_eq!(read_u32(40), 5 * 2);
+        let samples: Vec<i16> = bytes[44..].chunks(2).map(|mut b| b.read_i16::<LittleEndian>().unwrap()).collect();
+        assert_eq!(samples, [0, i16::MAX / 2, -(i16::MAX / 2), i16::MAX, -i16::MAX]);
+    }
+
+    #[test]
+    fn activity_matches_the_cells() {
+        let mut matrix = Matrix::new_empty(64, 64);
+        for x in 10..20 {
+            matrix.set_cell_material(IVec2::new(x, 10), Material::Wood, false);
+            matrix.set_cell_material(IVec2::new(x + 30, 50), Material::Water, false);
+        };
+        matrix.ignite(IVec2::new(10, 10));
+        for _ in 0..5 {
+            matrix.update();
+            let activity = SoundActivity::from_matrix(&matrix);
+            let burning = matrix.iter_cells().filter(|c| c.is_on_fire).count();
+            let flowing = matrix.iter_cells()
+                .filter(|c| c.material.get_type() == MaterialType::Liquid && (c.is_free_falling || c.velocity.length_squared() > 0.01))
+                .count();
+            assert_eq!(activity.burning_cells, burning);
+            assert_eq!(activity.flowing_liquid_cells, flowing);
+        };
+        assert!(SoundActivity::from_matrix(&matrix).burning_cells > 0);
+    }
+}
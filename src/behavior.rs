@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use glam::IVec2;
+use strum::IntoEnumIterator;
+
+use crate::cell_handler::{GasBehavior, LiquidBehavior, MovableSolidBehavior};
+use crate::{Color, Material, MaterialType, Matrix};
+
+
+/// Logic of a material, see BehaviorRegistry.
+///
+/// Burning, dying and material scripts are handled by cell_handler::handle_cell for every material, step is only about what
+/// the cell does on its own. All functions get the matrix and the position of the cell they are called for
+pub trait CellBehavior: Send + Sync {
+    /// Called once per update for every stepped cell of the material. Returns true if the cell moved or changed
+    fn step(&self, matrix: &mut Matrix, pos: IVec2) -> bool;
+
+    /// Called right after the cell caught fire
+    fn on_ignite(&self, _matrix: &mut Matrix, _pos: IVec2) {}
+
+    /// Called when the cell tried to move but ran into the cell at other
+    fn on_contact(&self, _matrix: &mut Matrix, _pos: IVec2, _other: IVec2) {}
+}
+
+/// Cells which stay where they are, like rock and wood
+pub struct StaticBehavior;
+
+impl CellBehavior for StaticBehavior {
+    fn step(&self, _matrix: &mut Matrix, _pos: IVec2) -> bool {
+        false
+    }
+}
+
+/// Which CellBehavior every material uses. By default that depends on the MaterialType,
+/// custom materials (see Material::register_custom) use the behavior of their base until they get their own
+pub struct BehaviorRegistry {
+    /// Indexed by Material::get_id, custom materials without a behavior of their own have no entry
+    behaviors: Vec<Option<Arc<dyn CellBehavior>>>,
+}
+
+impl BehaviorRegistry {
+    pub fn new() -> Self {
+        let behaviors = Material::iter()
+            .map(|material| -> Arc<dyn CellBehavior> {
+                match material.get_type() {
+                    MaterialType::MovableSolid => Arc::new(MovableSolidBehavior),
+                    MaterialType::Liquid => Arc::new(LiquidBehavior),
+                    MaterialType::Gas => Arc::new(GasBehavior),
+                    MaterialType::Empty | MaterialType::Solid => Arc::new(StaticBehavior),
+                }
+            })
+            .map(Some)
+            .collect();
+        BehaviorRegistry { behaviors }
+    }
+
+    /// Makes the material use behavior from now on, replacing whatever it used before
+    pub fn register(&mut self, material: Material, behavior: Box<dyn CellBehavior>) {
+        let id = material.get_id() as usize;
+        if id >= self.behaviors.len() {
+            self.behaviors.resize(id + 1, None);
+        };
+        self.behaviors[id] = Some(Arc::from(behavior));
+    }
+
+    /// Adds a new material with its own behavior, see Material::register_custom. None if the material couldn't be registered
+    pub fn register_material(&mut self, name: &'static str, color: Color, base: Material, behavior: Box<dyn CellBehavior>) -> Option<Material> {
+        let material = Material::register_custom(name, color, base)?;
+        self.register(material, behavior);
+        Some(material)
+    }
+
+    /// The behavior of the material. It's shared, so it can be called while the matrix gets changed
+    pub fn get(&self, material: Material) -> Arc<dyn CellBehavior> {
+        match self.behaviors.get(material.get_id() as usize) {
+            Some(Some(behavior)) => behavior.clone(),
+            _ => self.get(material.get_base()),
+        }
+    }
+}
+
+impl Default for BehaviorRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static STEPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counting;
+
+    impl CellBehavior for Counting {
+        fn step(&self, _matrix: &mut Matrix, _pos: IVec2) -> bool {
+            STEPS.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    #[test]
+    fn custom_materials_get_stepped() {
+        let mut matrix = Matrix::new_empty(64, 64);
+        let color = Color { r: 1.0, g: 0.0, b: 1.0, a: 1.0 };
+        let counted = matrix.behaviors.register_material("test_counted", color, Material::Sand, Box::new(Counting)).unwrap();
+        // Without a behavior of its own a custom material acts like its base
+        let falling = Material::register_custom("test_falling", color, Material::Sand).unwrap();
+        matrix.set_cell_material(IVec2::new(10, 10), counted, false);
+        matrix.set_cell_material(IVec2::new(20, 10), falling, false);
+        matrix.update();
+
+        assert_eq!(STEPS.load(Ordering::Relaxed), 1);
+        assert_eq!(matrix.get_material(IVec2::new(10, 10)), counted);
+        assert_eq!(matrix.get_material(IVec2::new(20, 10)), Material::Empty);
+        assert_eq!(matrix.iter_cells().filter(|c| c.material == falling).count(), 1);
+        assert_eq!(matrix.get_cell(IVec2::new(10, 10)).unwrap().color, color);
+    }
+}
@@ -0,0 +1,278 @@
+//! Runs the simulation without a window.
+//!
+//! Usage: headless [--ticks N] [--world PATH] [--seed N] [--replay PATH] [--scenario PATH] [--host ADDR [--peers N] | --join ADDR | --control ADDR] [--wav PATH] [--check] [--profile PATH]
+
+use std::time::Duration;
+
+use glam::IVec2;
+
+use falling_rust::{Matrix, Material, Emitter, Replay, ReplayPlayer, LockstepSession, ControlServer, SoundSynth, SoundActivity, AudioOutput, WavOutput, WIDTH, HEIGHT, audio::SAMPLE_RATE, script::DEFAULT_MATERIAL_SCRIPT_DIR};
+
+
+struct Args {
+    ticks: u32,
+    world: Option<String>,
+    /// Seed of the RNG, runs with the same seed and world end up the same
+    seed: Option<u64>,
+    /// Replay to play back instead of simulating the world, its length replaces the tick count
+    replay: Option<String>,
+    /// Scenario script that runs alongside the simulation, see Scripts
+    scenario: Option<String>,
+    /// Address to host a lockstep session on, the ticks start once `peers` clients joined
+    host: Option<String>,
+    peers: usize,
+    /// Address of a lockstep host to join, runs until the host leaves
+    join: Option<String>,
+    /// Address to serve the control API on, see ControlServer. Starts paused and runs until a client sends quit
+    control: Option<String>,
+    wav: Option<String>,
+    /// Validate the matrix after every update
+    check: bool,
+    /// Where the stage timings of the last ticks get written to as CSV
+    profile: Option<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        ticks: 600,
+        world: None,
+        seed: None,
+        replay: None,
+        scenario: None,
+        host: None,
+        peers: 1,
+        join: None,
+        control: None,
+        wav: None,
+        check: false,
+        profile: None,
+    };
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--ticks" => args.ticks = value()?.parse().map_err(|e| format!("invalid tick count: {}", e))?,
+            "--world" => args.world = Some(value()?),
+            "--seed" => args.seed = Some(value()?.parse().map_err(|e| format!("invalid seed: {}", e))?),
+            "--replay" => args.replay = Some(value()?),
+            "--scenario" => args.scenario = Some(value()?),
+            "--host" => args.host = Some(value()?),
+            "--peers" => args.peers = value()?.parse().map_err(|e| format!("invalid peer count: {}", e))?,
+            "--join" => args.join = Some(value()?),
+            "--control" => args.control = Some(value()?),
+            "--wav" => args.wav = Some(value()?),
+            "--check" => args.check = true,
+            "--profile" => args.profile = Some(value()?),
+            _ => return Err(format!("unknown argument {}", arg)),
+        };
+    };
+    if args.host.is_some() && args.join.is_some() {
+        return Err("--host and --join can't be combined".to_string());
+    };
+    if args.replay.is_some() && (args.host.is_some() || args.join.is_some()) {
+        return Err("a replay can't be played back in a lockstep session".to_string());
+    };
+    if args.control.is_some() && (args.replay.is_some() || args.host.is_some() || args.join.is_some()) {
+        return Err("--control can't be combined with a replay or a lockstep session".to_string());
+    };
+    Ok(args)
+}
+
+/// A small scene with something to hear: burning wood, pouring water and falling sand
+fn demo_world() -> Matrix {
+    let mut matrix = Matrix::new_empty(WIDTH as usize, HEIGHT as usize);
+    let (width, height) = (WIDTH as i32, HEIGHT as i32);
+    for x in 0..width {
+        for y in height - 20..height {
+            matrix.set_cell_material(IVec2::new(x, y), Material::Rock, false);
+        };
+    };
+    for x in 60..160 {
+        for y in height - 80..height - 20 {
+            matrix.set_cell_material(IVec2::new(x, y), Material::Wood, false);
+        };
+    };
+    for x in 60..160 {
+        if let Some(mut cell) = matrix.get_cell_mut(IVec2::new(x, height - 80)) {
+            cell.is_on_fire = true;
+        };
+    };
+    matrix.place_emitter(IVec2::new(300, 40), Emitter::new(Material::Water, 1.0));
+    for x in 380..440 {
+        for y in 40..100 {
+            matrix.set_cell_material(IVec2::new(x, y), Material::Sand, false);
+        };
+    };
+    matrix
+}
+
+fn main() {
+    env_logger::init();
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("Usage: headless [--ticks N] [--world PATH] [--seed N] [--replay PATH] [--scenario PATH] [--host ADDR [--peers N] | --join ADDR | --control ADDR] [--wav PATH] [--check] [--profile PATH]");
+            std::process::exit(2);
+        },
+    };
+
+    let mut player = args.replay.as_ref().map(|path| {
+        let replay = Replay::load(path).unwrap_or_else(|err| {
+            eprintln!("Could not load {}: {}", path, err);
+            std::process::exit(1);
+        });
+        ReplayPlayer::new(replay)
+    });
+
+    // A replay brings its own world
+    let mut matrix = match (&args.world, &player) {
+        (_, Some(_)) => Matrix::new_empty(WIDTH as usize, HEIGHT as usize),
+        (Some(path), None) => {
+            let mut matrix = Matrix::new_empty(WIDTH as usize, HEIGHT as usize);
+            if let Err(err) = matrix.load_world(path) {
+                eprintln!("Could not load {}: {}", path, err);
+                std::process::exit(1);
+            };
+            matrix
+        },
+        (None, None) => demo_world(),
+    };
+    if let Some(player) = &mut player {
+        if let Err(err) = player.start(&mut matrix) {
+            eprintln!("Could not start the replay: {}", err);
+            std::process::exit(1);
+        };
+    } else if let Some(seed) = args.seed {
+        matrix.seed_rng(seed);
+    };
+    matrix.scripts.load_materials(DEFAULT_MATERIAL_SCRIPT_DIR);
+    if let Some(path) = &args.scenario {
+        if let Err(err) = matrix.load_scenario(path) {
+            eprintln!("Could not start the scenario: {}", err);
+            std::process::exit(1);
+        };
+    };
+
+    // The host sends its world to the clients, which wait for it
+    let mut lockstep = match (&args.host, &args.join) {
+        (Some(addr), _) => {
+            let mut session = LockstepSession::host(addr, &mut matrix).unwrap_or_else(|err| {
+                eprintln!("Could not host on {}: {}", addr, err);
+                std::process::exit(1);
+            });
+            println!("Waiting for {} clients on {}", args.peers, addr);
+            while session.get_num_peers() < args.peers {
+                session.poll(&mut matrix);
+                std::thread::sleep(Duration::from_millis(10));
+            };
+            Some(session)
+        },
+        (None, Some(addr)) => Some(LockstepSession::join(addr, &mut matrix).unwrap_or_else(|err| {
+            eprintln!("Could not join {}: {}", addr, err);
+            std::process::exit(1);
+        })),
+        (None, None) => None,
+    };
+
+    let mut control = args.control.as_ref().map(|addr| {
+        let server = ControlServer::bind(addr).unwrap_or_else(|err| {
+            eprintln!("Could not serve the control API on {}: {}", addr, err);
+            std::process::exit(1);
+        });
+        println!("Serving the control API on {}", addr);
+        server
+    });
+    let mut paused = control.is_some();
+
+    matrix.check_consistency = args.check;
+    matrix.profiler.enabled = args.profile.is_some();
+    let mut num_violations = 0;
+
+    let mut audio = args.wav.as_ref().map(|path| {
+        let output = WavOutput::create(path, SAMPLE_RATE).unwrap_or_else(|err| {
+            eprintln!("Could not create {}: {}", path, err);
+            std::process::exit(1);
+        });
+        (SoundSynth::new(SAMPLE_RATE), output)
+    });
+
+    let mut ticks = 0;
+    loop {
+        if let Some(player) = &mut player {
+            if !player.step(&mut matrix) {
+                break;
+            };
+        } else if let Some(session) = &mut lockstep {
+            if session.is_host() && ticks >= args.ticks {
+                break;
+            };
+            let num_updates = session.update(&mut matrix);
+            if num_updates == 0 {
+                if !session.is_connected() {
+                    break;
+                };
+                std::thread::sleep(Duration::from_millis(1));
+                continue;
+            };
+            ticks += num_updates as u32 - 1;
+        } else if let Some(server) = &mut control {
+            server.poll(&mut matrix, &mut paused);
+            if server.is_quit_requested() {
+                break;
+            };
+            if paused {
+                std::thread::sleep(Duration::from_millis(1));
+                continue;
+            };
+            matrix.update();
+        } else if ticks < args.ticks {
+            matrix.update();
+        } else {
+            break;
+        };
+        ticks += 1;
+        matrix.profiler.end_frame();
+        num_violations += matrix.get_consistency_violations().len();
+        if let Some((synth, output)) = &mut audio {
+            synth.set_activity(&SoundActivity::from_matrix(&matrix));
+            output.write_samples(&synth.render_tick()).expect("could not write audio");
+        };
+    };
+
+    if let Some(session) = &mut lockstep {
+        // The last ticks of the clients have to arrive before their hashes can be compared
+        while session.get_num_peers() > 0 && !session.is_caught_up(&matrix) {
+            session.poll(&mut matrix);
+            std::thread::sleep(Duration::from_millis(1));
+        };
+    };
+
+    if let Some((_, output)) = audio {
+        output.finish().expect("could not write audio");
+        println!("Wrote {}", args.wav.unwrap_or_default());
+    };
+    if let Some(path) = &args.profile {
+        if let Err(err) = matrix.profiler.save_csv(path) {
+            eprintln!("Could not write {}: {}", path, err);
+            std::process::exit(1);
+        };
+        println!("Wrote {}", path);
+    };
+    println!("Simulated {} ticks, {} cells left, state hash {:016x}", ticks, matrix.iter_cells().count(), matrix.get_state_hash());
+    if let Some(session) = &lockstep {
+        match session.get_desync() {
+            Some(tick) => {
+                println!("Desync at tick {}", tick);
+                std::process::exit(1);
+            },
+            None => println!("In sync with the lockstep session"),
+        };
+    };
+    if args.check {
+        println!("Found {} inconsistencies", num_violations);
+        if num_violations > 0 {
+            std::process::exit(1);
+        };
+    };
+}
@@ -0,0 +1,72 @@
+use strum_macros::EnumIter;
+
+
+/// What happens to cells at one edge of the world
+#[derive(Clone, Copy, PartialEq, Eq, EnumIter, Debug)]
+pub enum BoundaryMode {
+    /// The edge behaves like an invisible, indestructible wall
+    Wall,
+    /// Cells leaving through this edge come back in at the opposite edge
+    Wrap,
+    /// Cells leaving through this edge are deleted
+    Void,
+}
+
+/// The boundary mode of every edge of the world
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Boundaries {
+    pub left: BoundaryMode,
+    pub right: BoundaryMode,
+    pub top: BoundaryMode,
+    pub bottom: BoundaryMode,
+}
+
+/// Makes both edges of an axis wrap or neither of them
+fn normalize_pair(pair: (BoundaryMode, BoundaryMode), was_wrapping: bool) -> (BoundaryMode, BoundaryMode) {
+    match pair {
+        (BoundaryMode::Wrap, BoundaryMode::Wrap) => pair,
+        (BoundaryMode::Wrap, other) | (other, BoundaryMode::Wrap) => {
+            if was_wrapping {
+                (other, other)
+            } else {
+                (BoundaryMode::Wrap, BoundaryMode::Wrap)
+            }
+        },
+        _ => pair,
+    }
+}
+
+impl Boundaries {
+    /// Every edge uses the same mode
+    pub fn all(mode: BoundaryMode) -> Self {
+        Boundaries {
+            left: mode,
+            right: mode,
+            top: mode,
+            bottom: mode,
+        }
+    }
+
+    /// Wrapping only makes sense in pairs, so if one edge of an axis wraps the opposite one wraps too.
+    ///
+    /// previous is used to tell which edge of a pair got changed: if a wrapping edge stops wrapping, its partner follows it
+    pub fn normalized(mut self, previous: &Boundaries) -> Self {
+        (self.left, self.right) = normalize_pair((self.left, self.right), previous.left == BoundaryMode::Wrap);
+        (self.top, self.bottom) = normalize_pair((self.top, self.bottom), previous.top == BoundaryMode::Wrap);
+        self
+    }
+
+    pub fn wraps_horizontally(&self) -> bool {
+        self.left == BoundaryMode::Wrap
+    }
+
+    pub fn wraps_vertically(&self) -> bool {
+        self.top == BoundaryMode::Wrap
+    }
+}
+
+impl Default for Boundaries {
+    fn default() -> Self {
+        Self::all(BoundaryMode::Wall)
+    }
+}
@@ -1,6 +1,6 @@
-use strum::IntoEnumIterator;
+use glam::Vec2;
 
-use crate::Material;
+use crate::{Material, Emitter};
 
 
 
@@ -8,6 +8,11 @@ pub struct Brush {
     pub size: u16,
     pub material_index: usize,
     pub place_fire: bool,
+    /// When true, the brush paints `force` into the force field instead of placing cells
+    pub place_force: bool,
+    pub force: Vec2,
+    /// Configuration given to placed emitter cells
+    pub emitter: Emitter,
 }
 
 impl Brush {
@@ -16,24 +21,27 @@ impl Brush {
             size: 35,
             material_index: 0,
             place_fire: false,
+            place_force: false,
+            force: Vec2::new(0.3, 0.0),
+            emitter: Emitter::default(),
         }
     }
 
     /// Converts the brush_material_index to a Material
     pub fn get_material_from_index(&self) -> Material {
-        Material::iter().nth(self.material_index).unwrap()
+        Material::all().get(self.material_index).copied().unwrap_or(Material::Empty)
     }
 
     pub fn increase_material_index(&mut self) {
         self.material_index += 1;
-        if self.material_index >= Material::iter().count() {
+        if self.material_index >= Material::all().len() {
             self.material_index = 0;
         };
     }
 
     pub fn decrease_material_index(&mut self) {
         if self.material_index == 0 {
-            self.material_index = Material::iter().count() - 1;
+            self.material_index = Material::all().len() - 1;
         } else {
             self.material_index -= 1;
         };
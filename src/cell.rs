@@ -1,72 +1,99 @@
 use std::{fmt::Display};
 
-use crate::{Color, darken_color, gen_range};
+use crate::{Color, darken_color, gen_range, glow::{self, MIN_GLOW_HEAT}};
 use glam::{IVec2, Vec2};
 
-use crate::{Material, MaterialType, rand_multiplier, Rng};
+use crate::{Material, MaterialType, Rng};
 
 
+/// A single cell. Inside the matrix cells are kept in a CellStorage, this is the view handed out by Matrix::get_cell
 #[derive(Clone, PartialEq)]
 pub struct Cell {
-    pub pos: IVec2,//2xi32
-    prev_pos: IVec2,//2xi32
-    pub velocity: Vec2,//2xf32
-    pub hp: u64,//u32
-    pub base_color: Color,//3xu8
-    pub color: Color,//3xu8
-    pub material: Material,//u8
+    pub pos: IVec2,
+    pub velocity: Vec2,
+    /// Fractional part of the position which hasn't been moved yet (always within -0.5..=0.5)
+    pub subpixel: Vec2,
+    pub hp: u16,
+    pub base_color: Color,
+    pub color: Color,
+    pub material: Material,
     pub processed_this_frame: bool,
     pub is_free_falling: bool,
     pub is_on_fire: bool,
     pub was_on_fire_last_frame: bool,
+    /// How hot the cell glows (0 - 1). Rises while burning and cools down afterwards
+    pub heat: f32,
+    /// Smoothed noise (-1 - 1) which makes the glow flicker
+    pub(crate) flicker: f32,
 }
-// = 284 bits = 4,4 u64's
+// = 104 bytes, the CellStorage only needs 33 bytes per grid position
 
 impl Cell {
     /// Creates a new cell with the specified material
     pub fn new(pos: IVec2, material: Material) -> Self {
         Self {
             pos,
-            prev_pos: pos,
             velocity: Vec2::ZERO,
+            subpixel: Vec2::ZERO,
             material,
             hp: material.get_hp(),
             base_color: material.get_color(),
             color: material.get_color(),
             processed_this_frame: false,
             is_free_falling: true,
-            is_on_fire: false,
+            is_on_fire: material.is_always_burning(),
             was_on_fire_last_frame: false,
+            heat: 0.0,
+            flicker: 0.0,
         }
     }
 
-    /// Updates the cells properties
-    pub fn update(&mut self) {
-        self.velocity += Vec2::new(0.0, 0.5);
+    /// Updates the cells properties. Acceleration is the sum of gravity and all forces acting on the cell
+    pub fn update(&mut self, acceleration: Vec2, rng: &Rng) {
+        self.velocity = accelerate(self.velocity, acceleration, self.material);
         
+        let was_hot = self.heat > 0.0;
         if self.is_on_fire {
             self.hp = self.hp.saturating_sub(1);
-            self.color = Color {r: 1.0, g: 0.25 + 0.25 * rand_multiplier() as f64, b: 0.0, a: 1.0}
-        } else if self.was_on_fire_last_frame {
-            self.color = darken_color(self.base_color, self.hp as f64 / self.material.get_hp() as f64);
+            // Fresh fires burn hot, cells which are almost burnt out only smoulder
+            let fuel = self.hp as f32 / self.material.get_hp() as f32;
+            let target_heat = 0.45 + 0.55 * fuel;
+            self.heat += (target_heat - self.heat) * 0.3;
+        } else {
+            self.heat *= 0.93;
+            if self.heat < MIN_GLOW_HEAT {
+                self.heat = 0.0;
+            };
+        };
+        if self.heat > 0.0 || was_hot {
+            let charred = darken_color(self.base_color, self.hp as f64 / self.material.get_hp() as f64);
+            if self.heat > 0.0 {
+                self.flicker = self.flicker * 0.7 + (gen_range(rng, 0.0, 1.0) * 2.0 - 1.0) * 0.3;
+                self.color = glow::glow_color(charred, (self.heat * (1.0 + 0.25 * self.flicker)).clamp(0.0, 1.0));
+            } else {
+                self.color = charred;
+            };
         };
         self.was_on_fire_last_frame = self.is_on_fire;
     }
     
-    /// Updates the cells properties after the cells has been handled by the cellhandler
-    pub fn post_update(&mut self) {
-        self.is_free_falling = self.pos.y != self.prev_pos.y;
-        self.prev_pos = self.pos;
+    /// Advances the sub-pixel position by the velocity and returns the whole pixels the cell should move this frame.
+    ///
+    /// The remaining fraction is kept, so slow cells move a pixel every few frames instead of never or every frame
+    pub fn integrate(&mut self) -> IVec2 {
+        integrate(self.velocity, &mut self.subpixel)
+    }
+
+    /// Brings the cell to a complete rest
+    pub fn stop(&mut self) {
+        self.velocity = Vec2::ZERO;
+        self.subpixel = Vec2::ZERO;
     }
 
     /// Tries to set a neighbouring cells "is_free_falling" to true based on inertia and that cells intertial resistance
-    pub fn attempt_free_fall(&mut self) {
-        if self.material.get_type() == MaterialType::MovableSolid {
-            let chance = self.material.get_intertial_resistance();
-            let rng = gen_range(0.0, 1.0);
-            if rng > chance {
-                self.is_free_falling = true;
-            };
+    pub fn attempt_free_fall(&mut self, rng: &Rng) {
+        if starts_falling(self.material, rng) {
+            self.is_free_falling = true;
         };
     }
 
@@ -76,6 +103,24 @@ impl Cell {
     }
 }
 
+/// Adds the acceleration to the velocity, limited by the terminal velocity of the material
+pub(crate) fn accelerate(velocity: Vec2, acceleration: Vec2, material: Material) -> Vec2 {
+    (velocity + acceleration).clamp_length_max(material.get_terminal_velocity())
+}
+
+/// See Cell::integrate
+pub(crate) fn integrate(velocity: Vec2, subpixel: &mut Vec2) -> IVec2 {
+    let target = *subpixel + velocity;
+    let displacement = target.round();
+    *subpixel = target - displacement;
+    displacement.as_ivec2()
+}
+
+/// Whether a resting cell of that material gets pulled along by a falling neighbour, decided at random by its inertial resistance
+pub(crate) fn starts_falling(material: Material, rng: &Rng) -> bool {
+    material.get_type() == MaterialType::MovableSolid && gen_range(rng, 0.0, 1.0) > material.get_intertial_resistance()
+}
+
 impl Display for Cell {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?} at {}, {}", self.material, self.pos, self.velocity)
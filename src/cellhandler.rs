@@ -3,253 +3,399 @@ pub mod cell_handler {
     use glam::{IVec2, Vec2};
     use fastrand::shuffle;
 
-    use crate::{Matrix, MaterialType, rand_multiplier, Material, Assets, Cell, Rng, gen_range, RNG};
+    use crate::storage::{FREE_FALLING, ON_FIRE, WAS_ON_FIRE};
+    use crate::profiler::Stage;
+    use crate::behavior::CellBehavior;
+    use crate::{BoundaryMode, SimEvent, Matrix, MaterialType, rand_multiplier, Material, Assets, Rng, gen_range};
 
     /// Function which gets called for all the cells.
     /// 
     /// Calls the respective methods depending on the cell material
-    pub fn handle_cell(matrix: &mut Matrix, cell_index: usize, chunk_index: usize) {
+    pub fn handle_cell(matrix: &mut Matrix, cellpos: IVec2, chunk_index: usize) {
         // if !matrix.chunks[chunk_index].should_step {
         //     return;
         // };
 
-        let cell = matrix.get_cell_by_cellindex_mut(cell_index);
-        if cell.is_none() {
-            return;
-        };
-        let (cellpos, hp, on_fire, was_on_fire, cellmat, hp_changed, cellvelocity) = {
-            let cell = cell.unwrap();
-            let hp = cell.hp;
-            let cellvel = cell.velocity;
-            cell.update();
-            cell.processed_this_frame = true;
-            (cell.pos, cell.hp, cell.is_on_fire, cell.was_on_fire_last_frame, cell.material, hp != cell.hp, cellvel)
+        // The cell has already been updated (and accelerated) once this frame by Matrix::step_all
+        let storage = matrix.get_cell_storage();
+        let idx = match matrix.get_storage_index(cellpos) {
+            Some(idx) if storage.is_occupied(idx) => idx,
+            _ => return,
         };
-        // if on_fire || was_on_fire || hp_changed || cellvelocity.length() > 0.0 {
-        //     matrix.set_chunk_active(cellpos);
-        // };
+        let (hp, flags, cellmat) = (storage.hp[idx], storage.flags[idx], storage.material[idx]);
+        let (on_fire, was_on_fire) = (flags & ON_FIRE != 0, flags & WAS_ON_FIRE != 0);
         
-        // This cell died; delete it
-        if hp == 0 {
+        // Cells like embers only exist while they are burning
+        if cellmat.is_always_burning() && !on_fire {
             matrix.set_cell_material(cellpos, Material::Empty, false);
+            matrix.push_event(SimEvent::CellDestroyed { pos: cellpos, material: cellmat });
+            return;
+        };
+
+        // This cell died; replace it with whatever it leaves behind
+        if hp == 0 {
+            if on_fire || was_on_fire {
+                leave_burn_residue(matrix, cellpos, cellmat);
+            } else {
+                matrix.set_cell_material(cellpos, Material::Empty, false);
+                matrix.push_event(SimEvent::CellDestroyed { pos: cellpos, material: cellmat });
+            };
             return;
         };
 
         if was_on_fire && on_fire {
-            fire_step(matrix, cell_index);
+            let fire_start = matrix.profiler.start();
+            fire_step(matrix, cellpos);
+            matrix.profiler.stop(Stage::Fire, fire_start);
         };
 
-        let _ = match cellmat.get_type() {
-            MaterialType::MovableSolid => movable_solid_step(matrix, cell_index),
-            MaterialType::Liquid => liquid_step(matrix, cell_index),
-            MaterialType::Gas => gas_step(matrix, cell_index),
-            _ => false,
+        // A material script runs before the built-in behaviour and may replace it
+        if matrix.scripts.has_material_script(cellmat) && (matrix.run_material_script(cellpos) || matrix.get_material(cellpos) != cellmat) {
+            return;
         };
+
+        let movement_start = matrix.profiler.start();
+        matrix.behaviors.get(cellmat).step(matrix, cellpos);
+        matrix.profiler.stop(Stage::Movement, movement_start);
+    }
+
+    /// Movable solids like sand fall down, then diagonally down, see movable_solid_step
+    pub struct MovableSolidBehavior;
+
+    impl CellBehavior for MovableSolidBehavior {
+        fn step(&self, matrix: &mut Matrix, pos: IVec2) -> bool {
+            movable_solid_step(matrix, pos)
+        }
+    }
+
+    /// Liquids move like movable solids and then sideways, see liquid_step
+    pub struct LiquidBehavior;
+
+    impl CellBehavior for LiquidBehavior {
+        fn step(&self, matrix: &mut Matrix, pos: IVec2) -> bool {
+            liquid_step(matrix, pos)
+        }
+    }
+
+    /// Gases rise against gravity and get carried along by the force field, see gas_step
+    pub struct GasBehavior;
+
+    impl CellBehavior for GasBehavior {
+        fn step(&self, matrix: &mut Matrix, pos: IVec2) -> bool {
+            gas_step(matrix, pos)
+        }
+    }
+
+    /// Slower hits than this don't count as impacts (see SimEvent::Impact)
+    const MIN_IMPACT_SPEED: f32 = 1.0;
+
+    /// Part of the fall speed which gets turned into sideways speed when a movable solid hits the ground
+    const IMPACT_SPREAD: f32 = 0.25;
+
+    /// Returns the offset which goes `down` steps along dir and `side` steps perpendicular to it.
+    ///
+    /// With dir = (0, 1) (regular gravity) this is simply (-side, down)
+    fn directional_offset(dir: Vec2, down: f32, side: f32) -> IVec2 {
+        (dir * down + dir.perp() * side).round().as_ivec2()
     }
 
     /// Handles the cell logic for movable solids like sand (first down then diagonally down)
-    fn movable_solid_step(matrix: &mut Matrix, cell_index: usize) -> bool {
-        let mut bottom = IVec2::new(0, 1);
-        let mut is_movable_solid = true;
-        {
-            let cell = matrix.get_cell_by_cellindex_mut(cell_index);
-            if cell.is_none() {
-                return false;
-            };
-            let (freefall, cellp) = {
-                let cell = cell.unwrap();
-                bottom = cell.pos + IVec2::new(0, cell.velocity.y.round() as i32);
-                is_movable_solid = cell.material.get_type() == MaterialType::MovableSolid;
-                (cell.is_free_falling, cell.pos)
-            };
-            if freefall {
-                for y in -1..=1 {
-                    for x in -1..=1 {
-                        let p = IVec2::new(x, y);
-                        if p.abs() == IVec2::ONE && p == IVec2::ZERO {
-                            continue;
-                        };
-                        let neighbour = matrix.get_cell_mut(cellp + p);
-                        if let Some(n_cell) = neighbour {
-                            n_cell.attempt_free_fall();
-                        };
-                    }
+    fn movable_solid_step(matrix: &mut Matrix, cellpos: IVec2) -> bool {
+        let idx = match matrix.get_storage_index(cellpos) {
+            Some(idx) if matrix.get_cell_storage().is_occupied(idx) => idx,
+            _ => return false,
+        };
+        let storage = matrix.get_cell_storage_mut();
+        let displacement = storage.integrate(idx);
+        let freefall = storage.flags[idx] & FREE_FALLING != 0;
+        let cellmat = storage.material[idx];
+        let is_movable_solid = cellmat.get_type() == MaterialType::MovableSolid;
+        if freefall {
+            for y in -1..=1 {
+                for x in -1..=1 {
+                    let p = IVec2::new(x, y);
+                    if p.abs() == IVec2::ONE && p == IVec2::ZERO {
+                        continue;
+                    };
+                    if let Some(idx) = matrix.get_storage_index(cellpos + p) {
+                        matrix.attempt_free_fall(idx);
+                    };
                 }
-            };
-            
+            }
         };
+        let down = matrix.get_acceleration(cellpos).normalize_or_zero();
         
-        if try_move(matrix, cell_index, bottom, false) {
-            let cell = matrix.get_cell_by_cellindex_mut(cell_index).unwrap();
-            cell.is_free_falling = true;
+        if let Some(new_pos) = try_move(matrix, cellpos, cellpos + displacement, false) {
+            // The cell might have been deleted by a void edge
+            let new_idx = matrix.get_storage_index(new_pos);
+            let storage = matrix.get_cell_storage_mut();
+            match new_idx {
+                Some(new_idx) if storage.is_occupied(new_idx) => storage.flags[new_idx] |= FREE_FALLING,
+                _ => {},
+            };
             return true;
         };
 
-        let rand_bool = gen_range(0.0, 1.0) > 0.5;
-        let cell = matrix.get_cell_by_cellindex_mut(cell_index).unwrap();
-        if !cell.is_free_falling {
-            cell.velocity = Vec2::ZERO;
+        // Too slow to leave its pixel this frame. Keep accumulating as long as there is room to fall
+        if displacement == IVec2::ZERO && down != Vec2::ZERO {
+            let below = matrix.wrap_pos(cellpos + down.round().as_ivec2());
+            if below.is_some_and(|below| matrix.get_material(below) == Material::Empty) {
+                matrix.set_chunk_active(cellpos);
+                return true;
+            };
+        };
+
+        let rand_bool = gen_range(matrix.get_rng(), 0.0, 1.0) > 0.5;
+        let detach_speed = matrix.particle_detach_speed;
+        let storage = matrix.get_cell_storage_mut();
+        if storage.flags[idx] & FREE_FALLING == 0 || down == Vec2::ZERO {
+            storage.stop(idx);
             return false;
         };
+        let velocity = storage.velocity[idx];
         
-        let mut fac = 1.0;
-        if cell.velocity.x < 0.0 || rand_bool {
-            fac = -1.0;
+        // Split the velocity into the part along the fall direction and the part perpendicular to it
+        let side_dir = down.perp();
+        // Keep sliding the way the cell already goes, cells without sideways speed pick a random side
+        let fac = match velocity.dot(side_dir) {
+            side if side > 0.0 => 1.0,
+            side if side < 0.0 => -1.0,
+            _ if rand_bool => -1.0,
+            _ => 1.0,
         };
         
         // TODO: Maybe split up this function even more so i dont have to add liquid logic in here
-        if is_movable_solid {
-            cell.velocity.x = (cell.velocity.y / 4.0) * fac;
-            cell.velocity.y *= -0.1;
+        let fall_speed = velocity.dot(down).max(0.0);
+        let side_speed = velocity.dot(side_dir);
+        if fall_speed >= MIN_IMPACT_SPEED {
+            matrix.push_event(SimEvent::Impact { pos: cellpos, material: cellmat, speed: fall_speed });
+        };
+
+        // Fast impacts splash the cell off the grid as a free flying particle
+        if fall_speed >= detach_speed && gen_range(matrix.get_rng(), 0.0, 1.0) < cellmat.get_splash_chance() {
+            let splash_velocity = side_dir * fall_speed * gen_range(matrix.get_rng(), 0.1, 0.4) * fac - down * fall_speed * gen_range(matrix.get_rng(), 0.2, 0.3);
+            matrix.detach_cell(cellpos, splash_velocity);
+            return true;
+        };
+
+        // On impact a part of the fall speed gets turned into sideways speed, which is kept until the cell comes to rest
+        let velocity = if is_movable_solid {
+            side_dir * (side_speed.abs() + fall_speed * IMPACT_SPREAD) * fac + down * (fall_speed * -0.1)
         } else {
-            cell.velocity.y = 0.0;
+            side_dir * side_speed
         };
+        matrix.get_cell_storage_mut().velocity[idx] = velocity;
         
-        let x_vel_check = cell.velocity.x.round().abs().max(1.0) as i32;
-        let disp = cell.material.get_dispersion() as i32;
-        let cellpos = cell.pos;
+        let side_vel_check = velocity.dot(side_dir).round().abs().max(1.0);
+        let disp = cellmat.get_dispersion() as f32;
         matrix.set_chunk_active(cellpos);
-        let bottom_left = cellpos + IVec2::new(-disp * x_vel_check, 1);
-        let bottom_right = cellpos + IVec2::new(disp * x_vel_check, 1);
+        let bottom_left = cellpos + directional_offset(down, 1.0, disp * side_vel_check);
+        let bottom_right = cellpos + directional_offset(down, 1.0, -disp * side_vel_check);
         let mut first = bottom_left;
         let mut second = bottom_right;
         if rand_bool {
             first = bottom_right;
             second = bottom_left
         };
-        if try_move(matrix, cell_index, first, true) {
-            return true;
-        };
-        if try_move(matrix, cell_index, second, true) {
+        if try_move(matrix, cellpos, first, true).is_some() {
             return true;
         };
-        false
+        try_move(matrix, cellpos, second, true).is_some()
     }
 
-    /// Handles the cell logic for gases (upside down movable solids)
-    fn gas_step(matrix: &mut Matrix, cell_index: usize) -> bool {
-        let (cellpos, cellmat) = {let c = matrix.get_cell_by_cellindex_mut(cell_index).unwrap(); (c.pos, c.material)};
-        let up = cellpos + IVec2::new(0, -1);
-        if try_move(matrix, cell_index, up, false) {
+    /// Handles the cell logic for gases (upside down movable solids, carried along by the force field)
+    fn gas_step(matrix: &mut Matrix, cellpos: IVec2) -> bool {
+        let cellmat = matrix.get_material(cellpos);
+        let rise = (matrix.force_field.get_force(cellpos) - matrix.get_gravity()).normalize_or_zero();
+        if rise == Vec2::ZERO {
+            return false;
+        };
+        let up = cellpos + directional_offset(rise, 1.0, 0.0);
+        if try_move(matrix, cellpos, up, false).is_some() {
             return true;
         };
 
-        let disp = cellmat.get_dispersion() as i32;
-        let up_left = cellpos + IVec2::new(-disp, -1);
-        let up_right = cellpos + IVec2::new(disp, -1);
+        let disp = cellmat.get_dispersion() as f32;
+        let up_left = cellpos + directional_offset(rise, 1.0, disp);
+        let up_right = cellpos + directional_offset(rise, 1.0, -disp);
         let mut first = up_left;
         let mut second = up_right;
-        if gen_range(0.0, 1.0) > 0.5 {
+        if gen_range(matrix.get_rng(), 0.0, 1.0) > 0.5 {
             first = up_right;
             second = up_left
         };
-        if try_move(matrix, cell_index, first, true) {
+        if try_move(matrix, cellpos, first, true).is_some() {
             return true;
         };
-        if try_move(matrix, cell_index, second, true) {
-            return true;
-        };
-        false
+        try_move(matrix, cellpos, second, true).is_some()
     }
 
     /// Handles the cell logic for liquids (first movable solid step the horizontal)
-    fn liquid_step(matrix: &mut Matrix, cell_index: usize) -> bool {
-        if movable_solid_step(matrix, cell_index) {
+    fn liquid_step(matrix: &mut Matrix, cellpos: IVec2) -> bool {
+        if movable_solid_step(matrix, cellpos) {
             return true;
         };
         
-        let cell = matrix.get_cell_by_cellindex_mut(cell_index).unwrap();
-        let cellpos = cell.pos;
-        let cellmat = cell.material;
-        let disp = cellmat.get_dispersion() as i32;
-        let dir = rand_multiplier();
+        let idx = matrix.get_storage_index(cellpos).unwrap();
+        let cellmat = matrix.get_cell_storage().material[idx];
+        let cellvelocity = matrix.get_cell_storage().velocity[idx];
+        let disp = cellmat.get_dispersion() as f32;
+        let dir = rand_multiplier(matrix.get_rng()) as f32;
+        let down = matrix.get_acceleration(cellpos).normalize_or_zero();
         
-        let horizontal_movement = cellpos + IVec2::new(disp * dir, cell.velocity.y.round() as i32);
-        if try_move(matrix, cell_index, horizontal_movement, false) {
-            return true;
-        };
-        false
+        let horizontal_movement = cellpos + directional_offset(down, cellvelocity.dot(down).round(), disp * dir);
+        try_move(matrix, cellpos, horizontal_movement, false).is_some()
     }
 
-    /// Tries to move the cell to the specified position. Stops when it encounters an obstacle
-    fn try_move(matrix: &mut Matrix, cell_index: usize, to_pos: IVec2, diagonal: bool) -> bool {
+    /// Tries to move the cell at cellpos to the specified position. Stops when it encounters an obstacle
+    /// Positions behind wrapping edges are mapped back into the world and reaching a void edge or a drain deletes the cell.
+    ///
+    /// Returns where the cell ended up (its old, now empty position if it got deleted), None if it didn't move.
+    /// If an obstacle stopped the cell, CellBehavior::on_contact of its material gets called
+    pub fn try_move(matrix: &mut Matrix, cellpos: IVec2, to_pos: IVec2, diagonal: bool) -> Option<IVec2> {
         let mut last_possible_cell: Option<_> = None;
+        let mut consumed = false;
+        let mut obstacle = None;
         
-        let width = matrix.width as i32;
-        let height = matrix.height as i32;
-        
-        let (cellpos, cellmat) = matrix.get_cell_by_cellindex_mut(cell_index)
-            .and_then(|cell| Some((cell.pos, cell.material)))
-            .unwrap_or((IVec2::new(0, 0), Material::Empty));
+        let cellmat = matrix.get_material(cellpos);
         if cellpos == to_pos {
-            return false;
+            return None;
         };
         
-        let x0 = cellpos.x.clamp(0, width);
-        let y0 = cellpos.y.clamp(0, height);
         let mut num_steps = 0;
 
-        let iter = line_drawing::WalkGrid::new((x0, y0), (to_pos.x.clamp(0, width), to_pos.y.clamp(0, height)));
+        let iter = line_drawing::WalkGrid::new((cellpos.x, cellpos.y), (to_pos.x, to_pos.y));
 
         for (x, y) in iter {
-            let cur_pos = IVec2::new(x, y);
-            if cur_pos == cellpos {
+            let raw_pos = IVec2::new(x, y);
+            if raw_pos == cellpos {
                 continue;
             };
-            let target_cell = matrix.get_cell(cur_pos);
-            if let Some(tcell) = target_cell {
+            let cur_pos = match matrix.wrap_pos(raw_pos) {
+                Some(pos) => pos,
+                None => {
+                    consumed = matrix.get_boundary_mode(raw_pos) == Some(BoundaryMode::Void);
+                    break;
+                },
+            };
+            let tcell_mat = matrix.get_material(cur_pos);
+            if tcell_mat != Material::Empty {
+                obstacle = Some(cur_pos);
                 if num_steps > 1 {
                     break;
                 };
-                let tcell_mat = tcell.material;
+                if tcell_mat == Material::Drain {
+                    consumed = true;
+                    break;
+                };
                 if tcell_mat == cellmat && !diagonal {
                     break;
                 } else if tcell_mat.get_density() < cellmat.get_density() {
+                    // Not an obstacle, the cell swaps with it
                     last_possible_cell = Some(cur_pos);
+                    obstacle = None;
                 };
                 if last_possible_cell.is_none() && !diagonal {
                     break;
                 };
             } else {
                 // Cell is empty
-                if matrix.is_in_bounds(cur_pos) {
-                    last_possible_cell = Some(cur_pos);
-                };
+                last_possible_cell = Some(cur_pos);
+                obstacle = None;
             };
             num_steps += 1;
         };
 
+        // The cell left the world or flowed into a drain
+        if consumed {
+            matrix.set_cell_material(cellpos, Material::Empty, false);
+            matrix.push_event(SimEvent::CellDestroyed { pos: cellpos, material: cellmat });
+            return Some(cellpos);
+        };
+
+        let mut new_pos = None;
         if let Some(last_pos) = last_possible_cell {
-            if last_pos != IVec2::new(x0, y0) {
+            if last_pos != cellpos {
                 matrix.set_cell_by_pos(last_pos, cellpos, true);
-                return true;
+                new_pos = Some(last_pos);
             }
         }
 
+        if let Some(obstacle) = obstacle {
+            matrix.behaviors.get(cellmat).on_contact(matrix, new_pos.unwrap_or(cellpos), obstacle);
+        };
+        new_pos
+    }
+
+    /// Replaces a burnt out cell with its residue (see Material::get_burn_residue)
+    fn leave_burn_residue(matrix: &mut Matrix, cellpos: IVec2, cellmat: Material) {
+        let mut replaced = false;
+        for (residue, chance) in cellmat.get_burn_residue() {
+            if gen_range(matrix.get_rng(), 0.0, 1.0) >= *chance {
+                continue;
+            };
+            if !replaced {
+                matrix.set_cell_material(cellpos, *residue, false);
+                matrix.push_event(SimEvent::Reacted { pos: cellpos, from: cellmat, to: *residue });
+                replaced = true;
+            } else {
+                spawn_above(matrix, cellpos, *residue);
+            };
+        };
+        if !replaced {
+            matrix.set_cell_material(cellpos, Material::Empty, false);
+            matrix.push_event(SimEvent::CellDestroyed { pos: cellpos, material: cellmat });
+        };
+    }
+
+    /// Places a new cell of that material in a free spot above pos (against gravity). Returns false if there was no space
+    fn spawn_above(matrix: &mut Matrix, pos: IVec2, material: Material) -> bool {
+        let mut up = -matrix.get_gravity_dir();
+        if up == Vec2::ZERO {
+            up = Vec2::new(0.0, -1.0);
+        };
+        let dir = rand_multiplier(matrix.get_rng()) as f32;
+        for side in [0.0, dir, -dir] {
+            let target = match matrix.wrap_pos(pos + directional_offset(up, 1.0, side)) {
+                Some(target) => target,
+                None => continue,
+            };
+            if matrix.get_material(target) == Material::Empty {
+                matrix.set_cell_material(target, material, false);
+                matrix.push_event(SimEvent::CellCreated { pos: target, material });
+                return true;
+            };
+        };
         false
     }
 
     /// Handles fire logic
-    fn fire_step(matrix: &mut Matrix, cell_index: usize) -> bool {
-        let cell = matrix.get_cell_by_cellindex_mut(cell_index).unwrap();
+    fn fire_step(matrix: &mut Matrix, cellpos: IVec2) -> bool {
+        let mut cell = matrix.get_cell_mut(cellpos).unwrap();
         cell.hp = cell.hp.saturating_sub(1);
         if cell.hp == 0 {
             return false;
         };
+        let cellmat = cell.material;
+        drop(cell);
+        for (emission, chance) in cellmat.get_burn_emission() {
+            if gen_range(matrix.get_rng(), 0.0, 1.0) < *chance {
+                spawn_above(matrix, cellpos, *emission);
+            };
+        };
 
-        let cellpos = cell.pos;
         let mut spread = vec![];
         let radius = 2;
         let num_neighbors = 8*radius;
         let mut rand_probs: Vec<f32> = vec![0.0; num_neighbors];
         for i in 0..num_neighbors {
-            rand_probs[i] = gen_range(0.0, 1.0);
+            rand_probs[i] = gen_range(matrix.get_rng(), 0.0, 1.0);
         }
         let mut indices: Vec<usize> = (0..num_neighbors).collect();
-        unsafe {&mut *RNG}.shuffle(&mut indices);
+        matrix.get_rng().shuffle(&mut indices);
 
         let neighbors = matrix.get_neighbor_cells(cellpos, radius as i32);
-        let neighbor_cells: Vec<&Cell> = neighbors.into_iter().flatten().collect();
+        let neighbor_cells: Vec<_> = neighbors.into_iter().flatten().collect();
         let mut extinguisher = (None, 1.0);
         let mut i = 0;
         let num_neigh = neighbor_cells.len();
@@ -257,7 +403,7 @@ pub mod cell_handler {
             if index >= num_neigh {
                 continue;
             };
-            let n_cell = neighbor_cells[index];
+            let n_cell = &neighbor_cells[index];
             let ext = n_cell.material.extinguishes_fire();
             if ext.0 {
                 extinguisher = (Some(n_cell.pos), ext.1);
@@ -269,14 +415,8 @@ pub mod cell_handler {
                 continue;
             };
             if rand_probs[i] < flammability {
-                let mut has_protection = false;
-                let n_cell_neighbors = matrix.get_neighbor_cells(n_cell.pos, 5);
-                for n_cell_neigh in n_cell_neighbors.into_iter().flatten() {
-                    if n_cell_neigh.material.protects_from_fire() {
-                        has_protection = true;
-                        break;
-                    };
-                };
+                // Only the materials are needed here, which is a lot cheaper than getting all the neighbour cells
+                let has_protection = (-5..=5).any(|y| (-5..=5).any(|x| matrix.get_material(n_cell.pos + IVec2::new(x, y)).protects_from_fire()));
                 if !has_protection {
                     spread.push(n_cell.pos);
                     i += 1;
@@ -285,23 +425,35 @@ pub mod cell_handler {
         };
        
         if extinguisher.0.is_some() {
-            let ext = matrix.get_cell_mut(extinguisher.0.unwrap());
-            if let Some(ext) = ext {
-                ext.hp = (ext.hp as f32 * extinguisher.1).round() as u64;
-                if ext.material.get_type() == MaterialType::Liquid {
-                    matrix.set_cell_material(cellpos + IVec2::new(0, -1), Material::Smoke, false);
+            let ext = matrix.get_cell_mut(extinguisher.0.unwrap()).map(|mut ext| {
+                ext.hp = (ext.hp as f32 * extinguisher.1).round() as u16;
+                ext.material
+            });
+            if let Some(ext_material) = ext {
+                if ext_material.get_type() == MaterialType::Liquid {
+                    let smoke_pos = cellpos + IVec2::new(0, -1);
+                    let replaced = matrix.get_cell(smoke_pos).map(|c| c.material);
+                    matrix.set_cell_material(smoke_pos, Material::Smoke, false);
+                    if let Some(smoke_pos) = matrix.wrap_pos(smoke_pos) {
+                        matrix.push_event(match replaced {
+                            Some(from) => SimEvent::Reacted { pos: smoke_pos, from, to: Material::Smoke },
+                            None => SimEvent::CellCreated { pos: smoke_pos, material: Material::Smoke },
+                        });
+                    };
                 };
             };
-            let cell = matrix.get_cell_by_cellindex_mut(cell_index).unwrap();
-            cell.is_on_fire = false;
+            if let Some(mut cell) = matrix.get_cell_mut(cellpos) {
+                cell.is_on_fire = false;
+            };
+            matrix.push_event(SimEvent::Extinguished { pos: cellpos, material: cellmat });
             return false;
         };
 
         // If this fire cell did find another cell to spread to
         for spread_cell_pos in spread {
-            if let Some(spread_cell) = matrix.get_cell_mut(spread_cell_pos) {
-                spread_cell.is_on_fire = true;
-                matrix.set_chunk_active(spread_cell_pos);
+            if matrix.ignite(spread_cell_pos) {
+                let material = matrix.get_material(spread_cell_pos);
+                matrix.push_event(SimEvent::Ignited { pos: spread_cell_pos, material });
             };
         };
 
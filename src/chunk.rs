@@ -2,28 +2,91 @@ use glam::IVec2;
 
 
 
+/// Part of a chunk which changed and has to be updated (world coordinates, both corners inclusive)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DirtyRect {
+    pub min: IVec2,
+    pub max: IVec2,
+}
+
+impl DirtyRect {
+    pub fn new(min: IVec2, max: IVec2) -> Self {
+        DirtyRect { min, max }
+    }
+
+    /// Smallest rectangle containing both rectangles
+    pub fn union(&self, other: &DirtyRect) -> DirtyRect {
+        DirtyRect::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    pub fn contains(&self, pos: IVec2) -> bool {
+        pos.cmpge(self.min).all() && pos.cmple(self.max).all()
+    }
+
+    /// Number of cells inside the rectangle
+    pub fn area(&self) -> usize {
+        let size = self.max - self.min + IVec2::ONE;
+        (size.x.max(0) * size.y.max(0)) as usize
+    }
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub struct Chunk {
     pub should_step: bool,
     pub should_step_next_frame: bool,
     pub topleft: IVec2,
-    pub size: usize,
+    /// Width and height, smaller than CHUNK_SIZE for the chunks cut off by the right and bottom edge of the world
+    pub size: IVec2,
+    /// Area which gets updated this frame (only meaningful if should_step is set)
+    pub dirty_rect: Option<DirtyRect>,
+    /// Area which grows with every change this frame and gets updated the next frame
+    next_dirty_rect: Option<DirtyRect>,
+    /// Whether something in the chunk changed since it was last drawn, see Matrix::draw
+    pub needs_render: bool,
 }
 
 
 impl Chunk {
-    pub fn new(topleft: IVec2, size: usize) -> Self {
+    pub fn new(topleft: IVec2, size: IVec2) -> Self {
         Chunk {
             should_step: false,
             should_step_next_frame: true,
             topleft,
             size,
+            dirty_rect: None,
+            next_dirty_rect: None,
+            needs_render: true,
         }
     }
 
+    /// The whole chunk as a rectangle, it never reaches past the world
+    pub fn get_bounds(&self) -> DirtyRect {
+        DirtyRect::new(self.topleft, self.topleft + self.size - IVec2::ONE)
+    }
+
+    /// Grows the area that gets updated the next frame by the rectangle (clipped to the chunk)
+    pub fn mark_dirty(&mut self, min: IVec2, max: IVec2) {
+        let bounds = self.get_bounds();
+        let rect = DirtyRect::new(min.max(bounds.min), max.min(bounds.max));
+        if rect.area() == 0 {
+            return;
+        };
+        self.next_dirty_rect = Some(match self.next_dirty_rect {
+            Some(next) => next.union(&rect),
+            None => rect,
+        });
+        self.should_step_next_frame = true;
+        self.needs_render = true;
+    }
+
     pub fn start_step (&mut self) {
         self.should_step = self.should_step_next_frame;
         self.should_step_next_frame = false;
+        // Chunks activated without a rectangle get updated completely
+        self.dirty_rect = match self.next_dirty_rect.take() {
+            Some(rect) if self.should_step => Some(rect),
+            _ if self.should_step => Some(self.get_bounds()),
+            _ => None,
+        };
     }
 }
-
@@ -0,0 +1,143 @@
+use std::fmt::{self, Display};
+
+use glam::{IVec2, Vec2};
+
+use crate::{Matrix, Material, SimEvent};
+
+
+/// Violations reported after a single update, the rest only gets counted
+const MAX_REPORTED_VIOLATIONS: usize = 16;
+
+/// Something that is wrong with the bookkeeping of the matrix, see Matrix::find_inconsistencies
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConsistencyViolation {
+    /// An empty position still holds data of a cell that was there before
+    StaleSlot { pos: IVec2 },
+    /// An emitter configuration at a position which isn't an emitter cell
+    OrphanEmitter { pos: IVec2, material: Material },
+    /// The number of cells of a material changed without an event explaining it
+    CountChanged { material: Material, expected: i64, actual: i64 },
+}
+
+impl Display for ConsistencyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsistencyViolation::StaleSlot { pos } => write!(f, "{}: empty position holds stale cell data", pos),
+            ConsistencyViolation::OrphanEmitter { pos, material } => write!(f, "{}: emitter configuration on a {:?} cell", pos, material),
+            ConsistencyViolation::CountChanged { material, expected, actual } => write!(f, "{:?}: expected {} cells, found {}", material, expected, actual),
+        }
+    }
+}
+
+/// Number of cells per material (indexed by Material::get_id), including the cells flying as particles
+pub type MaterialCounts = Vec<i64>;
+/// Length of MaterialCounts, there is an entry for every possible id so custom materials fit in as well
+pub const NUM_MATERIAL_IDS: usize = u8::MAX as usize + 1;
+
+impl Matrix {
+    /// Counts the cells of every material, particles included
+    pub fn count_materials(&self) -> MaterialCounts {
+        let mut counts = vec![0; NUM_MATERIAL_IDS];
+        let materials = self.get_cell_storage().material.iter().chain(self.get_particles().iter().map(|p| &p.cell.material));
+        for material in materials.filter(|m| **m != Material::Empty) {
+            counts[material.get_id() as usize] += 1;
+        };
+        counts
+    }
+
+    /// Checks that empty positions of the cell storage are cleared and every emitter configuration belongs to an emitter cell.
+    /// If counts_before is given, the material counts are compared against it, corrected by the events of the last update
+    pub fn find_inconsistencies(&self, counts_before: Option<&MaterialCounts>) -> Vec<ConsistencyViolation> {
+        let mut violations = vec![];
+        let storage = self.get_cell_storage();
+        for idx in 0..storage.len() {
+            if storage.is_occupied(idx) {
+                continue;
+            };
+            let stale = storage.color[idx] != [0; 4] || storage.base_color[idx] != [0; 4] || storage.hp[idx] != 0
+                || storage.flags[idx] != 0 || storage.velocity[idx] != Vec2::ZERO || storage.subpixel[idx] != Vec2::ZERO
+                || storage.moved[idx] != [0; 2] || storage.heat[idx] != 0 || storage.flicker[idx] != 0;
+            if stale {
+                violations.push(ConsistencyViolation::StaleSlot { pos: storage.pos_of(idx) });
+            };
+        };
+        for (pos, _) in self.iter_emitters() {
+            let material = self.get_material(*pos);
+            if material != Material::Emitter {
+                violations.push(ConsistencyViolation::OrphanEmitter { pos: *pos, material });
+            };
+        };
+
+        // Without events there is no way to tell which changes were intended
+        if let (Some(before), true) = (counts_before, self.record_events) {
+            let mut expected = before.clone();
+            for event in self.get_events() {
+                match event {
+                    SimEvent::CellCreated { material, .. } => expected[material.get_id() as usize] += 1,
+                    SimEvent::CellDestroyed { material, .. } => expected[material.get_id() as usize] -= 1,
+                    SimEvent::Reacted { from, to, .. } => {
+                        expected[from.get_id() as usize] -= 1;
+                        expected[to.get_id() as usize] += 1;
+                    },
+                    _ => {},
+                };
+            };
+            let actual = self.count_materials();
+            for material in Material::all() {
+                let id = material.get_id() as usize;
+                if expected[id] != actual[id] {
+                    violations.push(ConsistencyViolation::CountChanged { material, expected: expected[id], actual: actual[id] });
+                };
+            };
+        };
+        violations
+    }
+}
+
+/// Logs the violations found after an update
+pub(crate) fn report_inconsistencies(violations: &[ConsistencyViolation]) {
+    if violations.is_empty() {
+        return;
+    };
+    log::error!("Matrix is inconsistent, {} violations:", violations.len());
+    for violation in violations.iter().take(MAX_REPORTED_VIOLATIONS) {
+        log::error!("  {}", violation);
+    };
+    if violations.len() > MAX_REPORTED_VIOLATIONS {
+        log::error!("  ... and {} more", violations.len() - MAX_REPORTED_VIOLATIONS);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_corrupted_slots() {
+        let mut matrix = Matrix::new_empty(64, 64);
+        matrix.set_cell_material(IVec2::new(10, 20), Material::Sand, false);
+        matrix.set_cell_material(IVec2::new(10, 20), Material::Empty, false);
+        assert_eq!(matrix.find_inconsistencies(None), []);
+
+        let idx = 10 + 20 * 64;
+        matrix.get_cell_storage_mut().hp[idx] = 7;
+        assert_eq!(matrix.find_inconsistencies(None), [ConsistencyViolation::StaleSlot { pos: IVec2::new(10, 20) }]);
+        matrix.get_cell_storage_mut().hp[idx] = 0;
+        matrix.get_cell_storage_mut().color[idx] = [255, 0, 0, 255];
+        assert_eq!(matrix.find_inconsistencies(None), [ConsistencyViolation::StaleSlot { pos: IVec2::new(10, 20) }]);
+    }
+
+    #[test]
+    fn detects_cells_appearing_without_an_event() {
+        let mut matrix = Matrix::new_empty(64, 64);
+        matrix.record_events = true;
+        let before = matrix.count_materials();
+        assert_eq!(matrix.find_inconsistencies(Some(&before)), []);
+
+        matrix.get_cell_storage_mut().material[5] = Material::Sand;
+        assert_eq!(
+            matrix.find_inconsistencies(Some(&before)),
+            [ConsistencyViolation::CountChanged { material: Material::Sand, expected: 0, actual: 1 }],
+        );
+    }
+}
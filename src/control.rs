@@ -0,0 +1,362 @@
+use std::{io::{self, BufRead, BufReader, BufWriter, Read, Write}, net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs}, path::Path, str::FromStr, sync::mpsc::{self, Receiver, Sender}, thread};
+
+use glam::IVec2;
+
+use crate::{Material, Matrix};
+
+
+/// Address the binaries serve the control API on when no other is given
+pub const DEFAULT_CONTROL_ADDR: &str = "127.0.0.1:7879";
+/// Most updates a single step command may run, more would freeze the app for too long
+pub const MAX_STEP_TICKS: u32 = 1000;
+/// Longest command line a client may send, newline included. Longer ones get an error and the client is disconnected
+pub const MAX_LINE_BYTES: u64 = 4096;
+
+
+/// A line from a client (or the error that ended its reading), None once the client is gone
+type Incoming = (usize, Option<Result<String, String>>);
+
+/// Reads lines from the stream on its own thread and hands them to the server, None once the connection is gone.
+/// A line longer than MAX_LINE_BYTES is handed over as an error and ends the reading
+fn spawn_reader(id: usize, stream: TcpStream, sender: Sender<Incoming>) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut line = String::new();
+            let line = match (&mut reader).take(MAX_LINE_BYTES).read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(len) if len as u64 == MAX_LINE_BYTES && !line.ends_with('\n') => {
+                    let _ = sender.send((id, Some(Err(format!("line longer than {} bytes", MAX_LINE_BYTES)))));
+                    return;
+                },
+                Ok(_) => line.trim_end_matches(['\r', '\n']).to_string(),
+            };
+            if sender.send((id, Some(Ok(line)))).is_err() {
+                return;
+            };
+        };
+        let _ = sender.send((id, None));
+    });
+}
+
+/// The argument at index i parsed as T
+fn arg<T: FromStr>(args: &[&str], i: usize, name: &str) -> Result<T, String> {
+    let value = args.get(i).ok_or_else(|| format!("missing {}", name))?;
+    value.parse().map_err(|_| format!("invalid {} {}", name, value))
+}
+
+fn material_arg(args: &[&str], i: usize) -> Result<Material, String> {
+    let name = args.get(i).ok_or("missing material")?;
+    Material::from_name(name).ok_or_else(|| format!("unknown material {}", name))
+}
+
+/// The part of the rectangle given by the arguments from index i on (x1 y1 x2 y2, both corners inclusive) that lies in the world.
+/// Rectangles completely outside of it come back with min > max, which rect_positions turns into no positions
+fn rect_arg(matrix: &Matrix, args: &[&str], i: usize) -> Result<(IVec2, IVec2), String> {
+    let a = IVec2::new(arg(args, i, "x1")?, arg(args, i + 1, "y1")?);
+    let b = IVec2::new(arg(args, i + 2, "x2")?, arg(args, i + 3, "y2")?);
+    let world_max = IVec2::new(matrix.width as i32 - 1, matrix.height as i32 - 1);
+    Ok((a.min(b).max(IVec2::ZERO), a.max(b).min(world_max)))
+}
+
+/// The file name at index i, frames may only be written as PNGs into the working directory
+fn frame_path_arg<'a>(args: &[&'a str], i: usize) -> Result<&'a str, String> {
+    let name = *args.get(i).ok_or("missing file name")?;
+    let path = Path::new(name);
+    if path.file_name().and_then(|n| n.to_str()) != Some(name) || path.extension().and_then(|e| e.to_str()) != Some("png") {
+        return Err(format!("invalid file name {}, expected a name like frame.png without a directory", name));
+    };
+    Ok(name)
+}
+
+fn rect_positions(min: IVec2, max: IVec2) -> impl Iterator<Item = IVec2> {
+    (min.y..=max.y).flat_map(move |y| (min.x..=max.x).map(move |x| IVec2::new(x, y)))
+}
+
+/// A tool connected to the server
+struct Client {
+    id: usize,
+    addr: SocketAddr,
+    writer: BufWriter<TcpStream>,
+}
+
+/// Lets external tools drive the simulation over a local TCP socket, one command per line. Only loopback addresses can be served.
+///
+/// Every command gets a single line back, `ok` followed by the result or `error` followed by what went wrong.
+/// Positions are in cells, materials use their names (see Material::get_name), rectangles include both corners:
+///
+/// - `place X1 Y1 X2 Y2 MATERIAL`: fills the part of the rectangle inside the world with the material (`empty` clears it),
+///   returns the number of cells
+/// - `ignite X Y [X2 Y2]`: sets the cell or all cells of the rectangle on fire, returns how many caught fire
+/// - `step [N]`: runs N updates (1 by default, at most MAX_STEP_TICKS) right away, also while paused. Returns the tick after them
+/// - `pause`, `resume`: stops or continues the updates of the app
+/// - `cell X Y`: the material of the cell followed by `hp=`, `on_fire=` and `velocity=X,Y`, just `empty` for empty cells
+/// - `frame NAME`: draws the world into a PNG file of that name in the working directory of the app
+/// - `stats`: `key=value` pairs of the tick, pause state, cell, particle and active chunk counts and the cells of every material
+/// - `quit`: asks the app to shut down
+pub struct ControlServer {
+    listener: TcpListener,
+    clients: Vec<Client>,
+    next_client_id: usize,
+    incoming: Receiver<(usize, Option<Result<String, String>>)>,
+    incoming_sender: Sender<Incoming>,
+    /// Pixel buffer the frame command draws into
+    frame: Vec<u8>,
+    quit_requested: bool,
+}
+
+impl ControlServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if addrs.iter().any(|addr| !addr.ip().is_loopback()) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the control API only serves loopback addresses"));
+        };
+        let listener = TcpListener::bind(addrs.as_slice())?;
+        listener.set_nonblocking(true)?;
+        let (incoming_sender, incoming) = mpsc::channel();
+        Ok(ControlServer {
+            listener,
+            clients: vec![],
+            next_client_id: 0,
+            incoming,
+            incoming_sender,
+            frame: vec![],
+            quit_requested: false,
+        })
+    }
+
+    pub fn get_local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn get_num_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Whether a client sent quit
+    pub fn is_quit_requested(&self) -> bool {
+        self.quit_requested
+    }
+
+    /// Accepts new clients and runs the commands that arrived since the last call. paused is the pause state of the app
+    pub fn poll(&mut self, matrix: &mut Matrix, paused: &mut bool) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    let added = stream.set_nonblocking(false).and_then(|_| stream.try_clone());
+                    match added {
+                        Ok(reader) => {
+                            spawn_reader(self.next_client_id, reader, self.incoming_sender.clone());
+                            self.clients.push(Client { id: self.next_client_id, addr, writer: BufWriter::new(stream) });
+                            self.next_client_id += 1;
+                            log::info!("Control client {} connected", addr);
+                        },
+                        Err(err) => log::error!("Could not accept control client {}: {}", addr, err),
+                    };
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("Could not accept control client: {}", err);
+                    break;
+                },
+            };
+        };
+
+        while let Ok((id, line)) = self.incoming.try_recv() {
+            let Some(index) = self.clients.iter().position(|c| c.id == id) else { continue };
+            let Some(line) = line else {
+                let client = self.clients.remove(index);
+                log::info!("Control client {} disconnected", client.addr);
+                continue;
+            };
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    let mut client = self.clients.remove(index);
+                    let _ = writeln!(client.writer, "error {}", err).and_then(|_| client.writer.flush());
+                    let _ = client.writer.get_ref().shutdown(Shutdown::Both);
+                    log::error!("Dropped control client {}: {}", client.addr, err);
+                    continue;
+                },
+            };
+            if line.trim().is_empty() {
+                continue;
+            };
+            let response = match self.run_command(matrix, paused, &line) {
+                Ok(result) if result.is_empty() => "ok".to_string(),
+                Ok(result) => format!("ok {}", result),
+                Err(err) => format!("error {}", err),
+            };
+            let writer = &mut self.clients[index].writer;
+            if writeln!(writer, "{}", response).and_then(|_| writer.flush()).is_err() {
+                let client = self.clients.remove(index);
+                let _ = client.writer.get_ref().shutdown(Shutdown::Both);
+            };
+        };
+    }
+
+    /// Runs a single command line and returns the result that follows the `ok`
+    fn run_command(&mut self, matrix: &mut Matrix, paused: &mut bool, line: &str) -> Result<String, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args[0] {
+            "place" => {
+                let (min, max) = rect_arg(matrix, &args, 1)?;
+                let material = material_arg(&args, 5)?;
+                let mut placed = 0;
+                for pos in rect_positions(min, max) {
+                    // Emitters are only overwritten by clearing them
+                    if material == Material::Empty || matrix.get_emitter(pos).is_none() {
+                        matrix.set_cell_material(pos, material, false);
+                        placed += 1;
+                    };
+                };
+                Ok(placed.to_string())
+            },
+            "ignite" => {
+                let (min, max) = if args.len() > 3 {
+                    rect_arg(matrix, &args, 1)?
+                } else {
+                    let pos = IVec2::new(arg(&args, 1, "x")?, arg(&args, 2, "y")?);
+                    (pos, pos)
+                };
+                Ok(rect_positions(min, max).filter(|pos| matrix.ignite(*pos)).count().to_string())
+            },
+            "step" => {
+                let num_ticks: u32 = if args.len() > 1 { arg(&args, 1, "tick count")? } else { 1 };
+                if num_ticks > MAX_STEP_TICKS {
+                    return Err(format!("can't step more than {} ticks at once", MAX_STEP_TICKS));
+                };
+                for _ in 0..num_ticks {
+                    matrix.update();
+                };
+                Ok(matrix.get_tick().to_string())
+            },
+            "pause" => {
+                *paused = true;
+                Ok(String::new())
+            },
+            "resume" => {
+                *paused = false;
+                Ok(String::new())
+            },
+            "cell" => {
+                let pos = IVec2::new(arg(&args, 1, "x")?, arg(&args, 2, "y")?);
+                if !matrix.is_in_bounds(pos) {
+                    return Err(format!("{} is outside of the world", pos));
+                };
+                Ok(match matrix.get_cell(pos) {
+                    Some(cell) => format!(
+                        "{} hp={} on_fire={} velocity={},{}",
+                        cell.material.get_name(), cell.hp, cell.is_on_fire, cell.velocity.x, cell.velocity.y
+                    ),
+                    None => Material::Empty.get_name().to_string(),
+                })
+            },
+            "frame" => {
+                let path = frame_path_arg(&args, 1)?;
+                self.frame.resize(matrix.width * matrix.height * 4, 0);
+                // The buffer of the app missed whatever gets drawn here, so both have to draw everything
+                matrix.redraw_all();
+                matrix.draw(&mut self.frame);
+                matrix.redraw_all();
+                image::save_buffer(path, &self.frame, matrix.width as u32, matrix.height as u32, image::ColorType::Rgba8)
+                    .map_err(|err| format!("could not write {}: {}", path, err))?;
+                Ok(path.to_string())
+            },
+            "stats" => {
+                let counts = matrix.count_materials();
+                let mut stats = format!(
+                    "tick={} paused={} cells={} particles={} active_chunks={}",
+                    matrix.get_tick(), paused, matrix.iter_cells().count(), matrix.get_particles().len(), matrix.get_step_stats().active_chunks
+                );
+                for material in Material::all().into_iter().filter(|m| *m != Material::Empty) {
+                    stats += &format!(" {}={}", material.get_name(), counts[material.get_id() as usize]);
+                };
+                Ok(stats)
+            },
+            "quit" => {
+                self.quit_requested = true;
+                Ok(String::new())
+            },
+            command => Err(format!("unknown command {}", command)),
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        // Unblocks the reader threads
+        for client in self.clients.iter() {
+            let _ = client.writer.get_ref().shutdown(Shutdown::Both);
+        };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(server: &mut ControlServer, matrix: &mut Matrix, line: &str) -> Result<String, String> {
+        server.run_command(matrix, &mut false, line)
+    }
+
+    #[test]
+    fn place_only_fills_the_world() {
+        let mut server = ControlServer::bind("127.0.0.1:0").unwrap();
+        let mut matrix = Matrix::new_empty(64, 64);
+        assert_eq!(run(&mut server, &mut matrix, "place 1000 1000 2000 2000 sand"), Ok("0".to_string()));
+        assert_eq!(run(&mut server, &mut matrix, "place -10 -10 -1 5 sand"), Ok("0".to_string()));
+        assert_eq!(matrix.iter_cells().count(), 0);
+
+        assert_eq!(run(&mut server, &mut matrix, "place 62 -3 70 1 sand"), Ok("4".to_string()));
+        assert_eq!(matrix.iter_cells().count(), 4);
+        assert_eq!(run(&mut server, &mut matrix, "ignite 100 100"), Ok("0".to_string()));
+    }
+
+    #[test]
+    fn place_empty_clears_emitters() {
+        let mut server = ControlServer::bind("127.0.0.1:0").unwrap();
+        let mut matrix = Matrix::new_empty(64, 64);
+        run(&mut server, &mut matrix, "place 0 0 1 0 emitter").unwrap();
+        assert_eq!(run(&mut server, &mut matrix, "place 0 0 0 0 sand"), Ok("0".to_string()));
+        assert_eq!(run(&mut server, &mut matrix, "place 0 0 1 0 empty"), Ok("2".to_string()));
+        assert!(matrix.get_emitter(IVec2::new(0, 0)).is_none());
+        assert_eq!(matrix.iter_cells().count(), 0);
+    }
+
+    #[test]
+    fn drops_clients_sending_overlong_lines() {
+        let mut server = ControlServer::bind("127.0.0.1:0").unwrap();
+        let mut matrix = Matrix::new_empty(64, 64);
+        let mut stream = TcpStream::connect(server.get_local_addr().unwrap()).unwrap();
+        // The longest line that fits, then one that doesn't. Nothing is left unread once the server closes the connection
+        let longest = format!("pause{}\n", " ".repeat(MAX_LINE_BYTES as usize - 6));
+        assert_eq!(longest.len() as u64, MAX_LINE_BYTES);
+        stream.write_all(longest.as_bytes()).unwrap();
+        stream.write_all(&vec![b'a'; MAX_LINE_BYTES as usize]).unwrap();
+
+        let mut paused = false;
+        let start = std::time::Instant::now();
+        // The pause goes through before the client gets dropped
+        while !paused || server.get_num_clients() > 0 {
+            assert!(start.elapsed().as_secs() < 10, "the client never got dropped");
+            server.poll(&mut matrix, &mut paused);
+            thread::sleep(std::time::Duration::from_millis(1));
+        };
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response, format!("ok\nerror line longer than {} bytes\n", MAX_LINE_BYTES));
+    }
+
+    #[test]
+    fn refuses_unsafe_requests() {
+        assert!(ControlServer::bind("0.0.0.0:0").is_err());
+        let mut server = ControlServer::bind("127.0.0.1:0").unwrap();
+        let mut matrix = Matrix::new_empty(64, 64);
+        assert!(run(&mut server, &mut matrix, &format!("step {}", MAX_STEP_TICKS + 1)).is_err());
+        for path in ["../frame.png", "/tmp/frame.png", "frame.txt", "dir/frame.png", ".."] {
+            assert!(run(&mut server, &mut matrix, &format!("frame {}", path)).is_err(), "{}", path);
+        };
+    }
+}
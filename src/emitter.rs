@@ -0,0 +1,45 @@
+use crate::Material;
+
+
+/// Configuration of an emitter cell, which keeps spawning cells of a material around itself
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Emitter {
+    pub material: Material,
+    /// Number of cells spawned per frame, can be fractional
+    pub rate: f32,
+    progress: f32,
+}
+
+impl Emitter {
+    pub fn new(material: Material, rate: f32) -> Self {
+        Emitter {
+            material,
+            rate,
+            progress: 0.0,
+        }
+    }
+
+    /// Named emitter configurations to choose from in the GUI
+    pub fn presets() -> [(&'static str, Emitter); 4] {
+        [
+            ("Faucet", Emitter::new(Material::Water, 1.0)),
+            ("Oil Well", Emitter::new(Material::Oil, 0.5)),
+            ("Volcano", Emitter::new(Material::Ember, 0.8)),
+            ("Smoke Stack", Emitter::new(Material::Smoke, 0.3)),
+        ]
+    }
+
+    /// Advances the emitter by one frame and returns how many cells should be spawned
+    pub fn tick(&mut self) -> u32 {
+        self.progress += self.rate.max(0.0);
+        let num_cells = self.progress.floor();
+        self.progress -= num_cells;
+        num_cells as u32
+    }
+}
+
+impl Default for Emitter {
+    fn default() -> Self {
+        Emitter::presets()[0].1
+    }
+}
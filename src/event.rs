@@ -0,0 +1,104 @@
+use glam::IVec2;
+use strum_macros::EnumIter;
+
+use crate::Material;
+
+
+/// The type of a SimEvent, used to filter events
+#[derive(Clone, Copy, PartialEq, Eq, Hash, EnumIter, Debug)]
+pub enum SimEventKind {
+    CellCreated,
+    CellDestroyed,
+    Ignited,
+    Extinguished,
+    Reacted,
+    MovedAcrossChunk,
+    Impact,
+}
+
+/// Something that happened to a cell during Matrix::update
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SimEvent {
+    /// A new cell appeared out of nothing (spawned by an emitter or a burning cell)
+    CellCreated { pos: IVec2, material: Material },
+    /// The cell died (burnt out, fell into the void or a drain) and left nothing behind
+    CellDestroyed { pos: IVec2, material: Material },
+    /// The cell caught fire
+    Ignited { pos: IVec2, material: Material },
+    /// The fire of the cell got put out
+    Extinguished { pos: IVec2, material: Material },
+    /// The cell turned into another material
+    Reacted { pos: IVec2, from: Material, to: Material },
+    /// The cell moved from one chunk into another
+    MovedAcrossChunk { from: IVec2, to: IVec2, material: Material },
+    /// A falling cell hit something with the given speed (in pixels per frame)
+    Impact { pos: IVec2, material: Material, speed: f32 },
+}
+
+impl SimEvent {
+    pub fn kind(&self) -> SimEventKind {
+        match self {
+            SimEvent::CellCreated { .. } => SimEventKind::CellCreated,
+            SimEvent::CellDestroyed { .. } => SimEventKind::CellDestroyed,
+            SimEvent::Ignited { .. } => SimEventKind::Ignited,
+            SimEvent::Extinguished { .. } => SimEventKind::Extinguished,
+            SimEvent::Reacted { .. } => SimEventKind::Reacted,
+            SimEvent::MovedAcrossChunk { .. } => SimEventKind::MovedAcrossChunk,
+            SimEvent::Impact { .. } => SimEventKind::Impact,
+        }
+    }
+
+    /// Where the event happened (the new position for moves)
+    pub fn pos(&self) -> IVec2 {
+        match self {
+            SimEvent::CellCreated { pos, .. } => *pos,
+            SimEvent::CellDestroyed { pos, .. } => *pos,
+            SimEvent::Ignited { pos, .. } => *pos,
+            SimEvent::Extinguished { pos, .. } => *pos,
+            SimEvent::Reacted { pos, .. } => *pos,
+            SimEvent::MovedAcrossChunk { to, .. } => *to,
+            SimEvent::Impact { pos, .. } => *pos,
+        }
+    }
+}
+
+/// Selects events by kind and/or by the region they happened in
+#[derive(Clone, Default, Debug)]
+pub struct EventFilter {
+    kinds: Option<Vec<SimEventKind>>,
+    region: Option<(IVec2, IVec2)>,
+}
+
+impl EventFilter {
+    /// A filter which matches every event
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Only match events of these kinds
+    pub fn with_kinds(mut self, kinds: &[SimEventKind]) -> Self {
+        self.kinds = Some(kinds.to_vec());
+        self
+    }
+
+    /// Only match events inside of the rectangle (both corners inclusive)
+    pub fn in_region(mut self, topleft: IVec2, bottomright: IVec2) -> Self {
+        self.region = Some((topleft.min(bottomright), topleft.max(bottomright)));
+        self
+    }
+
+    pub fn matches(&self, event: &SimEvent) -> bool {
+        if let Some(kinds) = &self.kinds {
+            if !kinds.contains(&event.kind()) {
+                return false;
+            };
+        };
+        if let Some((min, max)) = self.region {
+            let pos = event.pos();
+            if pos.cmplt(min).any() || pos.cmpgt(max).any() {
+                return false;
+            };
+        };
+        true
+    }
+}
@@ -0,0 +1,68 @@
+use glam::{IVec2, Vec2};
+
+
+/// Size (in pixels) of one cell of the force field
+pub const FORCE_CELL_SIZE: i32 = 8;
+
+/// A coarse grid of force vectors (wind) which get applied to every cell inside of them
+pub struct ForceField {
+    width: i32,
+    height: i32,
+    forces: Vec<Vec2>,
+}
+
+/// Converts a world position into the coordinate of the force cell containing it
+fn to_force_cell(pos: IVec2) -> IVec2 {
+    IVec2::new(pos.x.div_euclid(FORCE_CELL_SIZE), pos.y.div_euclid(FORCE_CELL_SIZE))
+}
+
+impl ForceField {
+    pub fn new(width: usize, height: usize) -> Self {
+        let width = (width as i32 + FORCE_CELL_SIZE - 1) / FORCE_CELL_SIZE;
+        let height = (height as i32 + FORCE_CELL_SIZE - 1) / FORCE_CELL_SIZE;
+        ForceField {
+            width,
+            height,
+            forces: vec![Vec2::ZERO; (width * height) as usize],
+        }
+    }
+
+    /// Converts a world position into an index into self.forces
+    fn force_idx(&self, pos: IVec2) -> Option<usize> {
+        let p = to_force_cell(pos);
+        if p.x < 0 || p.y < 0 || p.x >= self.width || p.y >= self.height {
+            return None;
+        };
+        Some((p.x + p.y * self.width) as usize)
+    }
+
+    /// Returns the force at the world position
+    pub fn get_force(&self, pos: IVec2) -> Vec2 {
+        self.force_idx(pos).map_or(Vec2::ZERO, |idx| self.forces[idx])
+    }
+
+    /// Sets the force of every force cell touched by the rectangle (in world positions)
+    pub fn set_force_in_rect(&mut self, topleft: IVec2, bottomright: IVec2, force: Vec2) {
+        let min = to_force_cell(topleft).max(IVec2::ZERO);
+        let max = to_force_cell(bottomright).min(IVec2::new(self.width - 1, self.height - 1));
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                self.forces[(x + y * self.width) as usize] = force;
+            };
+        };
+    }
+
+    /// Removes all forces
+    pub fn clear(&mut self) {
+        self.forces.iter_mut().for_each(|f| *f = Vec2::ZERO);
+    }
+
+    /// Returns the world position (top left corner) of every force cell that has a force applied
+    pub fn get_active_positions(&self) -> Vec<IVec2> {
+        self.forces.iter()
+            .enumerate()
+            .filter(|(_, f)| **f != Vec2::ZERO)
+            .map(|(i, _)| IVec2::new(i as i32 % self.width, i as i32 / self.width) * FORCE_CELL_SIZE)
+            .collect()
+    }
+}
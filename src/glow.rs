@@ -0,0 +1,93 @@
+use glam::{IVec2, Vec2};
+
+use crate::{Color, Matrix, CHUNK_SIZE};
+
+
+/// Heat below this counts as cold, the cell is drawn with its normal colour again
+pub const MIN_GLOW_HEAT: f32 = 0.02;
+/// Light slots of the NoiseRenderer before this one are left alone
+pub const FIRST_GLOW_LIGHT: usize = 2;
+
+/// Colours of the blackbody gradient, from barely glowing to white hot
+const GRADIENT: [(f32, [f64; 3]); 5] = [
+    (0.0, [0.25, 0.0, 0.0]),
+    (0.3, [0.8, 0.1, 0.0]),
+    (0.55, [1.0, 0.4, 0.0]),
+    (0.8, [1.0, 0.8, 0.2]),
+    (1.0, [1.0, 1.0, 0.85]),
+];
+
+/// Returns the colour something glows in at the given heat (0 - 1)
+pub fn blackbody_color(heat: f32) -> Color {
+    let heat = heat.clamp(0.0, 1.0);
+    for pair in GRADIENT.windows(2) {
+        let ((t0, c0), (t1, c1)) = (pair[0], pair[1]);
+        if heat <= t1 {
+            let t = ((heat - t0) / (t1 - t0)) as f64;
+            return Color {
+                r: c0[0] + (c1[0] - c0[0]) * t,
+                g: c0[1] + (c1[1] - c0[1]) * t,
+                b: c0[2] + (c1[2] - c0[2]) * t,
+                a: 1.0,
+            };
+        };
+    };
+    let c = GRADIENT[GRADIENT.len() - 1].1;
+    Color { r: c[0], g: c[1], b: c[2], a: 1.0 }
+}
+
+/// Mixes the glow colour over the cells own colour, hotter cells cover more of it
+pub fn glow_color(base: Color, heat: f32) -> Color {
+    let glow = blackbody_color(heat);
+    let t = (heat * 2.0).clamp(0.0, 1.0) as f64;
+    Color {
+        r: base.r + (glow.r - base.r) * t,
+        g: base.g + (glow.g - base.g) * t,
+        b: base.b + (glow.b - base.b) * t,
+        a: base.a,
+    }
+}
+
+/// A light given off by the hot cells of one chunk
+#[derive(Clone, Copy, Debug)]
+pub struct GlowLight {
+    /// Center of the glowing cells in world coordinates
+    pub pos: Vec2,
+    pub color: Color,
+    /// Sum of the heat of all glowing cells
+    pub heat: f32,
+}
+
+impl Matrix {
+    /// Collects the hot cells chunk by chunk and returns the max brightest chunks as lights
+    pub fn collect_glow_lights(&self, max: usize) -> Vec<GlowLight> {
+        let chunks_x = self.chunks_x;
+        let chunks_y = self.chunks_y;
+        // Heat weighted position sum and heat sum per chunk
+        let mut sums = vec![(Vec2::ZERO, 0.0); chunks_x * chunks_y];
+        let storage = self.get_cell_storage();
+        for (idx, heat) in storage.heat.iter().enumerate() {
+            let heat = *heat as f32 / u16::MAX as f32;
+            if heat < MIN_GLOW_HEAT {
+                continue;
+            };
+            let pos = storage.pos_of(idx);
+            let chunk = pos / IVec2::splat(CHUNK_SIZE as i32);
+            let sum = &mut sums[chunk.x as usize + chunk.y as usize * chunks_x];
+            sum.0 += pos.as_vec2() * heat;
+            sum.1 += heat;
+        };
+
+        let mut lights: Vec<GlowLight> = sums.into_iter()
+            .filter(|(_, heat)| *heat > 0.0)
+            .map(|(pos_sum, heat)| GlowLight { pos: pos_sum / heat, color: Color::BLACK, heat })
+            .collect();
+        lights.sort_by(|a, b| b.heat.total_cmp(&a.heat));
+        lights.truncate(max);
+        for light in lights.iter_mut() {
+            // Big fires burn hotter
+            light.color = blackbody_color(0.4 + (light.heat / 400.0).min(0.5));
+        };
+        lights
+    }
+}
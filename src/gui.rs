@@ -1,9 +1,11 @@
 use egui::Align2;
 use egui::{ClippedPrimitive, Context, TexturesDelta, TextureHandle, ColorImage, widgets::ImageButton};
+use egui::plot::{Legend, Line, Plot, PlotPoints};
 use egui_wgpu::renderer::{Renderer, ScreenDescriptor};
 use strum::IntoEnumIterator;
 
-use crate::{Material, Matrix, Assets, UIInfo, ASSETS};
+use crate::{Material, Matrix, Assets, UIInfo, ASSETS, matrix::DEFAULT_GRAVITY, BoundaryMode, Emitter, CollapseMode, DebugOverlay, save::DEFAULT_WORLD_PATH, Stage, profiler::DEFAULT_PROFILE_PATH};
+use glam::Vec2;
 
 use pixels::{wgpu, PixelsContext};
 use winit::event_loop::EventLoopWindowTarget;
@@ -147,6 +149,7 @@ struct Gui {
     /// Only show the egui window when true.
     window_open: bool,
     info_open: bool,
+    world_open: bool,
     material_textures: Vec<(TextureHandle, Material)>,
 }
 impl Gui {
@@ -155,6 +158,7 @@ impl Gui {
         Self {
             window_open: true,
             info_open: false,
+            world_open: false,
             material_textures,
         }
     }
@@ -172,6 +176,10 @@ impl Gui {
                         self.info_open = true;
                         ui.close_menu();
                     };
+                    if ui.button("World Settings").clicked() {
+                        self.world_open = true;
+                        ui.close_menu();
+                    };
                 });
                 ui.separator();
                 ui.checkbox(&mut matrix.brush.place_fire, "Ignite Materials");
@@ -189,14 +197,21 @@ impl Gui {
         .resizable(true)
         .show(ctx, |ui| {
             ui.horizontal_wrapped(|ui| {
-                if self.material_textures.len() != Material::iter().count() {
-                    for mat in unsafe{(*ASSETS).loaded_material_textures.iter()} {
-                        let texture = mat.1;
-                        let (w, h) = (texture.width as usize, texture.height as usize);
-                        let matname = format!("{:?}", mat);
-                        let col_image = ColorImage::from_rgba_unmultiplied([w, h], &texture.pixels);
-                        let tex = ctx.load_texture(matname, col_image, Default::default());
-                        self.material_textures.push((tex, *mat.0));
+                let materials = Material::all();
+                if self.material_textures.len() != materials.len() {
+                    self.material_textures.clear();
+                    for mat in materials {
+                        let col_image = match unsafe{(*ASSETS).loaded_material_textures.get(&mat)} {
+                            Some(texture) => ColorImage::from_rgba_unmultiplied([texture.width as usize, texture.height as usize], &texture.pixels),
+                            // Custom materials have no texture, they are shown in their colour
+                            None => {
+                                let color = mat.get_color();
+                                let color = egui::Color32::from_rgb((color.r * 255.0) as u8, (color.g * 255.0) as u8, (color.b * 255.0) as u8);
+                                ColorImage::new([32, 32], color)
+                            },
+                        };
+                        let tex = ctx.load_texture(format!("{:?}", mat), col_image, Default::default());
+                        self.material_textures.push((tex, mat));
                     };
                 };
 
@@ -204,7 +219,108 @@ impl Gui {
                     let resp = ui.add(ImageButton::new(mattex, (32.0, 32.0)))
                         .on_hover_text(format!("{:?}", mat));
                     if resp.clicked() {
-                        matrix.brush.material_index = Material::iter().position(|x| &x == mat).unwrap();
+                        matrix.brush.material_index = Material::all().iter().position(|x| x == mat).unwrap();
+                    };
+                };
+            });
+            ui.separator();
+            ui.label("Emitter");
+            ui.horizontal_wrapped(|ui| {
+                for (name, preset) in Emitter::presets() {
+                    if ui.button(name).clicked() {
+                        matrix.brush.emitter = preset;
+                        matrix.brush.material_index = Material::Emitter.get_id() as usize;
+                    };
+                };
+            });
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_label("Emits")
+                    .selected_text(format!("{:?}", matrix.brush.emitter.material))
+                    .show_ui(ui, |ui| {
+                        for mat in Material::all().into_iter().filter(|m| *m != Material::Empty && *m != Material::Emitter && *m != Material::Drain) {
+                            ui.selectable_value(&mut matrix.brush.emitter.material, mat, format!("{:?}", mat));
+                        };
+                    });
+                ui.add(egui::widgets::Slider::new(&mut matrix.brush.emitter.rate, 0.0..=5.0).text("Cells per frame"));
+            });
+        });
+
+        egui::Window::new("World Settings")
+        .open(&mut self.world_open)
+        .resizable(false)
+        .show(ctx, |ui| {
+            let mut gravity = matrix.get_gravity();
+            ui.horizontal(|ui| {
+                ui.label("Gravity: ");
+                ui.add(egui::DragValue::new(&mut gravity.x).speed(0.01).prefix("x: "));
+                ui.add(egui::DragValue::new(&mut gravity.y).speed(0.01).prefix("y: "));
+            });
+            ui.horizontal(|ui| {
+                if ui.button("Down").clicked() {
+                    gravity = DEFAULT_GRAVITY;
+                };
+                if ui.button("Up").clicked() {
+                    gravity = -DEFAULT_GRAVITY;
+                };
+                if ui.button("Rotate 90°").clicked() {
+                    gravity = gravity.perp();
+                };
+                if ui.button("Zero").clicked() {
+                    gravity = Vec2::ZERO;
+                };
+            });
+            matrix.set_gravity(gravity);
+            ui.separator();
+            ui.checkbox(&mut matrix.brush.place_force, "Paint Wind");
+            ui.horizontal(|ui| {
+                ui.label("Wind force: ");
+                ui.add(egui::DragValue::new(&mut matrix.brush.force.x).speed(0.01).prefix("x: "));
+                ui.add(egui::DragValue::new(&mut matrix.brush.force.y).speed(0.01).prefix("y: "));
+            });
+            if ui.button("Clear Wind").clicked() {
+                matrix.force_field.clear();
+            };
+            ui.separator();
+            let mut boundaries = matrix.get_boundaries();
+            egui::Grid::new("boundaries").show(ui, |ui| {
+                for (name, mode) in [("Left", &mut boundaries.left), ("Right", &mut boundaries.right), ("Top", &mut boundaries.top), ("Bottom", &mut boundaries.bottom)] {
+                    ui.label(name);
+                    egui::ComboBox::from_id_source(name)
+                        .selected_text(format!("{:?}", mode))
+                        .show_ui(ui, |ui| {
+                            for option in BoundaryMode::iter() {
+                                ui.selectable_value(mode, option, format!("{:?}", option));
+                            };
+                        });
+                    ui.end_row();
+                };
+            });
+            matrix.set_boundaries(boundaries);
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut matrix.structural_integrity, "Unsupported solids collapse into");
+                egui::ComboBox::from_id_source("collapse_mode")
+                    .selected_text(format!("{:?}", matrix.collapse_mode))
+                    .show_ui(ui, |ui| {
+                        for mode in CollapseMode::iter() {
+                            ui.selectable_value(&mut matrix.collapse_mode, mode, format!("{:?}", mode));
+                        };
+                    });
+            });
+            ui.separator();
+            ui.horizontal(|ui| {
+                if ui.button("Save World").clicked() {
+                    if let Err(err) = matrix.save_world(DEFAULT_WORLD_PATH) {
+                        println!("Could not save the world: {}", err);
+                    };
+                };
+                // The hotkey also restarts lockstep sessions, this button can't reach them
+                let can_load = matrix.lockstep_outbox.is_none() && !matrix.is_recording();
+                let load = ui.add_enabled(can_load, egui::Button::new("Load World"))
+                    .on_disabled_hover_text("Not available during lockstep sessions and recordings, use F7 as lockstep host");
+                if load.clicked() {
+                    if let Err(err) = matrix.load_world(DEFAULT_WORLD_PATH) {
+                        println!("Could not load the world: {}", err);
                     };
                 };
             });
@@ -214,7 +330,75 @@ impl Gui {
         .open(&mut self.info_open)
         .anchor(Align2::RIGHT_TOP, (-5.0, 5.0))
         .show(ctx, |ui| {
-            ui.label(format!("FPS: {}", ui_info.num_frames.round()));
+            ui.label(format!("FPS: {}", ui_info.fps.round()));
+            ui.checkbox(&mut matrix.profiler.enabled, "Profile frames");
+            if matrix.profiler.enabled {
+                profiler_ui(ui, matrix);
+            };
+            ui.label(format!("Particles: {}", matrix.get_particles().len()));
+            let stats = matrix.get_step_stats();
+            ui.label(format!("Active chunks: {}", stats.active_chunks));
+            let saved = 1.0 - stats.stepped_area as f32 / stats.active_area.max(1) as f32;
+            ui.label(format!("Stepped cells: {} of {} ({:.0}% saved)", stats.stepped_area, stats.active_area, saved * 100.0));
+            ui.label(format!("Rendered chunks: {} of {}", matrix.get_rendered_chunks(), matrix.chunks.len()));
+            ui.checkbox(&mut matrix.shading, "Shading");
+            egui::ComboBox::from_label("Overlay (F5)")
+                .selected_text(format!("{:?}", matrix.debug_overlay))
+                .show_ui(ui, |ui| {
+                    for overlay in DebugOverlay::iter() {
+                        ui.selectable_value(&mut matrix.debug_overlay, overlay, format!("{:?}", overlay));
+                    };
+                });
+            for (color, text) in matrix.debug_overlay.get_legend() {
+                ui.horizontal(|ui| {
+                    let (rect, _) = ui.allocate_exact_size(egui::vec2(12.0, 12.0), egui::Sense::hover());
+                    let color = egui::Color32::from_rgb((color.r * 255.0) as u8, (color.g * 255.0) as u8, (color.b * 255.0) as u8);
+                    ui.painter().rect_filled(rect, 2.0, color);
+                    ui.label(text);
+                });
+            };
+            ui.checkbox(&mut matrix.check_consistency, "Check consistency");
+            if matrix.check_consistency {
+                let violations = matrix.get_consistency_violations();
+                if violations.is_empty() {
+                    ui.label("No inconsistencies");
+                } else {
+                    ui.colored_label(egui::Color32::RED, format!("{} inconsistencies", violations.len()));
+                    for violation in violations.iter().take(5) {
+                        ui.label(violation.to_string());
+                    };
+                };
+            };
         });
     }
-}
\ No newline at end of file
+}
+
+/// Graph of the stage timings over the history of the profiler, their averages and a button to dump them
+fn profiler_ui(ui: &mut egui::Ui, matrix: &Matrix) {
+    let history = matrix.profiler.get_history();
+    Plot::new("profiler")
+        .height(120.0)
+        .width(260.0)
+        .include_y(0.0)
+        .allow_drag(false)
+        .allow_zoom(false)
+        .legend(Legend::default())
+        .show(ui, |plot_ui| {
+            for stage in Stage::iter() {
+                let points: PlotPoints = history.iter()
+                    .enumerate()
+                    .map(|(i, frame)| [i as f64, frame.get(stage).as_secs_f64() * 1000.0])
+                    .collect();
+                plot_ui.line(Line::new(points).name(format!("{:?}", stage)));
+            };
+        });
+    for stage in Stage::iter() {
+        ui.label(format!("{:?}: {:.2} ms", stage, matrix.profiler.get_average(stage).as_secs_f64() * 1000.0));
+    };
+    if ui.button("Save CSV").clicked() {
+        match matrix.profiler.save_csv(DEFAULT_PROFILE_PATH) {
+            Ok(()) => println!("Saved profile to {}", DEFAULT_PROFILE_PATH),
+            Err(err) => println!("Could not save the profile: {}", err),
+        };
+    };
+}
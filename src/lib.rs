@@ -1,6 +1,9 @@
 pub mod cell;
 pub use cell::Cell;
 
+pub mod storage;
+pub use storage::CellStorage;
+
 pub mod cellhandler;
 pub use cellhandler::cell_handler;
 
@@ -9,6 +12,9 @@ pub use material::{Material, MaterialType};
 
 pub mod reaction;
 
+pub mod behavior;
+pub use behavior::{CellBehavior, BehaviorRegistry};
+
 
 mod assets;
 use assets::Assets;
@@ -24,9 +30,65 @@ pub use matrix::Matrix;
 pub mod chunk;
 pub use chunk::Chunk;
 
+pub mod force;
+pub use force::ForceField;
+
+pub mod particle;
+pub use particle::Particle;
+
+pub mod boundary;
+pub use boundary::{Boundaries, BoundaryMode};
+
+pub mod emitter;
+pub use emitter::Emitter;
+
+pub mod save;
+
+pub mod replay;
+pub use replay::{Replay, ReplayPlayer};
+
+pub mod lockstep;
+pub use lockstep::LockstepSession;
+
+pub mod control;
+pub use control::ControlServer;
+
+pub mod script;
+pub use script::Scripts;
+
+pub mod structure;
+pub use structure::CollapseMode;
+
+pub mod event;
+pub use event::{SimEvent, SimEventKind, EventFilter};
+
+pub mod consistency;
+pub use consistency::ConsistencyViolation;
+
+pub mod query;
+pub use query::{Region, RayFilter, RayHit};
+
+pub mod audio;
+pub use audio::{SoundSynth, SoundActivity, AudioOutput, WavOutput};
+
 use once_cell::sync::Lazy;
 pub use pixels::wgpu::Color;
 
+pub mod glow;
+pub use glow::GlowLight;
+
+pub mod occlusion;
+pub use occlusion::OcclusionMask;
+
+pub mod overlay;
+pub use overlay::DebugOverlay;
+
+pub mod shading;
+pub use shading::Shading;
+
+pub mod profiler;
+pub use profiler::{Profiler, Stage};
+
 pub mod renderer;
 pub use renderer::NoiseRenderer;
 
@@ -40,21 +102,21 @@ pub const COLOR_EMPTY: Color = Color { r: 1.0, g: 0.0, b: 0.8, a: 1.0 };
 
 pub type Rng = fastrand::Rng;
 const SEED: u64 = 1234;
-pub static mut RNG: Lazy<Rng> = Lazy::new(|| fastrand::Rng::with_seed(fastrand::u64(0..100)));
-pub fn gen_range(min: f32, max: f32) -> f32 {
-    return min + unsafe{&mut RNG}.f32() * max;
+pub fn gen_range(rng: &Rng, min: f32, max: f32) -> f32 {
+    return min + rng.f32() * max;
 }
 
 
 //pub type RngThr<'a> = std::sync::Arc<std::sync::Mutex<&'a mut rand::rngs::ThreadRng>>;
 
 pub struct UIInfo {
-    pub num_frames: f32,
+    /// Frames per second, see Profiler::get_fps
+    pub fps: f32,
 }
 impl UIInfo {
     pub fn new() -> Self {
         UIInfo {
-            num_frames: 30.0,
+            fps: 30.0,
         }
     }
 }
@@ -67,8 +129,8 @@ impl Default for UIInfo {
 
 const MULTIPLIER_OPTIONS: [i32; 2] = [-1, 1];
 /// Returns 1 or -1 at random
-pub fn rand_multiplier() -> i32 {
-    MULTIPLIER_OPTIONS[unsafe{&mut *RNG}.usize(0..MULTIPLIER_OPTIONS.len())]
+pub fn rand_multiplier(rng: &Rng) -> i32 {
+    MULTIPLIER_OPTIONS[rng.usize(0..MULTIPLIER_OPTIONS.len())]
 }
 
 pub fn darken_color(mut color: Color, amount: f64) -> Color {
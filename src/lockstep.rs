@@ -0,0 +1,545 @@
+use std::{collections::{HashMap, VecDeque}, io::{self, BufReader, BufWriter, Read, Write}, net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs}, sync::mpsc::{self, Receiver, Sender}, thread};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::replay::{read_brush_action, write_brush_action, BrushAction, Replay};
+use crate::save::invalid_data;
+use crate::Matrix;
+
+
+/// Address the windowed app hosts on and joins when no other is given
+pub const DEFAULT_LOCKSTEP_ADDR: &str = "127.0.0.1:7878";
+/// How many ticks the host may get ahead of the slowest client before it waits
+pub const MAX_TICKS_AHEAD: u64 = 4;
+/// Most brush actions a single frame may carry, peers sending more get dropped
+const MAX_FRAME_ACTIONS: u32 = 4096;
+/// Most brush actions the host keeps waiting for a frame, further ones get dropped
+const MAX_PENDING_ACTIONS: usize = 4 * MAX_FRAME_ACTIONS as usize;
+
+
+/// What peers send each other. Messages follow each other on the stream without any framing
+enum Message {
+    /// Host: everyone starts over from this world, seed and settings
+    Welcome { epoch: u32, start: Replay },
+    /// Host: the brush actions to draw before the update of tick, and the state hash right before them
+    Frame { epoch: u32, tick: u64, hash: u64, actions: Vec<BrushAction> },
+    /// Client: a brush action the host should hand out with the next tick
+    Brush(BrushAction),
+    /// Client: the update of a tick is done, tick is Matrix::get_tick after it
+    Ack { epoch: u32, tick: u64, hash: u64 },
+}
+
+fn write_message<W: Write>(writer: &mut W, message: &Message) -> io::Result<()> {
+    match message {
+        Message::Welcome { epoch, start } => {
+            writer.write_u8(0)?;
+            writer.write_u32::<LittleEndian>(*epoch)?;
+            start.write(writer)?;
+        },
+        Message::Frame { epoch, tick, hash, actions } => {
+            writer.write_u8(1)?;
+            writer.write_u32::<LittleEndian>(*epoch)?;
+            writer.write_u64::<LittleEndian>(*tick)?;
+            writer.write_u64::<LittleEndian>(*hash)?;
+            writer.write_u32::<LittleEndian>(actions.len() as u32)?;
+            for action in actions {
+                write_brush_action(writer, action)?;
+            };
+        },
+        Message::Brush(action) => {
+            writer.write_u8(2)?;
+            write_brush_action(writer, action)?;
+        },
+        Message::Ack { epoch, tick, hash } => {
+            writer.write_u8(3)?;
+            writer.write_u32::<LittleEndian>(*epoch)?;
+            writer.write_u64::<LittleEndian>(*tick)?;
+            writer.write_u64::<LittleEndian>(*hash)?;
+        },
+    };
+    Ok(())
+}
+
+fn read_message<R: Read>(reader: &mut R) -> io::Result<Message> {
+    match reader.read_u8()? {
+        0 => Ok(Message::Welcome { epoch: reader.read_u32::<LittleEndian>()?, start: Replay::read(reader)? }),
+        1 => {
+            let epoch = reader.read_u32::<LittleEndian>()?;
+            let tick = reader.read_u64::<LittleEndian>()?;
+            let hash = reader.read_u64::<LittleEndian>()?;
+            let num_actions = reader.read_u32::<LittleEndian>()?;
+            if num_actions > MAX_FRAME_ACTIONS {
+                return Err(invalid_data("too many brush actions in a lockstep frame"));
+            };
+            let mut actions = vec![];
+            for _ in 0..num_actions {
+                actions.push(read_brush_action(reader)?);
+            };
+            Ok(Message::Frame { epoch, tick, hash, actions })
+        },
+        2 => Ok(Message::Brush(read_brush_action(reader)?)),
+        3 => Ok(Message::Ack {
+            epoch: reader.read_u32::<LittleEndian>()?,
+            tick: reader.read_u64::<LittleEndian>()?,
+            hash: reader.read_u64::<LittleEndian>()?,
+        }),
+        _ => Err(invalid_data("unknown lockstep message")),
+    }
+}
+
+/// Reads messages from the stream on its own thread and hands them to the session, None once the connection is gone
+fn spawn_reader(id: usize, stream: TcpStream, sender: Sender<(usize, Option<Message>)>) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        loop {
+            match read_message(&mut reader) {
+                Ok(message) => {
+                    if sender.send((id, Some(message))).is_err() {
+                        break;
+                    };
+                },
+                Err(_) => {
+                    let _ = sender.send((id, None));
+                    break;
+                },
+            };
+        };
+    });
+}
+
+fn send(writer: &mut BufWriter<TcpStream>, message: &Message) -> io::Result<()> {
+    write_message(writer, message)?;
+    writer.flush()
+}
+
+/// Draws the actions of a frame and updates the matrix
+fn step_frame(matrix: &mut Matrix, actions: &[BrushAction]) {
+    for action in actions {
+        matrix.apply_brush_action(action);
+    };
+    matrix.update();
+}
+
+/// A client connected to the host
+struct Peer {
+    id: usize,
+    addr: SocketAddr,
+    writer: BufWriter<TcpStream>,
+    /// Last tick the client finished in the current epoch
+    acked_tick: u64,
+}
+
+enum Role {
+    Host {
+        listener: TcpListener,
+        peers: Vec<Peer>,
+        next_peer_id: usize,
+        /// Brush actions of all peers that go out with the next frames, at most MAX_FRAME_ACTIONS per frame
+        pending: VecDeque<BrushAction>,
+        /// State hash at every tick clients haven't confirmed yet
+        hashes: HashMap<u64, u64>,
+    },
+    Client {
+        writer: BufWriter<TcpStream>,
+        /// False until the host sent the world
+        started: bool,
+        frames: VecDeque<(u64, u64, Vec<BrushAction>)>,
+    },
+}
+
+/// Shares one world between several instances over TCP.
+///
+/// The host sends everyone its world and a seed for the RNG, after that brush actions are the only input. Clients send their
+/// brush actions to the host, which hands them out together with a tick number, and every peer draws them right before
+/// that tick's Matrix::update. The host runs at most MAX_TICKS_AHEAD ticks ahead of the slowest client.
+///
+/// Every tick the host sends its state hash and the clients send back theirs, a mismatch is reported as a desync.
+/// Like replays, only brush actions are shared: scripts and settings changed on a single peer make the worlds drift apart,
+/// LockstepSession::restart sends the world of the host to everyone again
+pub struct LockstepSession {
+    role: Role,
+    /// Counts the restarts, messages of an older epoch are ignored
+    epoch: u32,
+    incoming: Receiver<(usize, Option<Message>)>,
+    incoming_sender: Sender<(usize, Option<Message>)>,
+    /// State hash of the matrix after its last lockstep update
+    last_hash: u64,
+    /// First tick at which the state hashes differed
+    desync: Option<u64>,
+    connected: bool,
+}
+
+impl LockstepSession {
+    /// Waits for clients at addr. The world of the matrix gets reloaded and the RNG seeded, see LockstepSession::restart
+    pub fn host<A: ToSocketAddrs>(addr: A, matrix: &mut Matrix) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let (incoming_sender, incoming) = mpsc::channel();
+        let mut session = LockstepSession {
+            role: Role::Host { listener, peers: vec![], next_peer_id: 0, pending: VecDeque::new(), hashes: HashMap::new() },
+            epoch: 0,
+            incoming,
+            incoming_sender,
+            last_hash: 0,
+            desync: None,
+            connected: true,
+        };
+        session.restart(matrix)?;
+        Ok(session)
+    }
+
+    /// Connects to a host. The world of the matrix gets replaced as soon as the host sends its own
+    pub fn join<A: ToSocketAddrs>(addr: A, matrix: &mut Matrix) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let (incoming_sender, incoming) = mpsc::channel();
+        spawn_reader(0, stream.try_clone()?, incoming_sender.clone());
+        matrix.lockstep_outbox = Some(vec![]);
+        Ok(LockstepSession {
+            role: Role::Client { writer: BufWriter::new(stream), started: false, frames: VecDeque::new() },
+            epoch: 0,
+            incoming,
+            incoming_sender,
+            last_hash: 0,
+            desync: None,
+            connected: true,
+        })
+    }
+
+    pub fn is_host(&self) -> bool {
+        matches!(self.role, Role::Host { .. })
+    }
+
+    /// Connected clients for the host, 1 for a client that is still connected to its host
+    pub fn get_num_peers(&self) -> usize {
+        match &self.role {
+            Role::Host { peers, .. } => peers.len(),
+            Role::Client { .. } => self.connected as usize,
+        }
+    }
+
+    /// False once a client lost the connection to its host
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// The first tick at which a peer ended up in a different state, None while everyone is in sync
+    pub fn get_desync(&self) -> Option<u64> {
+        self.desync
+    }
+
+    /// Whether all clients finished every tick of the host. Always true for clients
+    pub fn is_caught_up(&self, matrix: &Matrix) -> bool {
+        match &self.role {
+            Role::Host { peers, .. } => peers.iter().all(|p| p.acked_tick >= matrix.get_tick()),
+            Role::Client { .. } => true,
+        }
+    }
+
+    /// Host only: reloads the world from its saved form, seeds the RNG with a new seed and sends everything to all clients,
+    /// which start over from it. This also ends a desync
+    pub fn restart(&mut self, matrix: &mut Matrix) -> io::Result<()> {
+        let Role::Host { peers, pending, hashes, .. } = &mut self.role else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "only the host can restart the session"));
+        };
+        let start = matrix.restart_from_snapshot(fastrand::u64(..))?;
+        matrix.lockstep_outbox = Some(vec![]);
+        self.epoch += 1;
+        self.last_hash = matrix.get_state_hash();
+        self.desync = None;
+        pending.clear();
+        hashes.clear();
+        hashes.insert(matrix.get_tick(), self.last_hash);
+        let welcome = Message::Welcome { epoch: self.epoch, start };
+        peers.retain_mut(|peer| {
+            peer.acked_tick = matrix.get_tick();
+            match send(&mut peer.writer, &welcome) {
+                Ok(()) => true,
+                Err(err) => {
+                    log::error!("Lost lockstep client {}: {}", peer.addr, err);
+                    false
+                },
+            }
+        });
+        Ok(())
+    }
+
+    /// Handles everything that arrived over the network and sends the local brush actions, without updating the matrix
+    pub fn poll(&mut self, matrix: &mut Matrix) {
+        let outbox = std::mem::take(matrix.lockstep_outbox.get_or_insert_with(Vec::new));
+        if self.is_host() {
+            self.poll_host(matrix, outbox);
+        } else {
+            self.poll_client(matrix, outbox);
+        };
+    }
+
+    /// Polls and then advances the matrix as far as lockstep allows. Use it instead of Matrix::update, returns the number of updates
+    pub fn update(&mut self, matrix: &mut Matrix) -> usize {
+        self.poll(matrix);
+        match &mut self.role {
+            Role::Host { peers, pending, hashes, .. } => {
+                let tick = matrix.get_tick();
+                if peers.iter().any(|p| p.acked_tick + MAX_TICKS_AHEAD < tick) {
+                    return 0;
+                };
+                let num_actions = pending.len().min(MAX_FRAME_ACTIONS as usize);
+                let actions = pending.drain(..num_actions).map(|action| BrushAction { tick, ..action }).collect();
+                let frame = Message::Frame { epoch: self.epoch, tick, hash: self.last_hash, actions };
+                peers.retain_mut(|peer| match send(&mut peer.writer, &frame) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        log::error!("Lost lockstep client {}: {}", peer.addr, err);
+                        false
+                    },
+                });
+                let Message::Frame { actions, .. } = frame else { unreachable!() };
+                step_frame(matrix, &actions);
+                self.last_hash = matrix.get_state_hash();
+                hashes.insert(matrix.get_tick(), self.last_hash);
+                // Hashes every client confirmed aren't needed anymore
+                let oldest = peers.iter().map(|p| p.acked_tick).min().unwrap_or(matrix.get_tick());
+                hashes.retain(|t, _| *t >= oldest);
+                1
+            },
+            Role::Client { writer, started, frames } => {
+                if !*started {
+                    return 0;
+                };
+                let mut num_updates = 0;
+                while let Some((tick, hash, actions)) = frames.pop_front() {
+                    if self.desync.is_none() && (tick != matrix.get_tick() || hash != self.last_hash) {
+                        log::error!("Lockstep desync: state differs from the host at tick {}", tick);
+                        self.desync = Some(tick);
+                    };
+                    step_frame(matrix, &actions);
+                    self.last_hash = matrix.get_state_hash();
+                    num_updates += 1;
+                    let ack = Message::Ack { epoch: self.epoch, tick: matrix.get_tick(), hash: self.last_hash };
+                    if self.connected && send(writer, &ack).is_err() {
+                        self.connected = false;
+                    };
+                };
+                num_updates
+            },
+        }
+    }
+
+    fn poll_host(&mut self, matrix: &mut Matrix, outbox: Vec<BrushAction>) {
+        let Role::Host { listener, peers, next_peer_id, pending, hashes } = &mut self.role else { return };
+        let mut num_dropped = 0;
+        let mut queue = |pending: &mut VecDeque<BrushAction>, action| {
+            if pending.len() < MAX_PENDING_ACTIONS {
+                pending.push_back(action);
+            } else {
+                num_dropped += 1;
+            };
+        };
+        for action in outbox {
+            queue(pending, action);
+        };
+
+        let mut joined = false;
+        loop {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    let added = stream.set_nonblocking(false)
+                        .and_then(|_| stream.set_nodelay(true))
+                        .and_then(|_| stream.try_clone());
+                    match added {
+                        Ok(reader) => {
+                            spawn_reader(*next_peer_id, reader, self.incoming_sender.clone());
+                            peers.push(Peer { id: *next_peer_id, addr, writer: BufWriter::new(stream), acked_tick: 0 });
+                            *next_peer_id += 1;
+                            log::info!("Lockstep client {} joined", addr);
+                            joined = true;
+                        },
+                        Err(err) => log::error!("Could not accept lockstep client {}: {}", addr, err),
+                    };
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("Could not accept lockstep client: {}", err);
+                    break;
+                },
+            };
+        };
+
+        while let Ok((id, message)) = self.incoming.try_recv() {
+            let Some(index) = peers.iter().position(|p| p.id == id) else { continue };
+            match message {
+                Some(Message::Brush(action)) => queue(pending, action),
+                Some(Message::Ack { epoch, tick, hash }) if epoch == self.epoch => {
+                    peers[index].acked_tick = peers[index].acked_tick.max(tick);
+                    if self.desync.is_none() && hashes.get(&tick).is_some_and(|h| *h != hash) {
+                        log::error!("Lockstep desync: client {} differs from the host at tick {}", peers[index].addr, tick);
+                        self.desync = Some(tick);
+                    };
+                },
+                Some(_) => {},
+                None => {
+                    let peer = peers.remove(index);
+                    let _ = peer.writer.get_ref().shutdown(Shutdown::Both);
+                    log::info!("Lockstep client {} left", peer.addr);
+                },
+            };
+        };
+
+        if num_dropped > 0 {
+            log::error!("Dropped {} lockstep brush actions, more than {} were waiting", num_dropped, MAX_PENDING_ACTIONS);
+        };
+
+        // Everyone has to start from the same state, including the clients that were there already
+        if joined {
+            if let Err(err) = self.restart(matrix) {
+                log::error!("Could not restart the lockstep session: {}", err);
+            };
+        };
+    }
+
+    fn poll_client(&mut self, matrix: &mut Matrix, outbox: Vec<BrushAction>) {
+        let Role::Client { writer, started, frames } = &mut self.role else { return };
+        while let Ok((_, message)) = self.incoming.try_recv() {
+            match message {
+                Some(Message::Welcome { epoch, start }) => {
+                    if let Err(err) = start.restart(matrix) {
+                        log::error!("Could not start the world of the lockstep host: {}", err);
+                        continue;
+                    };
+                    matrix.lockstep_outbox = Some(vec![]);
+                    self.epoch = epoch;
+                    self.last_hash = matrix.get_state_hash();
+                    self.desync = None;
+                    *started = true;
+                    frames.clear();
+                },
+                Some(Message::Frame { epoch, tick, hash, actions }) if epoch == self.epoch => frames.push_back((tick, hash, actions)),
+                Some(_) => {},
+                None => {
+                    if self.connected {
+                        log::info!("The lockstep host left");
+                    };
+                    self.connected = false;
+                },
+            };
+        };
+        if self.connected {
+            for action in outbox {
+                if send(writer, &Message::Brush(action)).is_err() {
+                    self.connected = false;
+                    break;
+                };
+            };
+        };
+    }
+
+    /// Leaves the session, the matrix draws its brush right away again
+    pub fn close(self, matrix: &mut Matrix) {
+        matrix.lockstep_outbox = None;
+    }
+}
+
+impl Drop for LockstepSession {
+    fn drop(&mut self) {
+        // Unblocks the reader threads
+        match &self.role {
+            Role::Host { peers, .. } => {
+                for peer in peers {
+                    let _ = peer.writer.get_ref().shutdown(Shutdown::Both);
+                };
+            },
+            Role::Client { writer, .. } => {
+                let _ = writer.get_ref().shutdown(Shutdown::Both);
+            },
+        };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use glam::IVec2;
+
+    use super::*;
+    use crate::Material;
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut written = vec![];
+        write_message(&mut written, &Message::Frame { epoch: 0, tick: 0, hash: 0, actions: vec![] }).unwrap();
+        assert!(read_message(&mut written.as_slice()).is_ok());
+        let num_actions_offset = 1 + 4 + 8 + 8;
+        written[num_actions_offset..num_actions_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(read_message(&mut written.as_slice()).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Updates both sessions until done, panics if that takes too long
+    fn run_until(
+        host: &mut LockstepSession, host_matrix: &mut Matrix, client: &mut LockstepSession, client_matrix: &mut Matrix,
+        done: impl Fn(&LockstepSession, &Matrix, &Matrix) -> bool,
+    ) {
+        let start = std::time::Instant::now();
+        while !done(host, host_matrix, client_matrix) {
+            assert!(start.elapsed().as_secs() < 30, "lockstep session got stuck");
+            host.update(host_matrix);
+            client.update(client_matrix);
+            thread::sleep(std::time::Duration::from_millis(1));
+        };
+    }
+
+    #[test]
+    fn floods_of_brush_actions_are_spread_over_frames() {
+        let mut host_matrix = Matrix::new_empty(64, 64);
+        let mut host = LockstepSession::host("127.0.0.1:0", &mut host_matrix).unwrap();
+        let Role::Host { listener, .. } = &host.role else { unreachable!() };
+        let mut client_matrix = Matrix::new_empty(64, 64);
+        let mut client = LockstepSession::join(listener.local_addr().unwrap(), &mut client_matrix).unwrap();
+        let num_pending = |host: &LockstepSession| match &host.role {
+            Role::Host { pending, .. } => pending.len(),
+            Role::Client { .. } => unreachable!(),
+        };
+        run_until(&mut host, &mut host_matrix, &mut client, &mut client_matrix, |host, _, client_matrix| {
+            host.get_num_peers() == 1 && client_matrix.get_tick() > 0
+        });
+
+        // Single cells keep the thousands of brush actions fast
+        host_matrix.brush.size = 1;
+        client_matrix.brush.size = 1;
+        // More than a frame holds from the host and more than the host keeps waiting, plus some from the client
+        let num_actions = MAX_PENDING_ACTIONS + MAX_FRAME_ACTIONS as usize;
+        for i in 0..num_actions {
+            host_matrix.draw_brush(IVec2::new(i as i32 % 64, 10), Material::Sand);
+        };
+        for i in 0..100 {
+            client_matrix.draw_brush(IVec2::new(i % 64, 20), Material::Water);
+        };
+        host.poll(&mut host_matrix);
+        assert_eq!(num_pending(&host), MAX_PENDING_ACTIONS);
+
+        run_until(&mut host, &mut host_matrix, &mut client, &mut client_matrix, |host, host_matrix, client_matrix| {
+            num_pending(host) == 0 && client_matrix.get_tick() == host_matrix.get_tick()
+                && host_matrix.count_materials()[Material::Water.get_id() as usize] > 0
+        });
+        // Waits for the ack of the last tick without running another one
+        while !host.is_caught_up(&host_matrix) {
+            host.poll(&mut host_matrix);
+            thread::sleep(std::time::Duration::from_millis(1));
+        };
+        assert!(client.is_connected());
+        assert_eq!(host.get_num_peers(), 1);
+        assert_eq!((host.get_desync(), client.get_desync()), (None, None));
+        assert_eq!(host_matrix.get_state_hash(), client_matrix.get_state_hash());
+    }
+
+    #[test]
+    fn welcome_needs_a_world_of_the_same_size() {
+        let mut host = Matrix::new_empty(96, 64);
+        let start = host.restart_from_snapshot(1).unwrap();
+        let mut written = vec![];
+        write_message(&mut written, &Message::Welcome { epoch: 0, start }).unwrap();
+        let Ok(Message::Welcome { start, .. }) = read_message(&mut written.as_slice()) else { panic!("no welcome") };
+        let mut client = Matrix::new_empty(64, 64);
+        assert!(start.restart(&mut client).is_err());
+        assert_eq!(client.width, 64);
+    }
+}
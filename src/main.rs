@@ -3,7 +3,7 @@
 
 use std::{time::Duration};
 
-use glam::IVec2;
+use glam::{IVec2, Vec2};
 use log::{error};
 use pixels::{Error, Pixels, SurfaceTexture, wgpu};
 use winit::{
@@ -14,23 +14,74 @@ use winit::{
 };
 use winit_input_helper::WinitInputHelper;
 
-use falling_rust::{Matrix, WIDTH, HEIGHT, SCALE, Framework, UIInfo, matrix::CHUNK_SIZE_VEC, NoiseRenderer, Color};
+use falling_rust::{Matrix, WIDTH, HEIGHT, SCALE, Framework, UIInfo, matrix::CHUNK_SIZE_VEC, NoiseRenderer, OcclusionMask, Color, save::DEFAULT_WORLD_PATH, Stage, Replay, ReplayPlayer, replay::DEFAULT_REPLAY_PATH, LockstepSession, lockstep::DEFAULT_LOCKSTEP_ADDR, ControlServer, control::DEFAULT_CONTROL_ADDR, script::{DEFAULT_MATERIAL_SCRIPT_DIR, DEFAULT_SCENARIO_PATH}};
 
 mod texture;
 use texture::Texture;
 
+/// Light slots of the noise renderer used for glowing cells
+const NUM_GLOW_LIGHTS: usize = 30;
+
+
+/// Command line options, each with an optional address: `--host [ADDR]` or `--join [ADDR]` start a lockstep session,
+/// `--control [ADDR]` serves the control API
+fn parse_args() -> Vec<(String, Option<String>)> {
+    let mut options = vec![];
+    let mut args = std::env::args().skip(1).peekable();
+    while let Some(option) = args.next() {
+        let addr = args.next_if(|arg| !arg.starts_with("--"));
+        options.push((option, addr));
+    }
+    options
+}
+
+fn start_lockstep(host: bool, addr: Option<String>, matrix: &mut Matrix) -> Option<LockstepSession> {
+    let addr = addr.unwrap_or_else(|| DEFAULT_LOCKSTEP_ADDR.to_string());
+    let started = if host { LockstepSession::host(&addr, matrix) } else { LockstepSession::join(&addr, matrix) };
+    match started {
+        Ok(session) => {
+            println!("{} lockstep session on {}", if session.is_host() { "Hosting" } else { "Joined" }, addr);
+            Some(session)
+        },
+        Err(err) => {
+            println!("Could not start the lockstep session on {}: {}", addr, err);
+            None
+        },
+    }
+}
+
+fn start_control(addr: Option<String>) -> Option<ControlServer> {
+    let addr = addr.unwrap_or_else(|| DEFAULT_CONTROL_ADDR.to_string());
+    match ControlServer::bind(&addr) {
+        Ok(server) => {
+            println!("Serving the control API on {}", addr);
+            Some(server)
+        },
+        Err(err) => {
+            println!("Could not serve the control API on {}: {}", addr, err);
+            None
+        },
+    }
+}
+
 
 // TODO: Add rigidbodies (https://youtu.be/prXuyMCgbTc?t=358)
 // TODO: Add sprite system (https://github.com/parasyte/pixels/tree/main/examples/invaders/simple-invaders)
 // TODO: Maybe add (verlet) rope physics
 // TODO: Camera system
 // TODO: Physics (https://parry.rs/)
-// TODO: Audio (https://crates.io/crates/kira)
 
 
 fn main() -> Result<(), Error> {
     //env::set_var("RUST_LOG", "falling_rust=debug");
     env_logger::init();
+    let options = parse_args();
+    let has_option = |name: &str| options.iter().any(|(option, _)| option == name);
+    // Control commands change the world directly, which would desync the peers
+    if has_option("--control") && (has_option("--host") || has_option("--join")) {
+        println!("--control can't be combined with --host or --join");
+        return Ok(());
+    }
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
 
@@ -64,15 +115,37 @@ fn main() -> Result<(), Error> {
 
     let mut time = 0.0;
     let mut noise_renderer = NoiseRenderer::new(&pixels, window_size.width, window_size.height)?;
+    let mut occlusion_mask = OcclusionMask::new(WIDTH as usize, HEIGHT as usize);
 
     let mut ui_info = UIInfo::new();
     let mut matrix = Matrix::new_empty(WIDTH as usize, HEIGHT as usize);
+    let num_scripts = matrix.scripts.load_materials(DEFAULT_MATERIAL_SCRIPT_DIR);
+    if num_scripts > 0 {
+        println!("Loaded {} material scripts from {}", num_scripts, DEFAULT_MATERIAL_SCRIPT_DIR);
+    };
     let mut paused = false;
+    let mut replay_player: Option<ReplayPlayer> = None;
+    let mut lockstep = None;
+    let mut control = None;
+    for (option, addr) in options {
+        match option.as_str() {
+            "--host" => lockstep = start_lockstep(true, addr, &mut matrix),
+            "--join" => lockstep = start_lockstep(false, addr, &mut matrix),
+            "--control" => control = start_control(addr),
+            _ => println!("Unknown argument {}, use --host [ADDR], --join [ADDR] or --control [ADDR]", option),
+        };
+    }
+
+    #[cfg(feature = "live-audio")]
+    let mut audio = match falling_rust::audio::LiveOutput::new() {
+        Ok(output) => Some((falling_rust::SoundSynth::new(output.get_sample_rate()), output)),
+        Err(err) => {
+            error!("Could not start audio output: {err}");
+            None
+        },
+    };
 
     let mut last_update = std::time::SystemTime::now();
-    let mut frame_time = last_update;
-    let start = std::time::SystemTime::now();
-    let mut num_frames = 0;
     
     let diffuse_bytes = include_bytes!("../data/sprites/lamp.png");
     let diffuse_texture = texture::Texture::from_bytes(pixels.device(), pixels.queue(), diffuse_bytes, "lamp.png").unwrap();
@@ -81,17 +154,22 @@ fn main() -> Result<(), Error> {
     event_loop.run(move |event, _, control_flow| {
         // The one and only event that winit_input_helper doesn't have for us...
         let current_time = std::time::SystemTime::now();
-        let frame_delta = current_time.duration_since(frame_time).unwrap();
         let update_delta = current_time.duration_since(last_update).unwrap();
         let should_update = matrix.wait_time_after_frame <= 0.0 || (update_delta >= Duration::from_millis(matrix.wait_time_after_frame as u64));
-        ui_info.num_frames = frame_delta.as_secs_f32() / 60.0;
         if let Event::RedrawRequested(_) = event {
+            ui_info.fps = matrix.profiler.get_fps();
+            let draw_start = matrix.profiler.start();
             matrix.draw(pixels.get_frame_mut());
+            matrix.write_occlusion_mask(&mut occlusion_mask);
+            matrix.profiler.stop(Stage::Draw, draw_start);
 
             // Prepare egui
+            let prepare_start = matrix.profiler.start();
             framework.prepare(&window, &mut matrix, &mut ui_info);
+            matrix.profiler.stop(Stage::EguiPrepare, prepare_start);
 
             // Render everything together
+            let submit_start = matrix.profiler.start();
             let render_result = pixels.render_with(|encoder, render_target, context| {
                 let target_copy = wgpu::ImageCopyTextureBase {
                     texture: &context.texture,
@@ -103,7 +181,9 @@ fn main() -> Result<(), Error> {
                 encoder.copy_texture_to_texture(source, target_copy, wgpu::Extent3d {
                     width: 23, height: 57, depth_or_array_layers: 0
                 });
+                noise_renderer.set_glow_lights(&matrix.collect_glow_lights(NUM_GLOW_LIGHTS), Vec2::new(WIDTH as f32, HEIGHT as f32));
                 noise_renderer.update(&context.queue);
+                noise_renderer.set_occlusion_mask(&context.queue, &occlusion_mask);
                 noise_renderer.lights[0].position[0] = time % 2.0;
                 let noise_texture = noise_renderer.get_texture_view();
                 noise_renderer.render(encoder, render_target, context.scaling_renderer.clip_rect());
@@ -118,6 +198,9 @@ fn main() -> Result<(), Error> {
                 Ok(())
             });
 
+            matrix.profiler.stop(Stage::GpuSubmit, submit_start);
+            matrix.profiler.end_frame();
+
             // Basic error handling
             if let Err(err) = render_result {
                 error!("pixels.render() failed: {err}");
@@ -147,6 +230,13 @@ fn main() -> Result<(), Error> {
                 *control_flow = ControlFlow::Exit;
                 return;
             }
+            if let Some(server) = &mut control {
+                server.poll(&mut matrix, &mut paused);
+                if server.is_quit_requested() {
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                };
+            }
             if input.key_pressed(VirtualKeyCode::P) {
                 paused = !paused;
             }
@@ -154,12 +244,77 @@ fn main() -> Result<(), Error> {
                 // Space is frame-step, so ensure we're paused
                 paused = true;
             }
-            if input.key_pressed(VirtualKeyCode::C) {
-                matrix = Matrix::new_empty(WIDTH as usize, HEIGHT as usize);
+            if input.key_pressed(VirtualKeyCode::C) && can_replace_world(&lockstep, &matrix) {
+                let profiler = std::mem::take(&mut matrix.profiler);
+                let scripts = std::mem::take(&mut matrix.scripts);
+                let behaviors = std::mem::take(&mut matrix.behaviors);
+                matrix = Matrix::new(WIDTH as usize, HEIGHT as usize, matrix.get_boundaries());
+                matrix.profiler = profiler;
+                matrix.scripts = scripts;
+                matrix.behaviors = behaviors;
+                restart_lockstep_host(&mut lockstep, &mut matrix);
             }
             if input.key_pressed(VirtualKeyCode::F5) {
-                matrix.debug_draw = !matrix.debug_draw;
-                println!("Debug: {}", matrix.debug_draw);
+                matrix.debug_overlay = matrix.debug_overlay.next();
+                println!("Debug overlay: {:?}", matrix.debug_overlay);
+            }
+            if input.key_pressed(VirtualKeyCode::F6) {
+                match matrix.save_world(DEFAULT_WORLD_PATH) {
+                    Ok(()) => println!("Saved world to {}", DEFAULT_WORLD_PATH),
+                    Err(err) => println!("Could not save the world: {}", err),
+                };
+            }
+            if input.key_pressed(VirtualKeyCode::F7) && can_replace_world(&lockstep, &matrix) {
+                match matrix.load_world(DEFAULT_WORLD_PATH) {
+                    Ok(()) => println!("Loaded world from {}", DEFAULT_WORLD_PATH),
+                    Err(err) => println!("Could not load the world: {}", err),
+                };
+                restart_lockstep_host(&mut lockstep, &mut matrix);
+            }
+            if input.key_pressed(VirtualKeyCode::F8) {
+                if let Some(replay) = matrix.stop_recording() {
+                    match replay.save(DEFAULT_REPLAY_PATH) {
+                        Ok(()) => println!("Saved replay of {} ticks to {}", replay.get_num_ticks(), DEFAULT_REPLAY_PATH),
+                        Err(err) => println!("Could not save the replay: {}", err),
+                    };
+                } else {
+                    let seed = fastrand::u64(..);
+                    match matrix.start_recording(seed) {
+                        Ok(()) => println!("Recording with seed {}", seed),
+                        Err(err) => println!("Could not start recording: {}", err),
+                    };
+                };
+            }
+            if input.key_pressed(VirtualKeyCode::F9) {
+                let started = Replay::load(DEFAULT_REPLAY_PATH).and_then(|replay| {
+                    let mut player = ReplayPlayer::new(replay);
+                    player.start(&mut matrix)?;
+                    Ok(player)
+                });
+                match started {
+                    Ok(player) => {
+                        println!("Playing back {}", DEFAULT_REPLAY_PATH);
+                        replay_player = Some(player);
+                    },
+                    Err(err) => println!("Could not play back the replay: {}", err),
+                };
+            }
+            if input.key_pressed(VirtualKeyCode::F10) {
+                match matrix.load_scenario(DEFAULT_SCENARIO_PATH) {
+                    Ok(()) => println!("Started scenario {}", DEFAULT_SCENARIO_PATH),
+                    Err(err) => println!("Could not start the scenario: {}", err),
+                };
+            }
+            if input.key_pressed(VirtualKeyCode::F11) {
+                if let Some(session) = &mut lockstep {
+                    match session.restart(&mut matrix) {
+                        Ok(()) => println!("Sent the world to {} clients", session.get_num_peers()),
+                        Err(err) => println!("Could not resync: {}", err),
+                    };
+                };
+            }
+            for path in matrix.scripts.reload_changed() {
+                println!("Reloaded {}", path.display());
             }
             if input.key_pressed(VirtualKeyCode::Up) {
                 matrix.brush.size = matrix.brush.size.saturating_add(1);
@@ -193,28 +348,31 @@ fn main() -> Result<(), Error> {
                 })
                 .unwrap_or_default();
                 
-            if input.mouse_pressed(0) {
-                let pos = IVec2::new(mouse_cell.0 as i32, mouse_cell.1 as i32);
-                let cp = pos / CHUNK_SIZE_VEC;
-                println!("Mouse click at {:?}, In bounds: {}, Chunk: {}, Chunk in bounds: {}", mouse_cell, matrix.is_in_bounds(pos), cp, matrix.chunk_in_bounds(cp));
-                matrix.draw_brush(pos, matrix.brush.get_material_from_index());
-            } else {
-                let release = input.mouse_released(0);
-                let held = input.mouse_held(0);
-                // If they either released (finishing the drawing) or are still
-                // in the middle of drawing, keep going.
-                if release || held {
-                    matrix.set_line(
-                        mouse_prev_cell.0,
-                        mouse_prev_cell.1,
-                        mouse_cell.0,
-                        mouse_cell.1,
-                        matrix.brush.get_material_from_index()
-                    );
-                }
-                // If they let go or are otherwise not clicking anymore, stop drawing.
-                if release || !held {
-                    //debug!("Draw end");
+            // Drawing during a playback would make it differ from the recording
+            if replay_player.is_none() {
+                if input.mouse_pressed(0) {
+                    let pos = IVec2::new(mouse_cell.0 as i32, mouse_cell.1 as i32);
+                    let cp = pos / CHUNK_SIZE_VEC;
+                    println!("Mouse click at {:?}, In bounds: {}, Chunk: {}, Chunk in bounds: {}", mouse_cell, matrix.is_in_bounds(pos), cp, matrix.chunk_in_bounds(cp));
+                    matrix.draw_brush(pos, matrix.brush.get_material_from_index());
+                } else {
+                    let release = input.mouse_released(0);
+                    let held = input.mouse_held(0);
+                    // If they either released (finishing the drawing) or are still
+                    // in the middle of drawing, keep going.
+                    if release || held {
+                        matrix.set_line(
+                            mouse_prev_cell.0,
+                            mouse_prev_cell.1,
+                            mouse_cell.0,
+                            mouse_cell.1,
+                            matrix.brush.get_material_from_index()
+                        );
+                    }
+                    // If they let go or are otherwise not clicking anymore, stop drawing.
+                    if release || !held {
+                        //debug!("Draw end");
+                    }
                 }
             }
             // Resize the window
@@ -237,13 +395,33 @@ fn main() -> Result<(), Error> {
             }
             if (!paused || input.key_pressed_os(VirtualKeyCode::Space)) && should_update
             {
-                matrix.update();
+                #[cfg(feature = "live-audio")]
+                let tick_before = matrix.get_tick();
+                if let Some(player) = &mut replay_player {
+                    if !player.step(&mut matrix) {
+                        println!("Replay finished after {} ticks, state hash {:016x}", matrix.get_tick(), matrix.get_state_hash());
+                        replay_player = None;
+                    };
+                } else if let Some(session) = &mut lockstep {
+                    session.update(&mut matrix);
+                    if !session.is_connected() {
+                        println!("Lost the connection to the lockstep host");
+                        lockstep.take().unwrap().close(&mut matrix);
+                    };
+                } else {
+                    matrix.update();
+                };
+                // Lockstep clients can run zero updates in a frame, the events are from an older tick then
+                #[cfg(feature = "live-audio")]
+                if let Some((synth, output)) = audio.as_mut().filter(|_| matrix.get_tick() != tick_before) {
+                    use falling_rust::AudioOutput;
+                    synth.set_activity(&falling_rust::SoundActivity::from_matrix(&matrix));
+                    let _ = output.write_samples(&synth.render_tick());
+                };
                 last_update = std::time::SystemTime::now();
-                num_frames += 1;
             };
             window.request_redraw();
         };
-        frame_time = std::time::SystemTime::now();
     });
 }
     
@@ -251,4 +429,27 @@ fn main() -> Result<(), Error> {
     
     
     
-    
\ No newline at end of file
+    
+
+/// Whether the world may be cleared or loaded. Clients only get the world from their host and a recording
+/// can't follow a world that gets swapped out, so it has to be stopped first
+fn can_replace_world(lockstep: &Option<LockstepSession>, matrix: &Matrix) -> bool {
+    if lockstep.as_ref().is_some_and(|s| !s.is_host()) {
+        println!("Only the lockstep host can replace the world");
+        false
+    } else if matrix.is_recording() {
+        println!("Stop the recording (F8) before replacing the world");
+        false
+    } else {
+        true
+    }
+}
+
+/// The clients of a lockstep host only know about brush actions, other changes to the world have to be sent again
+fn restart_lockstep_host(lockstep: &mut Option<LockstepSession>, matrix: &mut Matrix) {
+    if let Some(session) = lockstep.as_mut().filter(|s| s.is_host()) {
+        if let Err(err) = session.restart(matrix) {
+            println!("Could not send the world to the clients: {}", err);
+        };
+    };
+}
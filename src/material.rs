@@ -1,6 +1,9 @@
+use std::sync::RwLock;
+
+use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
-use crate::Color;
+use crate::{Color, Shading};
 
 
 #[derive(Clone, Copy, PartialEq)]
@@ -14,7 +17,25 @@ pub enum MaterialType {
 
 
 
+/// Number of materials built into the crate, Material::Custom ids come after them
+const NUM_BUILTIN_MATERIALS: u8 = 13;
+
+/// A material added by Material::register_custom
+#[derive(Clone, Copy, Debug)]
+pub struct CustomMaterial {
+    pub name: &'static str,
+    pub color: Color,
+    /// Built in material whose properties (type, hp, density, burning, ...) the custom one has
+    pub base: Material,
+}
+
+/// Indexed by the number in Material::Custom
+static CUSTOM_MATERIALS: RwLock<Vec<CustomMaterial>> = RwLock::new(Vec::new());
+
+
+/// Material::iter only goes over the built in materials, Material::all also returns the custom ones
 #[derive(Clone, Copy, PartialEq, Eq, EnumIter, Debug, Hash)]
+#[repr(u8)]
 pub enum Material {
     Empty,
     Sand,
@@ -23,11 +44,128 @@ pub enum Material {
     Rock,
     Smoke,
     Wood,
+    Oil,
+    BlackSmoke,
+    Ash,
+    Ember,
+    Emitter,
+    Drain,
+    /// Material registered with Material::register_custom, the number is its index in the order of registration
+    #[strum(disabled)]
+    Custom(u8),
 }
 
 impl Material {
+    /// Registers a material of a downstream crate, which gets the name and colour but otherwise behaves like base
+    /// until a CellBehavior is registered for it. Custom materials are saved by their id, so they have to be registered
+    /// in the same order every time. Returns None if the name is taken or there is no id left
+    pub fn register_custom(name: &'static str, color: Color, base: Material) -> Option<Material> {
+        let mut custom = CUSTOM_MATERIALS.write().unwrap();
+        let index = custom.len();
+        // Material::from_name would need the lock again
+        let taken = Material::iter().any(|m| m.get_name() == name) || custom.iter().any(|c| c.name == name);
+        if taken || index + NUM_BUILTIN_MATERIALS as usize > u8::MAX as usize {
+            return None;
+        };
+        let base = match base {
+            Material::Custom(base_index) => custom.get(base_index as usize)?.base,
+            _ => base,
+        };
+        custom.push(CustomMaterial { name, color, base });
+        Some(Material::Custom(index as u8))
+    }
+
+    /// Definition of a custom material, None for the built in ones
+    pub fn get_custom(&self) -> Option<CustomMaterial> {
+        match self {
+            Material::Custom(index) => CUSTOM_MATERIALS.read().unwrap().get(*index as usize).copied(),
+            _ => None,
+        }
+    }
+
+    /// The built in materials followed by the registered custom ones, in the order of their ids
+    pub fn all() -> Vec<Material> {
+        let num_custom = CUSTOM_MATERIALS.read().unwrap().len();
+        Material::iter().chain((0..num_custom).map(|i| Material::Custom(i as u8))).collect()
+    }
+
+    /// The built in material whose properties this one has, the material itself if it is built in
+    pub fn get_base(&self) -> Material {
+        self.get_custom().map_or(*self, |custom| custom.base)
+    }
+
+    /// Stable id of the material, used when the world is saved
+    pub fn get_id(&self) -> u8 {
+        match self {
+            Material::Empty => 0,
+            Material::Sand => 1,
+            Material::Dirt => 2,
+            Material::Water => 3,
+            Material::Rock => 4,
+            Material::Smoke => 5,
+            Material::Wood => 6,
+            Material::Oil => 7,
+            Material::BlackSmoke => 8,
+            Material::Ash => 9,
+            Material::Ember => 10,
+            Material::Emitter => 11,
+            Material::Drain => 12,
+            Material::Custom(index) => NUM_BUILTIN_MATERIALS + index,
+        }
+    }
+
+    /// Converts an id from Material::get_id back into a material
+    pub fn from_id(id: u8) -> Option<Material> {
+        match id {
+            0 => Some(Material::Empty),
+            1 => Some(Material::Sand),
+            2 => Some(Material::Dirt),
+            3 => Some(Material::Water),
+            4 => Some(Material::Rock),
+            5 => Some(Material::Smoke),
+            6 => Some(Material::Wood),
+            7 => Some(Material::Oil),
+            8 => Some(Material::BlackSmoke),
+            9 => Some(Material::Ash),
+            10 => Some(Material::Ember),
+            11 => Some(Material::Emitter),
+            12 => Some(Material::Drain),
+            _ => {
+                let index = id - NUM_BUILTIN_MATERIALS;
+                let registered = (index as usize) < CUSTOM_MATERIALS.read().unwrap().len();
+                registered.then_some(Material::Custom(index))
+            },
+        }
+    }
+
+    /// Lowercase name of the material, used by scripts and for script file names
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Material::Empty => "empty",
+            Material::Sand => "sand",
+            Material::Dirt => "dirt",
+            Material::Water => "water",
+            Material::Rock => "rock",
+            Material::Smoke => "smoke",
+            Material::Wood => "wood",
+            Material::Oil => "oil",
+            Material::BlackSmoke => "black_smoke",
+            Material::Ash => "ash",
+            Material::Ember => "ember",
+            Material::Emitter => "emitter",
+            Material::Drain => "drain",
+            Material::Custom(_) => self.get_custom().map_or("unknown", |custom| custom.name),
+        }
+    }
+
+    /// Converts a name from Material::get_name back into a material
+    pub fn from_name(name: &str) -> Option<Material> {
+        Material::all().into_iter().find(|m| m.get_name() == name)
+    }
+
     pub fn get_type(&self) -> MaterialType {
         match self {
+            Material::Custom(_) => self.get_base().get_type(),
             Material::Empty => MaterialType::Empty,
             Material::Sand => MaterialType::MovableSolid,
             Material::Dirt => MaterialType::MovableSolid,
@@ -35,6 +173,12 @@ impl Material {
             Material::Rock => MaterialType::Solid,
             Material::Smoke => MaterialType::Gas,
             Material::Wood => MaterialType::Solid,
+            Material::Oil => MaterialType::Liquid,
+            Material::BlackSmoke => MaterialType::Gas,
+            Material::Ash => MaterialType::MovableSolid,
+            Material::Ember => MaterialType::MovableSolid,
+            Material::Emitter => MaterialType::Solid,
+            Material::Drain => MaterialType::Solid,
             _ => MaterialType::Solid,
         }
     }
@@ -48,11 +192,19 @@ impl Material {
             Material::Rock => Color { r: 0.3, g: 0.3, b: 0.3, a: 1.0 },
             Material::Smoke => Color { r: 0.5, g: 0.5, b: 0.5, a: 1.0 },
             Material::Wood => Color {r: 0.5, g: 0.3, b: 0.1, a: 1.0},
+            Material::Oil => Color { r: 0.15, g: 0.12, b: 0.08, a: 1.0 },
+            Material::BlackSmoke => Color { r: 0.1, g: 0.1, b: 0.1, a: 1.0 },
+            Material::Ash => Color { r: 0.67, g: 0.66, b: 0.63, a: 1.0 },
+            Material::Ember => Color { r: 1.0, g: 0.47, b: 0.08, a: 1.0 },
+            Material::Emitter => Color { r: 0.24, g: 0.78, b: 0.86, a: 1.0 },
+            Material::Drain => Color { r: 0.16, g: 0.04, b: 0.2, a: 1.0 },
+            Material::Custom(_) => self.get_custom().map_or(Color::BLACK, |custom| custom.color),
         }
     }
 
-    pub fn get_hp(&self) -> u64 {
+    pub fn get_hp(&self) -> u16 {
         match self {
+            Material::Custom(_) => self.get_base().get_hp(),
             Material::Empty => 0,
             Material::Sand => 10,
             Material::Dirt => 20,
@@ -60,11 +212,18 @@ impl Material {
             Material::Rock => 150,
             Material::Smoke => 60,
             Material::Wood => 600,
+            Material::Oil => 100,
+            Material::BlackSmoke => 120,
+            Material::Ash => 10,
+            Material::Ember => 40,
+            Material::Emitter => 1,
+            Material::Drain => 1,
         }
     }
 
     pub fn get_density(&self) -> u64 {
         match self {
+            Material::Custom(_) => self.get_base().get_density(),
             Material::Empty => 0,
             Material::Sand => 300,
             Material::Dirt => 500,
@@ -72,38 +231,158 @@ impl Material {
             Material::Rock => 1000,
             Material::Smoke => 60,
             Material::Wood => 600,
+            Material::Oil => 80,
+            Material::BlackSmoke => 50,
+            Material::Ash => 150,
+            Material::Ember => 70,
+            Material::Emitter => 10000,
+            Material::Drain => 10000,
+        }
+    }
+
+    /// Maximum speed (in pixels per frame) a cell of this material can reach
+    pub fn get_terminal_velocity(&self) -> f32 {
+        match self {
+            Material::Custom(_) => self.get_base().get_terminal_velocity(),
+            Material::Sand => 8.0,
+            Material::Dirt => 8.0,
+            Material::Water => 6.0,
+            Material::Oil => 5.0,
+            Material::Ash => 1.5,
+            Material::Ember => 2.0,
+            Material::Smoke => 2.0,
+            Material::BlackSmoke => 2.0,
+            _ => 10.0,
         }
     }
 
     pub fn get_dispersion(&self) -> u8 {
         match self {
+            Material::Custom(_) => self.get_base().get_dispersion(),
             Material::Sand => 1,
             Material::Dirt => 1,
             Material::Water => 10,
             Material::Smoke => 5,
+            Material::Oil => 6,
+            Material::BlackSmoke => 3,
+            Material::Ash => 1,
+            Material::Ember => 1,
             _ => 0,
         }
     }
 
     pub fn get_intertial_resistance(&self) -> f32 {
         match self {
+            Material::Custom(_) => self.get_base().get_intertial_resistance(),
             Material::Sand => 0.1,
             Material::Dirt => 0.9,
+            Material::Ash => 0.05,
             _ => 0.0,
         }
     }
 
     pub fn get_flammability(&self) -> f32 {
         match self {
+            Material::Custom(_) => self.get_base().get_flammability(),
             Material::Smoke => 0.5,
             Material::Dirt => 0.2,
             Material::Wood => 0.005,
+            Material::Oil => 0.3,
+            _ => 0.0,
+        }
+    }
+
+    /// Chance that a cell splashes off as a free flying particle when it hits something at high speed
+    pub fn get_splash_chance(&self) -> f32 {
+        match self {
+            Material::Custom(_) => self.get_base().get_splash_chance(),
+            Material::Water => 0.3,
+            Material::Oil => 0.2,
+            Material::Sand => 0.1,
+            Material::Dirt => 0.05,
+            Material::Ash => 0.1,
             _ => 0.0,
         }
     }
 
+    /// How cells of this material get shaded when drawn (see Matrix::get_shade)
+    pub fn get_shading(&self) -> Shading {
+        match self {
+            Material::Custom(_) => self.get_base().get_shading(),
+            Material::Sand => Shading::new(0.35, 0.12, 0.06),
+            Material::Dirt => Shading::new(0.4, 0.08, 0.08),
+            Material::Water => Shading::new(0.3, 0.15, 0.0),
+            Material::Rock => Shading::new(0.45, 0.1, 0.05),
+            Material::Wood => Shading::new(0.35, 0.08, 0.04),
+            Material::Oil => Shading::new(0.3, 0.12, 0.0),
+            Material::Ash => Shading::new(0.3, 0.1, 0.08),
+            Material::Ember => Shading::new(0.0, 0.0, 0.1),
+            _ => Shading::NONE,
+        }
+    }
+
+    /// How much of the light passing through a cell of this material gets absorbed (0 - 1), see OcclusionMask.
+    /// Light passes through many cells of liquids and gases, so they only take away a little each
+    pub fn get_opacity(&self) -> f32 {
+        match self {
+            Material::Custom(_) => self.get_base().get_opacity(),
+            Material::Water => 0.03,
+            Material::Oil => 0.15,
+            Material::Smoke => 0.04,
+            Material::BlackSmoke => 0.12,
+            _ => match self.get_type() {
+                MaterialType::Solid | MaterialType::MovableSolid => 1.0,
+                _ => 0.0,
+            },
+        }
+    }
+
+    /// Color of the light that gets through the absorbed part (see get_opacity), black blocks it completely
+    pub fn get_light_tint(&self) -> Color {
+        match self {
+            Material::Custom(_) => self.get_base().get_light_tint(),
+            Material::Water => Color { r: 0.1, g: 0.45, b: 1.0, a: 1.0 },
+            Material::Oil => Color { r: 0.3, g: 0.2, b: 0.05, a: 1.0 },
+            Material::Smoke => Color { r: 0.3, g: 0.3, b: 0.3, a: 1.0 },
+            _ => Color::BLACK,
+        }
+    }
+
+    /// Whether solid cells connected to this material are held in place, even when they aren't connected to the floor
+    pub fn is_structural_anchor(&self) -> bool {
+        matches!(self.get_base(), Material::Emitter | Material::Drain)
+    }
+
+    /// Whether a cell of this material is spawned burning and dies as soon as it is extinguished
+    pub fn is_always_burning(&self) -> bool {
+        matches!(self.get_base(), Material::Ember)
+    }
+
+    /// What a burning cell leaves behind once its hp reaches zero, as (material, chance) pairs.
+    ///
+    /// The first entry that passes its roll takes the place of the dead cell, all following ones are spawned above it
+    pub fn get_burn_residue(&self) -> &'static [(Material, f32)] {
+        match self {
+            Material::Custom(_) => self.get_base().get_burn_residue(),
+            Material::Wood => &[(Material::Ash, 0.6), (Material::Ember, 0.3)],
+            Material::Oil => &[(Material::BlackSmoke, 0.7)],
+            _ => &[],
+        }
+    }
+
+    /// What a cell emits every frame while it is burning, as (material, chance) pairs
+    pub fn get_burn_emission(&self) -> &'static [(Material, f32)] {
+        match self {
+            Material::Custom(_) => self.get_base().get_burn_emission(),
+            Material::Wood => &[(Material::Smoke, 0.02), (Material::Ember, 0.005)],
+            Material::Oil => &[(Material::BlackSmoke, 0.08)],
+            _ => &[],
+        }
+    }
+
     pub fn extinguishes_fire(&self) -> (bool, f32) {
         match self {
+            Material::Custom(_) => self.get_base().extinguishes_fire(),
             Material::Water => (true, 0.5),
             Material::Sand => (true, 1.0),
             _ => (false, 1.0),
@@ -112,8 +391,41 @@ impl Material {
 
     pub fn protects_from_fire(&self) -> bool {
         match self {
+            Material::Custom(_) => self.get_base().protects_from_fire(),
             Material::Water => true,
             _ => false,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_round_trip() {
+        assert_eq!(Material::iter().count(), NUM_BUILTIN_MATERIALS as usize);
+        for (i, material) in Material::iter().enumerate() {
+            assert_eq!(material.get_id() as usize, i);
+            assert_eq!(Material::from_id(material.get_id()), Some(material));
+        };
+        assert_eq!(Material::from_id(u8::MAX), None);
+    }
+
+    #[test]
+    fn custom_materials_take_name_and_colour_from_the_registry() {
+        let color = Color { r: 0.2, g: 0.9, b: 0.4, a: 1.0 };
+        let slime = Material::register_custom("test_slime", color, Material::Water).unwrap();
+        assert!(Material::register_custom("test_slime", color, Material::Sand).is_none());
+        assert!(Material::register_custom("sand", color, Material::Sand).is_none());
+
+        assert_eq!(slime.get_name(), "test_slime");
+        assert_eq!(slime.get_color(), color);
+        assert!(slime.get_type() == MaterialType::Liquid);
+        assert_eq!(slime.get_density(), Material::Water.get_density());
+        assert_eq!(Material::from_name("test_slime"), Some(slime));
+        assert_eq!(Material::from_id(slime.get_id()), Some(slime));
+        assert!(Material::all().contains(&slime));
+        assert!(!Material::iter().any(|m| m == slime));
+    }
+}
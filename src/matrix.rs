@@ -1,14 +1,39 @@
-use std::sync::{RwLock, Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
 
-use glam::{IVec2};
-use crate::{Color, WIDTH, HEIGHT, Rng, ASSETS};
+use glam::{IVec2, Vec2};
+use crate::{darken_color, Rng, ASSETS, ForceField, Particle, Boundaries, BoundaryMode, Emitter, MaterialType, CollapseMode, SimEvent, EventFilter};
+use crate::structure::{self, RigidCluster};
+use crate::consistency::{self, ConsistencyViolation};
+use crate::overlay::DebugOverlay;
+use crate::storage::{self, CellStorage, CellRef, CellMut, PROCESSED};
+use crate::force::FORCE_CELL_SIZE;
+use crate::profiler::{Profiler, Stage};
+use crate::replay::{BrushAction, Replay, ReplayEvent};
+use crate::script::Scripts;
+use crate::behavior::BehaviorRegistry;
 use rayon::prelude::*;
 
 use crate::{Cell, Assets, Material, Chunk, cell_handler, CHUNK_SIZE, brush::Brush};
 const CHUNK_SIZE_I32: i32 = CHUNK_SIZE as i32;
 pub const CHUNK_SIZE_VEC: IVec2 = IVec2::new(CHUNK_SIZE_I32, CHUNK_SIZE_I32);
-const NUM_CHUNKS_X: usize = (WIDTH / CHUNK_SIZE as u32) as usize;
-const NUM_CHUNKS_Y: usize = (HEIGHT / CHUNK_SIZE as u32) as usize;
+pub const DEFAULT_GRAVITY: Vec2 = Vec2::new(0.0, 0.5);
+/// How far up a landing particle searches for a free cell before it gets discarded
+const PARTICLE_INSERT_SEARCH: i32 = 16;
+/// Speed of the cells in the center of Matrix::explode, in pixels per frame
+const EXPLOSION_SPEED: f32 = 6.0;
+/// How far around a changed cell the area that gets updated next frame reaches
+const DIRTY_MARGIN: i32 = 2;
+
+/// Work done by the last Matrix::update
+#[derive(Clone, Copy, Default, Debug)]
+pub struct StepStats {
+    pub active_chunks: usize,
+    /// Cells in all active chunks
+    pub active_area: usize,
+    /// Cells inside the dirty rectangles of the active chunks, the only ones that got stepped
+    pub stepped_area: usize,
+}
 
 pub struct Matrix {
     pub width: usize,
@@ -16,11 +41,56 @@ pub struct Matrix {
     pub height: usize,
     clamp_height: i32,
     
-    cells: Vec<Cell>,
-    data: Vec<usize>,
+    cells: CellStorage,
     pub chunks: Vec<Chunk>,
+    /// Number of chunk columns and rows, the last ones cover the rest of the world if it is not a multiple of CHUNK_SIZE
+    pub(crate) chunks_x: usize,
+    pub(crate) chunks_y: usize,
+    boundaries: Boundaries,
+
+    gravity: Vec2,
+    pub force_field: ForceField,
+
+    particles: Vec<Particle>,
+    emitters: HashMap<IVec2, Emitter>,
+    /// Positions where solid cells got removed since the last structural check
+    structure_checks: Vec<IVec2>,
+    rigid_clusters: Vec<RigidCluster>,
+    /// Events of the last update, see Matrix::drain_events
+    events: Vec<SimEvent>,
+    /// Whether events get recorded at all
+    pub record_events: bool,
+    /// Whether solid clusters without support collapse
+    pub structural_integrity: bool,
+    pub collapse_mode: CollapseMode,
+    /// Minimum impact speed for a cell to splash off as a particle
+    pub particle_detach_speed: f32,
+    /// Number of updates since the matrix was created
+    tick: u64,
+    /// Source of all randomness of the simulation, two matrices with the same seed and inputs play out the same way.
+    /// Only used through &mut self, so the lock never waits. It only keeps the matrix Sync for drawing in parallel
+    rng: Mutex<Rng>,
+    /// Replay being recorded, see Matrix::start_recording
+    pub(crate) recording: Option<Replay>,
+    /// Brush actions waiting to be sent to the host while in a lockstep session, see LockstepSession
+    pub(crate) lockstep_outbox: Option<Vec<BrushAction>>,
+    /// Whether the matrix validates itself after every update, see Matrix::find_inconsistencies
+    pub check_consistency: bool,
+    consistency_violations: Vec<ConsistencyViolation>,
 
-    pub debug_draw: bool,
+    pub debug_overlay: DebugOverlay,
+    step_stats: StepStats,
+    /// Overlay, shading and shading direction of the last draw, changing any of them redraws everything
+    last_draw_settings: Option<(DebugOverlay, bool, IVec2)>,
+    /// Where particles were drawn last frame, the grid below them has to be drawn again
+    drawn_particles: Vec<IVec2>,
+    rendered_chunks: usize,
+    pub profiler: Profiler,
+    pub scripts: Scripts,
+    /// What every material does when it gets stepped, see CellBehavior
+    pub behaviors: BehaviorRegistry,
+    /// Whether cells get shaded by their neighbours when drawn, see Material::get_shading
+    pub shading: bool,
     pub update_left: bool,
     pub brush: Brush,
     pub wait_time_after_frame: f32,
@@ -30,16 +100,22 @@ unsafe impl Send for Matrix {}
 
 impl Matrix {
     pub fn new_empty(width: usize, height: usize) -> Self {
+        Self::new(width, height, Boundaries::default())
+    }
+
+    /// Creates an empty matrix with the given behaviour at the world edges
+    pub fn new(width: usize, height: usize, boundaries: Boundaries) -> Self {
         assert!(width != 0 && height != 0);
         //let size = width.checked_mul(height).expect("too big");
 
-        let cells = Vec::<Cell>::new();
-        let data = vec![0; width * height];
-        
+        let cells = CellStorage::new(width, height);
+
         let mut chunks = vec![];
         for y in (0..height as i32).step_by(CHUNK_SIZE) {
             for x in (0..width as i32).step_by(CHUNK_SIZE) {
-                let chunk = Chunk::new(IVec2::new(x, y), CHUNK_SIZE);
+                let topleft = IVec2::new(x, y);
+                let size = CHUNK_SIZE_VEC.min(IVec2::new(width as i32, height as i32) - topleft);
+                let chunk = Chunk::new(topleft, size);
                 chunks.push(chunk);
             };
         };
@@ -52,10 +128,39 @@ impl Matrix {
             clamp_height: height as i32 - 1,
 
             cells,
-            data,
             chunks,
+            chunks_x: width.div_ceil(CHUNK_SIZE),
+            chunks_y: height.div_ceil(CHUNK_SIZE),
+            boundaries: boundaries.normalized(&Boundaries::default()),
+
+            gravity: DEFAULT_GRAVITY,
+            force_field: ForceField::new(width, height),
 
-            debug_draw: false,
+            particles: vec![],
+            emitters: HashMap::new(),
+            structure_checks: vec![],
+            rigid_clusters: vec![],
+            events: vec![],
+            record_events: true,
+            structural_integrity: true,
+            collapse_mode: CollapseMode::Debris,
+            particle_detach_speed: 3.0,
+            tick: 0,
+            rng: Mutex::new(Rng::new()),
+            recording: None,
+            lockstep_outbox: None,
+            check_consistency: false,
+            consistency_violations: vec![],
+
+            debug_overlay: DebugOverlay::None,
+            step_stats: StepStats::default(),
+            last_draw_settings: None,
+            drawn_particles: vec![],
+            rendered_chunks: 0,
+            profiler: Profiler::new(),
+            scripts: Scripts::new(),
+            behaviors: BehaviorRegistry::new(),
+            shading: true,
             brush: Brush::new(),
             update_left: true,
             wait_time_after_frame: 0.0,
@@ -64,107 +169,207 @@ impl Matrix {
 
     /// Checks wether the chunk position is valid
     pub fn chunk_in_bounds(&self, chunk_pos: IVec2) -> bool {
-        (chunk_pos.x >= 0 && chunk_pos.x < self.width as i32 / CHUNK_SIZE_I32) && (chunk_pos.y >= 0 && chunk_pos.y < self.height as i32 / CHUNK_SIZE_I32)
+        (chunk_pos.x >= 0 && chunk_pos.x < self.chunks_x as i32) && (chunk_pos.y >= 0 && chunk_pos.y < self.chunks_y as i32)
+    }
+
+    /// Index into Matrix::chunks of the chunk at the chunk position, which has to be in bounds
+    pub(crate) fn chunk_index(&self, chunk_pos: IVec2) -> usize {
+        chunk_pos.x as usize + chunk_pos.y as usize * self.chunks_x
     }
     
-    /// Tells the chunk to be updated the next frame
+    /// Marks the area around pos to be updated the next frame
     pub fn set_chunk_active(&mut self, pos: IVec2) {
-        let chunk_pos = pos / CHUNK_SIZE_VEC;
-        if self.chunk_in_bounds(chunk_pos) {
-            self.chunks[chunk_pos.x as usize + chunk_pos.y as usize * NUM_CHUNKS_X].should_step_next_frame = true;
-        };
+        self.set_rect_active(pos - IVec2::splat(DIRTY_MARGIN), pos + IVec2::splat(DIRTY_MARGIN));
     }
-    
-    /// Tells the chunk and all chunks around it to be updated the next frame
+
+    /// Marks the area around pos, reaching into all chunks around it, to be updated the next frame
     pub fn set_chunk_cluster_active(&mut self, pos: IVec2) {
-        for y in (pos.y-CHUNK_SIZE_I32..=pos.y+CHUNK_SIZE_I32).step_by(CHUNK_SIZE) {
-            for x in (pos.x-CHUNK_SIZE_I32..=pos.x+CHUNK_SIZE_I32).step_by(CHUNK_SIZE) {
-                self.set_chunk_active(IVec2::new(x, y));
-            }
-        }
+        self.set_rect_active(pos - CHUNK_SIZE_VEC, pos + CHUNK_SIZE_VEC);
+    }
+
+    /// Marks the rectangle (both corners inclusive) to be updated the next frame. Parts outside of wrapping edges get wrapped
+    pub fn set_rect_active(&mut self, min: IVec2, max: IVec2) {
+        let chunk_min = IVec2::new(min.x.div_euclid(CHUNK_SIZE_I32), min.y.div_euclid(CHUNK_SIZE_I32));
+        let chunk_max = IVec2::new(max.x.div_euclid(CHUNK_SIZE_I32), max.y.div_euclid(CHUNK_SIZE_I32));
+        for cy in chunk_min.y..=chunk_max.y {
+            for cx in chunk_min.x..=chunk_max.x {
+                let topleft = IVec2::new(cx, cy) * CHUNK_SIZE_VEC;
+                let wrapped = match self.wrap_pos(topleft) {
+                    Some(wrapped) => wrapped,
+                    None => continue,
+                };
+                let shift = wrapped - topleft;
+                let chunk_pos = wrapped / CHUNK_SIZE_VEC;
+                if self.chunk_in_bounds(chunk_pos) {
+                    let chunk_index = self.chunk_index(chunk_pos);
+                    self.chunks[chunk_index].mark_dirty(min + shift, max + shift);
+                };
+            };
+        };
+    }
+
+    /// Tells every chunk to be updated completely the next frame
+    pub fn set_all_chunks_active(&mut self) {
+        self.chunks.iter_mut().for_each(|chunk| {
+            let bounds = chunk.get_bounds();
+            chunk.mark_dirty(bounds.min, bounds.max);
+        });
+    }
+
+    /// How much work the last update did compared to updating every active chunk completely
+    pub fn get_step_stats(&self) -> StepStats {
+        self.step_stats
+    }
+
+    /// Number of updates since the matrix was created or loaded
+    pub fn get_tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn get_gravity(&self) -> Vec2 {
+        self.gravity
+    }
+
+    /// Changes the world gravity. Wakes up all chunks, since every cell might be able to move now
+    pub fn set_gravity(&mut self, gravity: Vec2) {
+        if gravity != self.gravity {
+            self.gravity = gravity;
+            self.set_all_chunks_active();
+        };
+    }
+
+    /// Normalized gravity direction (zero without gravity)
+    pub fn get_gravity_dir(&self) -> Vec2 {
+        self.gravity.normalize_or_zero()
+    }
+
+    /// Returns the sum of gravity and the force field at that position
+    pub fn get_acceleration(&self, pos: IVec2) -> Vec2 {
+        self.gravity + self.force_field.get_force(pos)
     }
 
     pub fn is_in_bounds(&self, pos: IVec2) -> bool {
         (pos.x >= 0 && pos.x < self.width as i32) && (pos.y >= 0 && pos.y < self.height as i32)
     }
 
+    pub fn get_boundaries(&self) -> Boundaries {
+        self.boundaries
+    }
+
+    /// Changes the behaviour at the world edges. Wakes up all chunks, since cells at the edges might be able to move now
+    pub fn set_boundaries(&mut self, boundaries: Boundaries) {
+        let boundaries = boundaries.normalized(&self.boundaries);
+        if boundaries != self.boundaries {
+            self.boundaries = boundaries;
+            self.set_all_chunks_active();
+        };
+    }
+
+    /// Maps the position into the world by wrapping it around the wrapping edges.
+    ///
+    /// Returns None if the position lies behind a wall or void edge
+    pub fn wrap_pos(&self, mut pos: IVec2) -> Option<IVec2> {
+        if self.boundaries.wraps_horizontally() {
+            pos.x = pos.x.rem_euclid(self.width as i32);
+        };
+        if self.boundaries.wraps_vertically() {
+            pos.y = pos.y.rem_euclid(self.height as i32);
+        };
+        if self.is_in_bounds(pos) {
+            Some(pos)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the mode of the edge that has to be crossed to get to pos (None if pos is inside the world).
+    ///
+    /// If a corner is crossed, void takes precedence over walls
+    pub fn get_boundary_mode(&self, pos: IVec2) -> Option<BoundaryMode> {
+        let mut modes = vec![];
+        if pos.x < 0 {
+            modes.push(self.boundaries.left);
+        } else if pos.x >= self.width as i32 {
+            modes.push(self.boundaries.right);
+        };
+        if pos.y < 0 {
+            modes.push(self.boundaries.top);
+        } else if pos.y >= self.height as i32 {
+            modes.push(self.boundaries.bottom);
+        };
+        modes.retain(|mode| *mode != BoundaryMode::Wrap);
+        if modes.contains(&BoundaryMode::Void) {
+            Some(BoundaryMode::Void)
+        } else {
+            modes.first().copied()
+        }
+    }
+
     /// Clamps the position to be within the bounds of the pixel buffer
     pub fn clamp_pos(&self, pos: IVec2) -> IVec2 {
         IVec2::new(std::cmp::max(0, std::cmp::min(pos.x, self.clamp_width)), std::cmp::max(0, std::cmp::min(pos.y, self.clamp_height)))
     }
 
-    /// Converts the position into an index to be used in self.data
+    /// Converts the position into an index into the cell storage. Positions outside of the world get wrapped or clamped
     fn cell_idx(&self, mut pos: IVec2) -> usize {
-        pos = self.clamp_pos(pos);
+        pos = self.wrap_pos(pos).unwrap_or_else(|| self.clamp_pos(pos));
         (pos.x + pos.y * self.width as i32) as usize
     }
 
-    /// Gets the index of the cell in self.cells at that position
-    pub fn get_data_at_pos(&self, pos: IVec2) -> usize {
+    /// Returns the material at this position, Material::Empty if there is no cell or the position is outside of the world.
+    ///
+    /// Cheaper than Matrix::get_cell, since only the material column is read
+    pub fn get_material(&self, pos: IVec2) -> Material {
+        match self.wrap_pos(pos) {
+            Some(pos) => self.cells.material[self.cell_idx(pos)],
+            None => Material::Empty,
+        }
+    }
+
+    /// Returns a view of the cell at this position
+    pub fn get_cell(&self, pos: IVec2) -> Option<CellRef<'_>> {
+        let pos = self.wrap_pos(pos)?;
+        self.cells.get(self.cell_idx(pos)).map(CellRef::new)
+    }
+
+    /// Returns a mutable view of the cell at this position, changes get written back when it is dropped
+    pub fn get_cell_mut(&mut self, pos: IVec2) -> Option<CellMut<'_>> {
+        let pos = self.wrap_pos(pos)?;
         let idx = self.cell_idx(pos);
-        self.data[idx]
+        CellMut::new(&mut self.cells, idx)
     }
 
-    /// Returns a reference to the cell at cell_index (which is written in self.data)
-    fn get_cell_from_cells(&self, cell_index: usize) -> Option<&Cell> {
-        if cell_index < 1 || cell_index > self.cells.len() {
-            return None;
-        };
-        Some(&self.cells[cell_index - 1])
+    /// Returns the storage all cells are kept in
+    pub fn get_cell_storage(&self) -> &CellStorage {
+        &self.cells
     }
 
-    /// Returns a mutable reference to the cell at cell_index (which is written in self.data)
-    fn get_cell_from_cells_mut(&mut self, cell_index: usize) -> Option<&mut Cell> {
-        if cell_index < 1 || cell_index > self.cells.len() {
-            return None;
-        };
-        Some(&mut self.cells[cell_index - 1])
+    /// Restarts the RNG of the matrix from a seed, everything after it plays out the same way given the same inputs
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng.get_mut().unwrap().seed(seed);
     }
 
-    /// Returns a reference to the cell at this position
-    pub fn get_cell(&self, pos: IVec2) -> Option<&Cell> {
-        if !self.is_in_bounds(pos) {
-            None
-        } else {
-            let cell_idx = self.get_data_at_pos(pos);
-            if cell_idx == 0 {
-                return None;
-            };
-            self.get_cell_from_cells(cell_idx)
-        }
+    /// The RNG of the matrix, behaviours should use it for everything random so seeded runs stay the same
+    pub fn get_rng(&mut self) -> &Rng {
+        self.rng.get_mut().unwrap()
     }
 
-    /// Returns a mutable reference to the cell at this position
-    pub fn get_cell_mut(&mut self, pos: IVec2) -> Option<&mut Cell> {
-        if !self.is_in_bounds(pos) {
-            None
-        } else {
-            let cell_idx = self.get_data_at_pos(pos);
-            if cell_idx == 0 {
-                return None;
-            };
-            self.get_cell_from_cells_mut(cell_idx)
-        }
+    /// Storage::attempt_free_fall with the RNG of the matrix
+    pub(crate) fn attempt_free_fall(&mut self, idx: usize) {
+        self.cells.attempt_free_fall(idx, self.rng.get_mut().unwrap());
     }
 
-    /// Returns a reference to the cell based on cell_index
-    pub fn get_cell_by_cellindex(&self, cell_index: usize) -> Option<&Cell> {
-        if cell_index > self.cells.len() {
-            return None;
-        };
-        self.get_cell_from_cells(cell_index)
+    pub(crate) fn get_cell_storage_mut(&mut self) -> &mut CellStorage {
+        &mut self.cells
     }
 
-    /// Returns a mutable reference to the cell based on cell_index
-    pub fn get_cell_by_cellindex_mut(&mut self, cell_index: usize) -> Option<&mut Cell> {
-        if cell_index > self.cells.len() {
-            return None;
-        };
-        self.get_cell_from_cells_mut(cell_index)
+    /// Index of the position in the cell storage, None if the position lies behind a wall or void edge
+    pub fn get_storage_index(&self, pos: IVec2) -> Option<usize> {
+        self.wrap_pos(pos).map(|pos| self.cell_idx(pos))
     }
 
-    /// Returns a reference to all the neighbor cells around a position
-    pub fn get_neighbor_cells(&self, pos: IVec2, radius: i32) -> Vec<Option<&Cell>> {
+    /// Returns views of all the neighbor cells around a position
+    pub fn get_neighbor_cells(&self, pos: IVec2, radius: i32) -> Vec<Option<CellRef<'_>>> {
         if radius == 1 {
             let left = IVec2::new(1, 0);
             let down = IVec2::new(0, 1);
@@ -181,41 +386,34 @@ impl Matrix {
         neighbors
     }
 
-    /// Appends the cell to self.cells and updates self.data with its index
+    /// Writes the cell into the storage at its position, replacing the cell that was there
     pub fn add_cell_to_cells(&mut self, mut cell: Cell) {
         cell.set_color(unsafe {ASSETS.get_color_for_material(cell.pos, cell.material)});
+        self.emitters.remove(&cell.pos);
 
-        let cell_at_pos = self.get_data_at_pos(cell.pos);
-        // If there is already a cell at that position, replace that cell in self.cells with the new cell
-        if cell_at_pos != 0 {
-            let _ = std::mem::replace(self.get_cell_from_cells_mut(cell_at_pos).unwrap(), cell);
-        } else {
-            let c_idx = self.cell_idx(cell.pos);
-            self.cells.push(cell);
-            self.data[c_idx] = self.cells.len();
+        let idx = self.cell_idx(cell.pos);
+        if self.cells.material[idx].get_type() == MaterialType::Solid && cell.material.get_type() != MaterialType::Solid {
+            self.structure_checks.push(cell.pos);
         };
+        self.cells.clear(idx);
+        self.cells.set(idx, &cell);
     }
 
-    /// Replaces the cell at cellpos with the last cell in self.cells (faster than shifting) and updates self.data
+    /// Removes the cell at cellpos from the storage
     pub fn remove_cell_from_cells(&mut self, cellpos: IVec2) {
-        if self.cells.is_empty() {
+        if self.wrap_pos(cellpos).is_none() {
             return;
         };
-        let cell_index = self.get_data_at_pos(cellpos);
-        let data_idx = self.cell_idx(cellpos);
-        self.data[data_idx] = 0;
-        self.set_chunk_cluster_active(cellpos);
-        let cell_to_remove_idx = cell_index - 1;
-        if cell_index == self.cells.len() {
-            self.cells.remove(cell_to_remove_idx);
+        let idx = self.cell_idx(cellpos);
+        if !self.cells.is_occupied(idx) {
             return;
         };
-        if self.get_cell_from_cells(cell_index).is_some() {
-            let last_cell_pos = self.cells.last().unwrap().pos;
-            let idx_of_last_cell_in_data = self.cell_idx(last_cell_pos);
-            self.data[idx_of_last_cell_in_data] = cell_index;
-            self.cells.swap_remove(cell_to_remove_idx);
+        self.emitters.remove(&cellpos);
+        if self.cells.material[idx].get_type() == MaterialType::Solid {
+            self.structure_checks.push(cellpos);
         };
+        self.cells.clear(idx);
+        self.set_chunk_active(cellpos);
     }
 
     /// Places a cell at specified pos with the material given
@@ -224,115 +422,364 @@ impl Matrix {
             self.remove_cell_from_cells(pos);
             return;
         };
-        pos = self.clamp_pos(pos);
+        pos = match self.wrap_pos(pos) {
+            Some(pos) => pos,
+            None => return,
+        };
         let cell = Cell::new(pos, material);
         self.add_cell_to_cells(cell);
         self.set_cell_by_pos(pos, pos, swap);
+        if material == Material::Emitter {
+            self.emitters.insert(pos, Emitter::default());
+        };
+    }
+
+    /// Places an emitter cell with the given configuration
+    pub fn place_emitter(&mut self, pos: IVec2, emitter: Emitter) {
+        if let Some(pos) = self.wrap_pos(pos) {
+            self.set_cell_material(pos, Material::Emitter, false);
+            self.emitters.insert(pos, emitter);
+        };
+    }
+
+    /// Returns the configuration of the emitter cell at pos
+    pub fn get_emitter(&self, pos: IVec2) -> Option<&Emitter> {
+        self.emitters.get(&pos)
+    }
+
+    /// Returns the position and configuration of every emitter cell
+    pub fn iter_emitters(&self) -> impl Iterator<Item = (&IVec2, &Emitter)> {
+        self.emitters.iter()
+    }
+
+    /// Returns views of all cells currently in the grid, row by row
+    pub fn iter_cells(&self) -> impl Iterator<Item = CellRef<'_>> {
+        (0..self.cells.len()).filter_map(|idx| self.cells.get(idx).map(CellRef::new))
+    }
+
+    /// Finds an empty cell around pos, trying dir first, then the cells next to it and then all other neighbours
+    fn find_free_neighbor(&self, pos: IVec2, dir: Vec2) -> Option<IVec2> {
+        let dir = if dir == Vec2::ZERO { Vec2::new(0.0, 1.0) } else { dir.normalize() };
+        let mut candidates = vec![dir, dir + dir.perp(), dir - dir.perp(), dir.perp(), -dir.perp()];
+        candidates.extend([-dir + dir.perp(), -dir - dir.perp(), -dir]);
+        candidates.into_iter()
+            .filter_map(|offset| self.wrap_pos(pos + offset.normalize().round().as_ivec2()))
+            .find(|p| self.get_material(*p) == Material::Empty)
+    }
+
+    /// Lets every emitter spawn its cells. Gases get spawned against gravity, everything else along it
+    fn update_emitters(&mut self) {
+        let gravity_dir = self.get_gravity_dir();
+        let mut spawns = vec![];
+        for (pos, emitter) in self.emitters.iter_mut() {
+            for _ in 0..emitter.tick() {
+                spawns.push((*pos, emitter.material));
+            };
+        };
+        // The map has no fixed order, spawning in it would make runs with the same seed differ
+        spawns.sort_by_key(|(pos, _)| (pos.y, pos.x));
+        for (pos, material) in spawns {
+            let dir = if material.get_type() == MaterialType::Gas { -gravity_dir } else { gravity_dir };
+            if let Some(target) = self.find_free_neighbor(pos, dir) {
+                self.set_cell_material(target, material, false);
+                self.push_event(SimEvent::CellCreated { pos: target, material });
+            };
+        };
     }
 
     /// Places a cell which is located at cellpos at the specified target position (pos)
     pub fn set_cell_by_pos(&mut self, pos: IVec2, cellpos: IVec2, swap: bool) {
-        let data_at_cellpos = self.get_data_at_pos(cellpos);
-        if data_at_cellpos == 0 {
+        let cell_pos_index = self.cell_idx(cellpos);
+        if !self.cells.is_occupied(cell_pos_index) {
             return;
         };
-
-        // Index of the cell inside self.data
-        let cell_pos_index = self.cell_idx(cellpos);
-        // Index of position where the cell wants to go inside self.data
         let target_pos_index = self.cell_idx(pos);
-        let data_at_targetpos = self.get_data_at_pos(pos);
-        
-        let cell = self.get_cell_from_cells_mut(data_at_cellpos).unwrap();
-        cell.pos = pos;
-        let cellmat = cell.material;
-        let _cell_velocity = cell.velocity;
-        self.data[target_pos_index] = data_at_cellpos;
-
-        // Target cell is empty
-        if data_at_targetpos == 0 || !swap {
-            if pos != cellpos {
-                self.data[cell_pos_index] = 0;
-            };
-        } else {
-            let target_cell = self.get_cell_from_cells_mut(data_at_targetpos).unwrap();
-            let target_cellmat = target_cell.material;
-            
-            if cellmat == target_cellmat {
-                return;
+        let cellmat = self.cells.material[cell_pos_index];
+        let target_cellmat = self.cells.material[target_pos_index];
+
+        // Swapping two cells of the same material changes nothing
+        if swap && target_cellmat != Material::Empty && target_pos_index != cell_pos_index && cellmat == target_cellmat {
+            return;
+        };
+
+        let mut swapped_material = None;
+        if target_pos_index != cell_pos_index {
+            let offset = self.cells.pos_of(target_pos_index) - self.cells.pos_of(cell_pos_index);
+            if target_cellmat == Material::Empty || !swap {
+                self.cells.move_cell(cell_pos_index, target_pos_index);
+            } else {
+                self.cells.swap(cell_pos_index, target_pos_index);
+                self.cells.add_moved(cell_pos_index, -offset);
+                swapped_material = Some(target_cellmat);
             };
-            target_cell.pos = cellpos;
-            self.data[cell_pos_index] = data_at_targetpos;
+            self.cells.add_moved(target_pos_index, offset);
         };
         
-        // Set both positions chunks active (new and previous cell position)
+        if pos / CHUNK_SIZE_VEC != cellpos / CHUNK_SIZE_VEC {
+            self.push_event(SimEvent::MovedAcrossChunk { from: cellpos, to: pos, material: cellmat });
+            if let Some(material) = swapped_material {
+                self.push_event(SimEvent::MovedAcrossChunk { from: pos, to: cellpos, material });
+            };
+        };
+
+        // Set the area around both positions active (new and previous cell position), this reaches into neighbouring chunks
         self.set_chunk_active(cellpos);
         self.set_chunk_active(pos);
-        let x_chunked = pos.x % CHUNK_SIZE_I32;
-        let x_chunked_upper = CHUNK_SIZE_I32 - 1 - x_chunked;
-        if x_chunked <= 5 || x_chunked_upper <= 5 {
-            if x_chunked < x_chunked_upper {
-                self.set_chunk_active(pos - IVec2::new(CHUNK_SIZE_I32, 0));
-            } else {
-                self.set_chunk_active(pos + IVec2::new(CHUNK_SIZE_I32, 0));
-            }
+    }
+
+    /// Returns all the cells which are currently flying freely
+    pub fn get_particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    /// Removes the cell at pos from the grid and lets it fly freely with the given velocity
+    pub fn detach_cell(&mut self, pos: IVec2, velocity: Vec2) {
+        let cell = match self.get_cell(pos) {
+            Some(cell) => cell.clone(),
+            None => return,
+        };
+        self.remove_cell_from_cells(pos);
+        self.particles.push(Particle::new(cell, velocity));
+    }
+
+    /// Sets the cell at pos on fire. Returns false if there is no cell, its material doesn't burn or it already burns
+    pub fn ignite(&mut self, pos: IVec2) -> bool {
+        let material = match self.get_cell_mut(pos) {
+            Some(mut cell) if cell.material.get_flammability() > 0.0 && !cell.is_on_fire => {
+                cell.is_on_fire = true;
+                cell.material
+            },
+            _ => return false,
+        };
+        self.set_chunk_active(pos);
+        self.behaviors.get(material).on_ignite(self, pos);
+        true
+    }
+
+    /// Blows every cell within radius around center away as particles, flammable cells catch fire.
+    /// Emitters stay where they are
+    pub fn explode(&mut self, center: IVec2, radius: i32) {
+        for y in -radius..=radius {
+            for x in -radius..=radius {
+                let offset = IVec2::new(x, y);
+                let distance = offset.as_vec2().length();
+                let pos = center + offset;
+                if distance > radius as f32 || matches!(self.get_material(pos), Material::Empty | Material::Emitter) {
+                    continue;
+                };
+                if self.ignite(pos) {
+                    self.push_event(SimEvent::Ignited { pos, material: self.get_material(pos) });
+                };
+                // Cells further out get pushed less, the center goes straight up
+                let dir = if offset == IVec2::ZERO { -self.get_gravity_dir() } else { offset.as_vec2() / distance };
+                self.detach_cell(pos, dir * EXPLOSION_SPEED * (1.0 - distance / (radius as f32 + 1.0)));
+            };
         };
-        let y_chunked = pos.y % CHUNK_SIZE_I32;
-        let y_chunked_upper = CHUNK_SIZE_I32 - 1 - y_chunked;
-        if y_chunked <= 5 || y_chunked_upper <= 5 {
-            if y_chunked < y_chunked_upper {
-                self.set_chunk_active(pos - IVec2::new(0, CHUNK_SIZE_I32));
+        self.set_rect_active(center - IVec2::splat(radius), center + IVec2::splat(radius));
+    }
+
+    /// Places the particles cell back into the grid, on the first free cell against gravity starting at pos
+    fn insert_particle(&mut self, mut particle: Particle, pos: IVec2) {
+        let mut up = -self.get_gravity_dir();
+        if up == Vec2::ZERO {
+            up = -particle.velocity.normalize_or_zero();
+        };
+        for i in 0..PARTICLE_INSERT_SEARCH {
+            let target = self.clamp_pos(pos + (up * i as f32).round().as_ivec2());
+            if self.get_cell(target).is_none() {
+                particle.cell.pos = target;
+                particle.cell.velocity = particle.velocity;
+                particle.cell.subpixel = Vec2::ZERO;
+                self.add_cell_to_cells(particle.cell);
+                self.set_chunk_cluster_active(target);
+                return;
+            };
+        };
+        // No space left, the cell is lost
+        self.push_event(SimEvent::CellDestroyed { pos, material: particle.cell.material });
+    }
+
+    /// Moves all particles along their velocity and puts them back into the grid once they hit something
+    fn update_particles(&mut self) {
+        let particles = std::mem::take(&mut self.particles);
+        for mut particle in particles {
+            particle.velocity += self.get_acceleration(particle.get_grid_pos());
+            let start = particle.pos;
+            let end = start + particle.velocity;
+            let num_steps = (end - start).abs().max_element().ceil().max(1.0) as i32;
+            let mut landed = false;
+            let mut consumed = false;
+            for step in 1..=num_steps {
+                let cur = start.lerp(end, step as f32 / num_steps as f32);
+                let grid_pos = cur.round().as_ivec2();
+                let wrapped_pos = match self.wrap_pos(grid_pos) {
+                    Some(pos) => pos,
+                    None => {
+                        consumed = self.get_boundary_mode(grid_pos) == Some(BoundaryMode::Void);
+                        landed = true;
+                        break;
+                    },
+                };
+                if let Some(cell) = self.get_cell(wrapped_pos) {
+                    consumed = cell.material == Material::Drain;
+                    landed = true;
+                    break;
+                };
+                particle.pos = cur + (wrapped_pos - grid_pos).as_vec2();
+            };
+            if consumed {
+                self.push_event(SimEvent::CellDestroyed { pos: particle.get_grid_pos(), material: particle.cell.material });
+                continue;
+            };
+            if landed {
+                let pos = self.clamp_pos(particle.get_grid_pos());
+                self.insert_particle(particle, pos);
             } else {
-                self.set_chunk_active(pos + IVec2::new(0, CHUNK_SIZE_I32));
-            }
+                self.particles.push(particle);
+            };
         };
+    }
+
+    /// Places a single cell of the brush, emitters get the brushes emitter configuration
+    fn place_brush_cell(&mut self, pos: IVec2, material: Material) {
+        if material == Material::Emitter {
+            self.place_emitter(pos, self.brush.emitter);
+        } else {
+            self.set_cell_material(pos, material, false);
+        };
+    }
+
+    /// Records an event, which can be drained after the update
+    pub fn push_event(&mut self, event: SimEvent) {
+        if self.record_events {
+            self.events.push(event);
+        };
+    }
+
+    /// Returns the events of the last update without removing them
+    pub fn get_events(&self) -> &[SimEvent] {
+        &self.events
+    }
+
+    /// Removes and returns all events of the last update
+    pub fn drain_events(&mut self) -> std::vec::Drain<'_, SimEvent> {
+        self.events.drain(..)
+    }
+
+    /// Removes and returns only the events matching the filter, the rest stays for other consumers
+    pub fn drain_events_filtered(&mut self, filter: &EventFilter) -> Vec<SimEvent> {
+        let (matching, rest) = std::mem::take(&mut self.events).into_iter().partition(|event| filter.matches(event));
+        self.events = rest;
+        matching
+    }
+
+    /// Returns the solid clusters which are currently falling as one piece
+    pub fn get_rigid_clusters(&self) -> &[RigidCluster] {
+        &self.rigid_clusters
+    }
 
-        //self.set_chunk_active(pos + cell_velocity.round().as_ivec2())
+    /// Checks the solid cells around every removed solid cell for support and lets unsupported clusters collapse
+    fn update_structure(&mut self) {
+        let checks = std::mem::take(&mut self.structure_checks);
+        let mut rigid_clusters = std::mem::take(&mut self.rigid_clusters);
+        rigid_clusters.retain_mut(|cluster| cluster.step(self));
+        self.rigid_clusters = rigid_clusters;
+        // Falling clusters leave holes behind them, those don't need to be checked
+        self.structure_checks.clear();
+        if !self.structural_integrity {
+            return;
+        };
+
+        // Cells that are already falling must not be picked up by another cluster
+        let mut visited: HashSet<IVec2> = self.rigid_clusters.iter().flat_map(|c| c.get_cells().iter().copied()).collect();
+        for pos in checks {
+            for offset in [IVec2::new(1, 0), IVec2::new(-1, 0), IVec2::new(0, 1), IVec2::new(0, -1)] {
+                let start = match self.wrap_pos(pos + offset) {
+                    Some(start) => start,
+                    None => continue,
+                };
+                if let Some(cluster) = structure::find_unsupported_cluster(self, start, &mut visited) {
+                    match self.collapse_mode {
+                        CollapseMode::Debris => structure::crumble(self, &cluster),
+                        CollapseMode::Rigid => self.rigid_clusters.push(RigidCluster::new(cluster)),
+                    };
+                };
+            };
+        };
     }
 
     /// Places cells in the specified brush size
     pub fn draw_brush(&mut self, pos: IVec2, material: Material) {
+        // In a lockstep session the brush only gets drawn once the host hands the action back for a tick
+        let action = self.get_brush_action(pos, material);
+        if let Some(outbox) = &mut self.lockstep_outbox {
+            outbox.push(action);
+            return;
+        };
+        self.draw_brush_now(pos, material);
+    }
+
+    /// Draws the brush right away, even during a lockstep session
+    pub(crate) fn draw_brush_now(&mut self, pos: IVec2, material: Material) {
+        self.record_brush(pos, material);
         let bs = self.brush.size as i32;
-        if bs == 1 && !self.brush.place_fire {
-            self.set_cell_material(pos, material, false);
+        if bs == 1 && !self.brush.place_fire && !self.brush.place_force {
+            self.place_brush_cell(pos, material);
             return;
         };
         let bs_2 = bs as f32 / 2.0;
         let lower = bs_2.floor() as i32;
         let upper = bs_2.ceil() as i32;
+        if self.brush.place_force {
+            let force = self.brush.force;
+            self.force_field.set_force_in_rect(pos - IVec2::splat(lower), pos + IVec2::splat(upper - 1), force);
+            self.set_chunk_cluster_active(pos);
+            return;
+        };
         for y in (pos.y-lower..pos.y+upper).rev() {
             for x in pos.x-lower..pos.x+upper {
                 let cur_pos = IVec2::new(x, y);
                 if self.brush.place_fire {
-                    if let Some(c) = self.get_cell_mut(cur_pos) {
-                        if c.material.get_flammability() > 0.0 {
-                            c.is_on_fire = true;
-                        };
-                        self.set_chunk_active(cur_pos);
-                    };
+                    self.ignite(cur_pos);
                 } else {
-                    self.set_cell_material(cur_pos, material, false);
+                    self.place_brush_cell(cur_pos, material);
                 };
             };
         };
     }
 
+    /// Returns the inconsistencies found after the last update (only filled if check_consistency is set)
+    pub fn get_consistency_violations(&self) -> &[ConsistencyViolation] {
+        &self.consistency_violations
+    }
+
     /// New frame. Update the matrix (includes cells and chunks)
     pub fn update(&mut self) {
+        // Events which haven't been drained after the last update are dropped
+        self.events.clear();
+        let counts_before = if self.check_consistency { Some(self.count_materials()) } else { None };
+
+        // Cells inside of a force field keep getting pushed, so their chunks never fall asleep
+        for pos in self.force_field.get_active_positions() {
+            self.set_rect_active(pos, pos + IVec2::splat(FORCE_CELL_SIZE - 1));
+        };
+
+        self.update_emitters();
+
+        self.profiler.count_update();
+        let chunk_start = self.profiler.start();
         // Tells all chunks that a new frame has begun
         self.chunks.par_iter_mut().for_each(|chunk| {
             chunk.start_step();
         });
 
-        // Helpers to only convert to i32 once
-        let w = self.width as i32;
+        // Helper to only convert to i32 once
         let h = self.height as i32;
 
         // Tell every cells that a new frame has begun
-        self.cells.par_iter_mut().for_each(|cell| {
-            cell.processed_this_frame = false;
-            cell.post_update();
-        });
+        let gravity_dir = self.get_gravity_dir();
+        self.cells.start_frame(gravity_dir);
+        self.profiler.stop(Stage::ChunkStart, chunk_start);
 
 
         // Iterate all cells from the bottom up and either from left to right or the other way around
@@ -350,20 +797,55 @@ impl Matrix {
         // });
 
 
-        let x_range: Vec<i32> = if self.update_left {
-            (0..w).collect()
-        } else {
-            (0..w).rev().collect()
+        // Only the dirty rectangles of the active chunks get stepped, still row by row over the whole world
+        let mut stats = StepStats::default();
+        let mut chunk_columns: Vec<usize> = (0..self.chunks_x).collect();
+        if !self.update_left {
+            chunk_columns.reverse();
+        };
+        for chunk in self.chunks.iter().filter(|c| c.should_step) {
+            stats.active_chunks += 1;
+            stats.active_area += chunk.get_bounds().area();
+            stats.stepped_area += chunk.dirty_rect.map_or(0, |rect| rect.area());
         };
-
         for y in (0..h).rev() {
-            for x in &x_range {
-            //x_range.par_iter().for_each(|x| {
-                self.step_all(*x, y);
-            //});
+            let chunk_row = (y / CHUNK_SIZE_I32) as usize;
+            for chunk_x in chunk_columns.iter() {
+                let chunk = &self.chunks[chunk_x + chunk_row * self.chunks_x];
+                let rect = match chunk.dirty_rect {
+                    Some(rect) if chunk.should_step && y >= rect.min.y && y <= rect.max.y => rect,
+                    _ => continue,
+                };
+                if self.update_left {
+                    for x in rect.min.x..=rect.max.x {
+                        self.step_all(x, y);
+                    };
+                } else {
+                    for x in (rect.min.x..=rect.max.x).rev() {
+                        self.step_all(x, y);
+                    };
+                };
             };
         };
+        self.step_stats = stats;
         self.update_left = !self.update_left;
+
+        self.update_particles();
+        self.update_structure();
+
+        self.run_scenario();
+
+        self.tick += 1;
+        if let Some(recording) = &mut self.recording {
+            recording.push(ReplayEvent::Tick);
+        };
+
+        if let Some(counts_before) = counts_before {
+            self.consistency_violations = self.find_inconsistencies(Some(&counts_before));
+            consistency::report_inconsistencies(&self.consistency_violations);
+        } else {
+            self.consistency_violations.clear();
+        };
     }
 
     /// Helper function to always execute the same logic regardless of wether iterating from the left or right side of the window
@@ -374,59 +856,137 @@ impl Matrix {
         if !self.chunk_in_bounds(chunk_pos) {
             return;
         };
-        let chunk_index = chunk_pos.x as usize + chunk_pos.y as usize * NUM_CHUNKS_X;
+        let chunk_index = self.chunk_index(chunk_pos);
         let cur_chunk = &self.chunks[chunk_index];
         
         // If the chunk should process, update the cell
         if cur_chunk.should_step {
-            let cell_idx = self.get_data_at_pos(cur_pos);
-            if cell_idx == 0 {
+            let idx = self.cell_idx(cur_pos);
+            if !self.cells.is_occupied(idx) || self.cells.flags[idx] & PROCESSED != 0 {
                 return;
             };
-            if let Some(cell) = self.get_cell_by_cellindex_mut(cell_idx) {
-                if !cell.processed_this_frame {
+            let update_start = self.profiler.start();
+            let acceleration = self.get_acceleration(cur_pos);
+            self.cells.flags[idx] |= PROCESSED;
+            // Most cells are cold and only get accelerated, those don't need the whole cell
+            if !self.cells.update_cold(idx, acceleration) {
+                let changed = {
+                    let mut cell = CellMut::new(&mut self.cells, idx).unwrap();
                     let hp = cell.hp;
-                    cell.update();
-                    cell.processed_this_frame = true;
-                    if cell.hp != hp || cell.is_on_fire || cell.was_on_fire_last_frame {
-                        self.set_chunk_cluster_active(cur_pos);
-                    };
-                    cell_handler::handle_cell(self, cell_idx, chunk_index);
+                    cell.update(acceleration, self.rng.get_mut().unwrap());
+                    cell.hp != hp || cell.is_on_fire || cell.was_on_fire_last_frame || cell.heat > 0.0
+                };
+                if changed {
+                    self.set_chunk_active(cur_pos);
                 };
             };
+            self.profiler.stop(Stage::CellUpdate, update_start);
+            cell_handler::handle_cell(self, cur_pos, chunk_index);
         };
     }
 
 
-    /// Renders all the cells into the pixel buffer
-    pub fn draw(&self, screen: &mut [u8]) {
-        //debug_assert_eq!(screen.len(), 4 * self.cells.len());
+    /// Number of chunks that were drawn again by the last Matrix::draw
+    pub fn get_rendered_chunks(&self) -> usize {
+        self.rendered_chunks
+    }
 
-        // Faster solution for filling the array
-        unsafe {
-            std::ptr::write_bytes(screen.as_mut_ptr(), 0, screen.len());
-        };
+    /// Makes the next Matrix::draw render every chunk, e.g. after the pixel buffer got replaced
+    pub fn redraw_all(&mut self) {
+        self.last_draw_settings = None;
+    }
 
-        let sc = RwLock::new(screen);
+    /// Decides which chunks have to be drawn again and resets their needs_render flag
+    fn take_chunks_to_render(&mut self) -> Vec<bool> {
+        let up = self.get_shading_up();
+        let settings = (self.debug_overlay, self.shading, up);
+        // Overlays show things that change every frame without any cell changing
+        let render_all = self.debug_overlay != DebugOverlay::None || self.last_draw_settings != Some(settings);
+        self.last_draw_settings = Some(settings);
 
-        self.cells.par_iter().for_each(|c| {
-            let mut draw_color = c.color;
-            
-            let chunk_pos = c.pos / CHUNK_SIZE_VEC;
-            if self.chunk_in_bounds(chunk_pos) {
-                let chunk = &self.chunks[(chunk_pos.x as usize + chunk_pos.y as usize * NUM_CHUNKS_X)];
-                if self.debug_draw && chunk.should_step {
-                    draw_color = Color::RED;
+        let chunks_x = self.chunks_x as i32;
+        let chunks_y = self.chunks_y as i32;
+        for pos in std::mem::take(&mut self.drawn_particles) {
+            let chunk_index = self.chunk_index(pos / CHUNK_SIZE_VEC);
+            self.chunks[chunk_index].needs_render = true;
+        };
+        let mut render: Vec<bool> = self.chunks.iter().map(|c| render_all || c.needs_render).collect();
+        if self.shading && !render_all {
+            // Cells are shaded by what is above them (up to OCCLUSION_DEPTH, less than a chunk),
+            // so a change also affects the chunks below it
+            for (i, chunk) in self.chunks.iter().enumerate().filter(|(_, c)| c.needs_render) {
+                let chunk_pos = chunk.topleft / CHUNK_SIZE_VEC;
+                for offset in [IVec2::new(up.x, 0), IVec2::new(0, up.y), up] {
+                    let below = chunk_pos - offset;
+                    let below = IVec2::new(below.x.rem_euclid(chunks_x), below.y.rem_euclid(chunks_y));
+                    render[(below.x + below.y * chunks_x) as usize] = true;
                 };
+                render[i] = true;
             };
-    
-            let idx = self.cell_idx(c.pos) * 4;
-            let pixel_color = &mut sc.write().unwrap()[idx..idx+4];
-            let color = [(draw_color.r * 255.0) as u8, (draw_color.g * 255.0) as u8, (draw_color.b * 255.0) as u8, (draw_color.a * 255.0) as u8];
-            if pixel_color != color {
-                pixel_color.copy_from_slice(&color);
+        };
+        self.chunks.iter_mut().for_each(|chunk| chunk.needs_render = false);
+        render
+    }
+
+    /// Colour of the pixel at the index in the cell storage
+    fn get_draw_color(&self, idx: usize, up: IVec2) -> [u8; 4] {
+        let material = self.cells.material[idx];
+        let overlay_color = match self.debug_overlay {
+            DebugOverlay::None => None,
+            _ => self.cells.get(idx).and_then(|c| self.get_overlay_color(&c)),
+        };
+        match overlay_color {
+            Some(color) => storage::pack_color(color),
+            None if self.shading && material != Material::Empty => {
+                let shade = self.get_shade(self.cells.pos_of(idx), material, up) as f64;
+                storage::pack_color(darken_color(storage::unpack_color(self.cells.color[idx]), shade))
+            },
+            None => self.cells.color[idx],
+        }
+    }
+
+    /// Renders the cells of all chunks that changed since the last call into the pixel buffer.
+    ///
+    /// The buffer has to be the same one every frame, everything else is left as it was drawn before
+    pub fn draw(&mut self, screen: &mut [u8]) {
+        let render = self.take_chunks_to_render();
+        self.rendered_chunks = render.iter().filter(|r| **r).count();
+        let chunks_x = self.chunks_x;
+        let up = self.get_shading_up();
+        let plain = self.debug_overlay == DebugOverlay::None && !self.shading;
+
+        // Every row of chunks gets its own slice of the buffer, so they can be drawn in parallel without locking
+        let this = &*self;
+        screen.par_chunks_mut(this.width * CHUNK_SIZE * 4).enumerate().for_each(|(chunk_y, rows)| {
+            for chunk_x in (0..chunks_x).filter(|chunk_x| render[chunk_x + chunk_y * chunks_x]) {
+                let min_x = chunk_x * CHUNK_SIZE;
+                let max_x = (min_x + CHUNK_SIZE).min(this.width);
+                for (row_y, row) in rows.chunks_exact_mut(this.width * 4).enumerate() {
+                    let row_start = (chunk_y * CHUNK_SIZE + row_y) * this.width;
+                    let pixels = &mut row[min_x * 4..max_x * 4];
+                    if plain {
+                        // Empty positions are zeroed, so the colour column already holds the finished pixels
+                        pixels.copy_from_slice(bytemuck::cast_slice(&this.cells.color[row_start + min_x..row_start + max_x]));
+                        continue;
+                    };
+                    for (x, pixel) in (min_x..max_x).zip(pixels.chunks_exact_mut(4)) {
+                        pixel.copy_from_slice(&this.get_draw_color(row_start + x, up));
+                    };
+                };
             };
         });
+
+        // Particles are drawn on top of the grid
+        self.draw_overlay_extras(screen);
+        for particle in self.particles.iter() {
+            let pos = particle.get_grid_pos();
+            if !self.is_in_bounds(pos) {
+                continue;
+            };
+            let idx = self.cell_idx(pos) * 4;
+            screen[idx..idx+4].copy_from_slice(&storage::pack_color(particle.cell.color));
+            self.drawn_particles.push(pos);
+        };
     }
 
     /// Draws a line with the specified material
@@ -443,3 +1003,25 @@ impl Matrix {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worlds_of_other_sizes_update() {
+        for (width, height) in [(64, 64), (96, 64), (100, 70)] {
+            let mut matrix = Matrix::new_empty(width, height);
+            matrix.set_cell_material(IVec2::new(width as i32 - 1, 0), Material::Sand, false);
+            matrix.set_cell_material(IVec2::new(5, 5), Material::Water, false);
+            for _ in 0..100 {
+                matrix.update();
+            };
+            assert_eq!(matrix.chunks.len(), matrix.chunks_x * matrix.chunks_y);
+            assert_eq!(matrix.iter_cells().count(), 2);
+            let mut screen = vec![0; width * height * 4];
+            matrix.draw(&mut screen);
+        };
+    }
+}
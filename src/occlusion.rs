@@ -0,0 +1,140 @@
+use glam::{IVec2, Vec2, Vec3};
+use rayon::prelude::*;
+
+use crate::Matrix;
+
+
+/// Most samples a shadow ray takes on its way to a light, MAX_OCCLUSION_STEPS in noise.wgsl has to match
+pub const MAX_OCCLUSION_STEPS: i32 = 128;
+
+/// How every grid position absorbs light, uploaded to the renderer every frame (see NoiseRenderer::set_occlusion_mask).
+/// The shader casts shadows by marching through it towards every light.
+///
+/// Every position has 4 bytes: the light tint in rgb and the opacity in a, see Material::get_opacity and Material::get_light_tint
+pub struct OcclusionMask {
+    width: usize,
+    height: usize,
+    data: Vec<[u8; 4]>,
+}
+
+impl OcclusionMask {
+    pub fn new(width: usize, height: usize) -> Self {
+        OcclusionMask { width, height, data: vec![[0; 4]; width * height] }
+    }
+
+    pub fn get_width(&self) -> usize {
+        self.width
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    /// Four bytes per position (rgba), row by row
+    pub fn as_bytes(&self) -> &[u8] {
+        self.data.as_flattened()
+    }
+
+    /// Part of the light of every color (0 - 1) that passes the position, positions outside are clamped to the edge like
+    /// textureLoad in the shader
+    pub fn get_transmission(&self, pos: IVec2) -> Vec3 {
+        let pos = pos.clamp(IVec2::ZERO, IVec2::new(self.width as i32 - 1, self.height as i32 - 1));
+        let [r, g, b, a] = self.data[pos.x as usize + pos.y as usize * self.width].map(|v| v as f32 / 255.0);
+        Vec3::ONE.lerp(Vec3::new(r, g, b), a)
+    }
+
+    /// How much of every color of a light at `light` reaches `from`, both in texture coordinates (0 - 1 across the world).
+    ///
+    /// Same as occlusion_visibility in noise.wgsl: samples once per pixel along the way (at most MAX_OCCLUSION_STEPS times)
+    /// and ignores the pixels at both ends, so surfaces facing a light and the cell a light sits on are lit
+    pub fn get_visibility(&self, from: Vec2, light: Vec2) -> Vec3 {
+        let size = Vec2::new(self.width as f32, self.height as f32);
+        let start = from * size;
+        let end = light * size;
+        let start_texel = start.floor().as_ivec2();
+        let end_texel = end.floor().as_ivec2();
+        let steps = (start.distance(end).ceil() as i32).min(MAX_OCCLUSION_STEPS);
+        let mut visibility = Vec3::ONE;
+        for i in 1..steps {
+            let texel = start.lerp(end, i as f32 / steps as f32).floor().as_ivec2();
+            if texel == start_texel || texel == end_texel {
+                continue;
+            };
+            visibility *= self.get_transmission(texel);
+            if visibility.cmple(Vec3::ZERO).all() {
+                break;
+            };
+        };
+        visibility
+    }
+}
+
+impl Matrix {
+    /// Fills the mask with the opacity and light tint of every cell
+    pub fn write_occlusion_mask(&self, mask: &mut OcclusionMask) {
+        if mask.width != self.width || mask.height != self.height {
+            *mask = OcclusionMask::new(self.width, self.height);
+        };
+        let materials = &self.get_cell_storage().material;
+        mask.data.par_iter_mut().zip(materials.par_iter()).for_each(|(texel, material)| {
+            let tint = material.get_light_tint();
+            *texel = [tint.r, tint.g, tint.b, material.get_opacity() as f64].map(|v| (v * 255.0 + 0.5) as u8);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Material;
+
+    const SIZE: usize = 64;
+
+    /// Visibility along row 32 from the cell at x = 5 to a light on the cell at x = 50, with the given cells placed first
+    fn visibility(cells: &[(i32, Material)]) -> Vec3 {
+        let mut matrix = Matrix::new_empty(SIZE, SIZE);
+        for (x, material) in cells {
+            matrix.set_cell_material(IVec2::new(*x, 32), *material, false);
+        };
+        let mut mask = OcclusionMask::new(SIZE, SIZE);
+        matrix.write_occlusion_mask(&mut mask);
+        let texel_center = |x: f32| Vec2::new(x + 0.5, 32.5) / SIZE as f32;
+        mask.get_visibility(texel_center(5.0), texel_center(50.0))
+    }
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!(a.abs_diff_eq(b, 0.01), "{a} != {b}");
+    }
+
+    #[test]
+    fn walls_block_light() {
+        assert_eq!(visibility(&[(30, Material::Rock)]), Vec3::ZERO);
+    }
+
+    #[test]
+    fn open_paths_are_fully_lit() {
+        assert_eq!(visibility(&[]), Vec3::ONE);
+        // Cells off the path don't matter
+        assert_eq!(visibility(&[(4, Material::Rock), (51, Material::Rock)]), Vec3::ONE);
+    }
+
+    #[test]
+    fn end_texels_are_excluded() {
+        assert_eq!(visibility(&[(5, Material::Rock), (50, Material::Rock)]), Vec3::ONE);
+    }
+
+    #[test]
+    fn liquids_and_smoke_tint_the_light() {
+        for material in [Material::Water, Material::Oil, Material::Smoke] {
+            let tint = material.get_light_tint();
+            let expected = Vec3::ONE.lerp(Vec3::new(tint.r as f32, tint.g as f32, tint.b as f32), material.get_opacity());
+            assert!(expected.cmplt(Vec3::ONE).any());
+            assert_close(visibility(&[(30, material)]), expected);
+            assert_close(visibility(&[(30, material), (31, material)]), expected * expected);
+        };
+        let water = visibility(&[(30, Material::Water)]);
+        assert!(water.x < water.z);
+        let oil = visibility(&[(30, Material::Oil)]);
+        assert!(oil.x > oil.z);
+    }
+}
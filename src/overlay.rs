@@ -0,0 +1,225 @@
+use glam::{IVec2, Vec2};
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+
+use crate::{Cell, Color, Material, Matrix, CHUNK_SIZE};
+
+
+/// Debug visualisation drawn by Matrix::draw instead of the normal cell colours
+#[derive(Clone, Copy, PartialEq, Eq, EnumIter, Debug, Default)]
+pub enum DebugOverlay {
+    #[default]
+    None,
+    /// Cells which are updated this frame are red, the dirty rectangles of the chunks get outlined
+    ChunkActivity,
+    /// Hue shows the direction of the velocity, brightness its magnitude
+    Velocity,
+    FreeFalling,
+    OnFire,
+    /// Remaining hp compared to the materials full hp
+    Health,
+    Density,
+    /// Where the cell comes in the order the last update stepped the grid: rows from the bottom up,
+    /// each row from the side the update started on
+    UpdateOrder,
+}
+
+/// Goes from blue (0.0) over green to red (1.0)
+pub fn heatmap_color(t: f32) -> Color {
+    let t = t.clamp(0.0, 1.0) as f64;
+    let (r, g, b) = if t < 0.25 {
+        (0.0, t * 4.0, 1.0)
+    } else if t < 0.5 {
+        (0.0, 1.0, 1.0 - (t - 0.25) * 4.0)
+    } else if t < 0.75 {
+        ((t - 0.5) * 4.0, 1.0, 0.0)
+    } else {
+        (1.0, 1.0 - (t - 0.75) * 4.0, 0.0)
+    };
+    Color { r, g, b, a: 1.0 }
+}
+
+/// Colour for a direction (hue) and a magnitude (brightness, 0 - 1)
+pub fn direction_color(dir: Vec2, magnitude: f32) -> Color {
+    let hue = (dir.y.atan2(dir.x) / std::f32::consts::TAU).rem_euclid(1.0) * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    let (r, g, b) = match hue as u32 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    let v = (0.15 + magnitude.clamp(0.0, 1.0) * 0.85) as f64;
+    Color { r: r as f64 * v, g: g as f64 * v, b: b as f64 * v, a: 1.0 }
+}
+
+/// Colour for a yes/no flag
+fn flag_color(flag: bool) -> Color {
+    if flag {
+        Color { r: 1.0, g: 0.8, b: 0.0, a: 1.0 }
+    } else {
+        Color { r: 0.2, g: 0.2, b: 0.3, a: 1.0 }
+    }
+}
+
+impl DebugOverlay {
+    /// The overlay after this one, wrapping around to DebugOverlay::None
+    pub fn next(&self) -> DebugOverlay {
+        let overlays: Vec<DebugOverlay> = DebugOverlay::iter().collect();
+        let index = overlays.iter().position(|o| o == self).unwrap();
+        overlays[(index + 1) % overlays.len()]
+    }
+
+    /// Colours used by the overlay and what they mean
+    pub fn get_legend(&self) -> Vec<(Color, String)> {
+        match self {
+            DebugOverlay::None => vec![],
+            DebugOverlay::ChunkActivity => vec![
+                (Color::RED, "Cell is updated".to_string()),
+                (Color { r: 0.5, g: 0.0, b: 0.0, a: 1.0 }, "Dirty rectangle".to_string()),
+            ],
+            DebugOverlay::Velocity => vec![
+                (direction_color(Vec2::new(1.0, 0.0), 1.0), "Right".to_string()),
+                (direction_color(Vec2::new(0.0, 1.0), 1.0), "Down".to_string()),
+                (direction_color(Vec2::new(-1.0, 0.0), 1.0), "Left".to_string()),
+                (direction_color(Vec2::new(0.0, -1.0), 1.0), "Up".to_string()),
+                (direction_color(Vec2::new(1.0, 0.0), 0.0), "Resting (darker is slower)".to_string()),
+            ],
+            DebugOverlay::FreeFalling => vec![
+                (flag_color(true), "Free falling".to_string()),
+                (flag_color(false), "Resting".to_string()),
+            ],
+            DebugOverlay::OnFire => vec![
+                (flag_color(true), "On fire".to_string()),
+                (flag_color(false), "Not burning".to_string()),
+            ],
+            DebugOverlay::Health => vec![
+                (heatmap_color(0.0), "No hp left".to_string()),
+                (heatmap_color(0.5), "Half hp".to_string()),
+                (heatmap_color(1.0), "Full hp".to_string()),
+            ],
+            DebugOverlay::Density => {
+                let max = max_density();
+                [0.0, 0.5, 1.0].iter()
+                    .map(|t| (heatmap_color(*t), format!("Density {}", (*t as f64 * max as f64).round())))
+                    .collect()
+            },
+            DebugOverlay::UpdateOrder => vec![
+                (heatmap_color(0.0), "Stepped first".to_string()),
+                (heatmap_color(1.0), "Stepped last".to_string()),
+            ],
+        }
+    }
+}
+
+/// Highest density of all materials that can move, anchors like emitters would squash the gradient
+fn max_density() -> u64 {
+    Material::iter().filter(|m| !m.is_structural_anchor()).map(|m| m.get_density()).max().unwrap_or(1)
+}
+
+impl Matrix {
+    /// Colour of the cell in the current overlay, None draws the cell normally
+    pub(crate) fn get_overlay_color(&self, cell: &Cell) -> Option<Color> {
+        match self.debug_overlay {
+            DebugOverlay::None => None,
+            DebugOverlay::ChunkActivity => {
+                let chunk_pos = cell.pos / IVec2::splat(CHUNK_SIZE as i32);
+                let active = self.chunk_in_bounds(chunk_pos) && {
+                    let chunk = &self.chunks[self.chunk_index(chunk_pos)];
+                    chunk.should_step && chunk.dirty_rect.is_some_and(|rect| rect.contains(cell.pos))
+                };
+                if active { Some(Color::RED) } else { None }
+            },
+            DebugOverlay::Velocity => {
+                let magnitude = cell.velocity.length() / cell.material.get_terminal_velocity();
+                Some(direction_color(cell.velocity, magnitude))
+            },
+            DebugOverlay::FreeFalling => Some(flag_color(cell.is_free_falling)),
+            DebugOverlay::OnFire => Some(flag_color(cell.is_on_fire)),
+            DebugOverlay::Health => Some(heatmap_color(cell.hp as f32 / cell.material.get_hp().max(1) as f32)),
+            DebugOverlay::Density => Some(heatmap_color(cell.material.get_density() as f32 / max_density() as f32)),
+            DebugOverlay::UpdateOrder => {
+                // Matrix::update flips update_left once it is done, so this is the direction of the last one
+                let x = if self.update_left { self.width as i32 - 1 - cell.pos.x } else { cell.pos.x };
+                let order = (self.height as i32 - 1 - cell.pos.y) as usize * self.width + x as usize;
+                Some(heatmap_color(order as f32 / (self.width * self.height - 1).max(1) as f32))
+            },
+        }
+    }
+
+    /// Draws what the overlay shows besides the cells themselves
+    pub(crate) fn draw_overlay_extras(&self, screen: &mut [u8]) {
+        if self.debug_overlay != DebugOverlay::ChunkActivity {
+            return;
+        };
+        let outline = [128, 0, 0, 255];
+        for rect in self.chunks.iter().filter(|c| c.should_step).filter_map(|c| c.dirty_rect) {
+            for x in rect.min.x..=rect.max.x {
+                for y in [rect.min.y, rect.max.y] {
+                    let idx = (x as usize + y as usize * self.width) * 4;
+                    screen[idx..idx+4].copy_from_slice(&outline);
+                };
+            };
+            for y in rect.min.y..=rect.max.y {
+                for x in [rect.min.x, rect.max.x] {
+                    let idx = (x as usize + y as usize * self.width) * 4;
+                    screen[idx..idx+4].copy_from_slice(&outline);
+                };
+            };
+        };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_order_follows_the_last_update() {
+        let mut matrix = Matrix::new_empty(64, 64);
+        let corners = [IVec2::new(0, 63), IVec2::new(63, 63), IVec2::new(0, 0), IVec2::new(63, 0)];
+        for pos in corners {
+            matrix.set_cell_material(pos, Material::Rock, false);
+        };
+        matrix.debug_overlay = DebugOverlay::UpdateOrder;
+        let order = |matrix: &Matrix, pos: IVec2| matrix.get_cell(pos).and_then(|c| matrix.get_overlay_color(&c));
+
+        // Stepped from the left: the bottom left cell comes first, the top right one last
+        matrix.update();
+        assert!(!matrix.update_left);
+        assert_eq!(order(&matrix, corners[0]), Some(heatmap_color(0.0)));
+        assert_eq!(order(&matrix, corners[3]), Some(heatmap_color(1.0)));
+
+        matrix.update();
+        assert_eq!(order(&matrix, corners[1]), Some(heatmap_color(0.0)));
+        assert_eq!(order(&matrix, corners[2]), Some(heatmap_color(1.0)));
+    }
+
+    #[test]
+    fn chunk_activity_stays_inside_worlds_of_other_sizes() {
+        let (width, height) = (100, 70);
+        let mut matrix = Matrix::new_empty(width, height);
+        matrix.debug_overlay = DebugOverlay::ChunkActivity;
+        let mut screen = vec![0; width * height * 4];
+        let pixel = |screen: &[u8], x: usize, y: usize| screen[(x + y * width) * 4..][..4].to_vec();
+
+        // Every chunk is active at first, the edge ones only reach to the edge of the world
+        matrix.update();
+        matrix.draw(&mut screen);
+        assert_eq!(pixel(&screen, width - 1, height - 1), [128, 0, 0, 255]);
+
+        // Only the sand falling down the right edge stays active, nothing wraps into the next row
+        matrix.set_cell_material(IVec2::new(width as i32 - 2, 40), Material::Sand, false);
+        for _ in 0..10 {
+            matrix.update();
+        };
+        screen.fill(0);
+        matrix.draw(&mut screen);
+        let outlined = |screen: &[u8], x, y| pixel(screen, x, y) == [128, 0, 0, 255];
+        assert!((0..height).any(|y| outlined(&screen, width - 1, y)));
+        assert!((0..height).all(|y| !outlined(&screen, 0, y)));
+    }
+}
@@ -0,0 +1,28 @@
+use glam::{IVec2, Vec2};
+
+use crate::Cell;
+
+
+/// A cell which has been detached from the grid and flies freely with sub-pixel precision until it hits something
+#[derive(Clone)]
+pub struct Particle {
+    pub pos: Vec2,
+    pub velocity: Vec2,
+    /// The cell that gets placed back into the matrix once the particle lands
+    pub cell: Cell,
+}
+
+impl Particle {
+    pub fn new(cell: Cell, velocity: Vec2) -> Self {
+        Particle {
+            pos: cell.pos.as_vec2(),
+            velocity,
+            cell,
+        }
+    }
+
+    /// The grid position the particle is currently over
+    pub fn get_grid_pos(&self) -> IVec2 {
+        self.pos.round().as_ivec2()
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+
+
+/// Number of frames kept in the rolling history
+pub const HISTORY_LENGTH: usize = 300;
+/// File the Info window writes the history to
+pub const DEFAULT_PROFILE_PATH: &str = "profile.csv";
+
+/// Part of a frame that gets timed separately
+#[derive(Clone, Copy, PartialEq, Eq, EnumIter, Debug)]
+pub enum Stage {
+    /// Telling chunks and cells that a new simulation step has begun
+    ChunkStart,
+    /// Cell::update of every stepped cell
+    CellUpdate,
+    Fire,
+    /// Movement of movable solids, liquids and gases
+    Movement,
+    /// Matrix::draw
+    Draw,
+    EguiPrepare,
+    /// Recording and submitting everything to the GPU
+    GpuSubmit,
+}
+
+impl Stage {
+    pub const COUNT: usize = 7;
+
+    /// Name used as CSV column
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Stage::ChunkStart => "chunk_start",
+            Stage::CellUpdate => "cell_update",
+            Stage::Fire => "fire",
+            Stage::Movement => "movement",
+            Stage::Draw => "draw",
+            Stage::EguiPrepare => "egui_prepare",
+            Stage::GpuSubmit => "gpu_submit",
+        }
+    }
+}
+
+/// Timings of a single frame
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FrameTimings {
+    /// Time since the previous frame ended
+    pub frame_time: Duration,
+    /// Time spent in every stage, indexed by the stage
+    pub stages: [Duration; Stage::COUNT],
+    /// Simulation steps done during the frame
+    pub updates: u32,
+}
+
+impl FrameTimings {
+    pub fn get(&self, stage: Stage) -> Duration {
+        self.stages[stage as usize]
+    }
+}
+
+/// Measures how long the stages of every frame take and keeps the last HISTORY_LENGTH frames.
+///
+/// Stages are only timed while enabled, since timing the cell stages adds some overhead to every stepped cell.
+/// The frame time (and with it the FPS) is always tracked
+#[derive(Default)]
+pub struct Profiler {
+    pub enabled: bool,
+    current: FrameTimings,
+    history: VecDeque<FrameTimings>,
+    last_frame_end: Option<Instant>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts timing a stage, returns None while the profiler is disabled
+    pub fn start(&self) -> Option<Instant> {
+        self.enabled.then(Instant::now)
+    }
+
+    /// Adds the time since start (from Profiler::start) to the stage
+    pub fn stop(&mut self, stage: Stage, start: Option<Instant>) {
+        if let Some(start) = start {
+            self.record(stage, start.elapsed());
+        };
+    }
+
+    /// Adds the duration to the stage of the current frame
+    pub fn record(&mut self, stage: Stage, duration: Duration) {
+        if self.enabled {
+            self.current.stages[stage as usize] += duration;
+        };
+    }
+
+    /// Counts a simulation step for the current frame
+    pub fn count_update(&mut self) {
+        self.current.updates += 1;
+    }
+
+    /// Finishes the current frame and moves it into the history
+    pub fn end_frame(&mut self) {
+        let now = Instant::now();
+        if let Some(last) = self.last_frame_end {
+            self.current.frame_time = now - last;
+        };
+        self.last_frame_end = Some(now);
+        self.history.push_back(std::mem::take(&mut self.current));
+        while self.history.len() > HISTORY_LENGTH {
+            self.history.pop_front();
+        };
+    }
+
+    /// Finished frames, oldest first
+    pub fn get_history(&self) -> &VecDeque<FrameTimings> {
+        &self.history
+    }
+
+    /// Frames per second over the last second of the history
+    pub fn get_fps(&self) -> f32 {
+        let mut frames = 0;
+        let mut time = Duration::ZERO;
+        for frame in self.history.iter().rev().take_while(|f| f.frame_time > Duration::ZERO) {
+            frames += 1;
+            time += frame.frame_time;
+            if time >= Duration::from_secs(1) {
+                break;
+            };
+        };
+        if time.is_zero() {
+            0.0
+        } else {
+            frames as f32 / time.as_secs_f32()
+        }
+    }
+
+    /// Average time of the stage over the whole history
+    pub fn get_average(&self, stage: Stage) -> Duration {
+        if self.history.is_empty() {
+            return Duration::ZERO;
+        };
+        self.history.iter().map(|f| f.get(stage)).sum::<Duration>() / self.history.len() as u32
+    }
+
+    /// Writes one line per frame of the history, all times in milliseconds
+    pub fn write_csv<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "frame,frame_time,updates")?;
+        for stage in Stage::iter() {
+            write!(writer, ",{}", stage.get_name())?;
+        };
+        writeln!(writer)?;
+        for (i, frame) in self.history.iter().enumerate() {
+            write!(writer, "{},{:.4},{}", i, frame.frame_time.as_secs_f64() * 1000.0, frame.updates)?;
+            for stage in Stage::iter() {
+                write!(writer, ",{:.4}", frame.get(stage).as_secs_f64() * 1000.0)?;
+            };
+            writeln!(writer)?;
+        };
+        Ok(())
+    }
+
+    /// Saves the history as CSV file
+    pub fn save_csv<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_csv(&mut writer)?;
+        writer.flush()
+    }
+}
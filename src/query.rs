@@ -0,0 +1,242 @@
+use glam::{IVec2, Vec2};
+
+use crate::consistency::{MaterialCounts, NUM_MATERIAL_IDS};
+use crate::storage::CellRef;
+use crate::{Material, MaterialType, Matrix};
+
+
+/// Part of the world that gets searched by the spatial queries. Everything outside of the world is ignored, also at wrapping edges
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Region {
+    /// Both corners inclusive
+    Rect { min: IVec2, max: IVec2 },
+    /// All positions at most radius away from center
+    Circle { center: IVec2, radius: i32 },
+}
+
+impl Region {
+    pub fn contains(&self, pos: IVec2) -> bool {
+        match *self {
+            Region::Rect { min, max } => pos.cmpge(min).all() && pos.cmple(max).all(),
+            Region::Circle { center, radius } => (pos - center).dot(pos - center) <= radius * radius,
+        }
+    }
+
+    /// Smallest rectangle around the region, both corners inclusive
+    pub fn get_bounds(&self) -> (IVec2, IVec2) {
+        match *self {
+            Region::Rect { min, max } => (min, max),
+            Region::Circle { center, radius } => (center - IVec2::splat(radius), center + IVec2::splat(radius)),
+        }
+    }
+}
+
+/// Which cells stop a ray, see Matrix::raycast
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RayFilter {
+    /// Any cell
+    NonEmpty,
+    /// Solids and movable solids, rays pass through liquids and gases
+    Solid,
+}
+
+impl RayFilter {
+    pub fn stops_at(&self, material: Material) -> bool {
+        match self {
+            RayFilter::NonEmpty => material != Material::Empty,
+            RayFilter::Solid => matches!(material.get_type(), MaterialType::Solid | MaterialType::MovableSolid),
+        }
+    }
+}
+
+/// Where a ray hit something
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RayHit {
+    pub pos: IVec2,
+    pub material: Material,
+    /// Side of the hit cell the ray entered through, zero if the ray started inside of it
+    pub normal: IVec2,
+    /// Distance from the origin to where the ray entered the cell
+    pub distance: f32,
+}
+
+impl Matrix {
+    /// Positions of the region that are inside of the world, row by row
+    fn region_positions(&self, region: Region) -> impl Iterator<Item = IVec2> {
+        let (min, max) = region.get_bounds();
+        let min = min.max(IVec2::ZERO);
+        let max = max.min(IVec2::new(self.width as i32 - 1, self.height as i32 - 1));
+        (min.y..=max.y)
+            .flat_map(move |y| (min.x..=max.x).map(move |x| IVec2::new(x, y)))
+            .filter(move |pos| region.contains(*pos))
+    }
+
+    /// Returns views of all cells in the region, row by row
+    pub fn iter_region(&self, region: Region) -> impl Iterator<Item = CellRef<'_>> {
+        self.region_positions(region).filter_map(|pos| self.get_cell(pos))
+    }
+
+    /// Returns views of all cells in the rectangle (both corners inclusive), row by row
+    pub fn iter_rect(&self, min: IVec2, max: IVec2) -> impl Iterator<Item = CellRef<'_>> {
+        self.iter_region(Region::Rect { min, max })
+    }
+
+    /// Returns views of all cells at most radius away from center, row by row
+    pub fn iter_circle(&self, center: IVec2, radius: i32) -> impl Iterator<Item = CellRef<'_>> {
+        self.iter_region(Region::Circle { center, radius })
+    }
+
+    /// Counts the cells of every material in the region, indexed by Material::get_id. Particles aren't counted
+    pub fn count_materials_in(&self, region: Region) -> MaterialCounts {
+        let mut counts: MaterialCounts = vec![0; NUM_MATERIAL_IDS];
+        for pos in self.region_positions(region) {
+            let material = self.get_material(pos);
+            if material != Material::Empty {
+                counts[material.get_id() as usize] += 1;
+            };
+        };
+        counts
+    }
+
+    /// The closest cell of the material to pos which is at most max_distance away, None if there is none
+    pub fn find_nearest(&self, pos: IVec2, material: Material, max_distance: i32) -> Option<IVec2> {
+        let mut nearest: Option<(i32, IVec2)> = None;
+        // Searches square rings of growing size, a ring can't hold anything closer than its radius
+        for ring in 0..=max_distance {
+            if nearest.is_some_and(|(distance, _)| distance <= ring * ring) {
+                break;
+            };
+            for y in -ring..=ring {
+                // Only the edge of the ring, everything inside got searched already
+                let step = if y.abs() == ring { 1 } else { (2 * ring).max(1) as usize };
+                for x in (-ring..=ring).step_by(step) {
+                    let offset = IVec2::new(x, y);
+                    let distance = offset.dot(offset);
+                    if distance > max_distance * max_distance || nearest.is_some_and(|(best, _)| best <= distance) {
+                        continue;
+                    };
+                    let cur_pos = pos + offset;
+                    if self.is_in_bounds(cur_pos) && self.get_material(cur_pos) == material {
+                        nearest = Some((distance, cur_pos));
+                    };
+                };
+            };
+        };
+        nearest.map(|(_, pos)| pos)
+    }
+
+    /// Walks the grid from origin along dir (DDA) and returns the first cell the filter stops at.
+    /// Gives up after max_distance or at the edge of the world
+    pub fn raycast(&self, origin: Vec2, dir: Vec2, max_distance: f32, filter: RayFilter) -> Option<RayHit> {
+        let dir = dir.normalize_or_zero();
+        let mut pos = origin.floor().as_ivec2();
+        let sign = |v: f32| (v > 0.0) as i32 - (v < 0.0) as i32;
+        let step = IVec2::new(sign(dir.x), sign(dir.y));
+        // Distance along the ray to cross one cell on either axis
+        let delta = Vec2::new((1.0 / dir.x).abs(), (1.0 / dir.y).abs());
+        let next_border = |axis_pos: i32, axis_origin: f32, axis_step: i32| {
+            if axis_step > 0 { axis_pos as f32 + 1.0 - axis_origin } else { axis_origin - axis_pos as f32 }
+        };
+        let mut side_distance = Vec2::new(
+            if step.x == 0 { f32::INFINITY } else { next_border(pos.x, origin.x, step.x) * delta.x },
+            if step.y == 0 { f32::INFINITY } else { next_border(pos.y, origin.y, step.y) * delta.y },
+        );
+        let mut normal = IVec2::ZERO;
+        let mut distance = 0.0;
+        loop {
+            if !self.is_in_bounds(pos) {
+                return None;
+            };
+            let material = self.get_material(pos);
+            if filter.stops_at(material) {
+                return Some(RayHit { pos, material, normal, distance });
+            };
+            if dir == Vec2::ZERO {
+                return None;
+            };
+            if side_distance.x < side_distance.y {
+                distance = side_distance.x;
+                side_distance.x += delta.x;
+                pos.x += step.x;
+                normal = IVec2::new(-step.x, 0);
+            } else {
+                distance = side_distance.y;
+                side_distance.y += delta.y;
+                pos.y += step.y;
+                normal = IVec2::new(0, -step.y);
+            };
+            if distance > max_distance {
+                return None;
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix_with(cells: &[(IVec2, Material)]) -> Matrix {
+        let mut matrix = Matrix::new_empty(64, 64);
+        for (pos, material) in cells {
+            matrix.set_cell_material(*pos, *material, false);
+        };
+        matrix
+    }
+
+    #[test]
+    fn raycast_along_the_axes() {
+        let matrix = matrix_with(&[(IVec2::new(20, 10), Material::Rock), (IVec2::new(10, 5), Material::Rock)]);
+        let hit = matrix.raycast(Vec2::new(5.5, 10.5), Vec2::X, 100.0, RayFilter::NonEmpty).unwrap();
+        assert_eq!((hit.pos, hit.material, hit.normal), (IVec2::new(20, 10), Material::Rock, IVec2::new(-1, 0)));
+        assert_eq!(hit.distance, 14.5);
+
+        let hit = matrix.raycast(Vec2::new(10.5, 30.5), Vec2::new(0.0, -3.0), 100.0, RayFilter::NonEmpty).unwrap();
+        assert_eq!((hit.pos, hit.normal), (IVec2::new(10, 5), IVec2::new(0, 1)));
+        assert_eq!(hit.distance, 24.5);
+
+        // Too short, or leaving the world
+        assert_eq!(matrix.raycast(Vec2::new(5.5, 10.5), Vec2::X, 14.0, RayFilter::NonEmpty), None);
+        assert_eq!(matrix.raycast(Vec2::new(5.5, 10.5), -Vec2::X, 100.0, RayFilter::NonEmpty), None);
+    }
+
+    #[test]
+    fn raycast_diagonally() {
+        let matrix = matrix_with(&[(IVec2::new(10, 10), Material::Rock)]);
+        // Crosses x = 10 at y = 9.75 (into 10, 9) and enters 10, 10 through its top a quarter cell later
+        let hit = matrix.raycast(Vec2::new(2.5, 2.25), Vec2::ONE, 100.0, RayFilter::NonEmpty).unwrap();
+        assert_eq!((hit.pos, hit.normal), (IVec2::new(10, 10), IVec2::new(0, -1)));
+        assert!((hit.distance - 7.75 * std::f32::consts::SQRT_2).abs() < 1e-4);
+
+        let hit = matrix.raycast(Vec2::new(17.5, 17.75), -Vec2::ONE, 100.0, RayFilter::NonEmpty).unwrap();
+        assert_eq!((hit.pos, hit.normal), (IVec2::new(10, 10), IVec2::new(0, 1)));
+        assert!((hit.distance - 6.75 * std::f32::consts::SQRT_2).abs() < 1e-4);
+    }
+
+    #[test]
+    fn raycast_filters_and_starts_inside() {
+        let matrix = matrix_with(&[(IVec2::new(8, 4), Material::Water), (IVec2::new(12, 4), Material::Sand)]);
+        let hit = matrix.raycast(Vec2::new(2.5, 4.5), Vec2::X, 100.0, RayFilter::NonEmpty).unwrap();
+        assert_eq!(hit.material, Material::Water);
+        let hit = matrix.raycast(Vec2::new(2.5, 4.5), Vec2::X, 100.0, RayFilter::Solid).unwrap();
+        assert_eq!((hit.pos, hit.material), (IVec2::new(12, 4), Material::Sand));
+
+        let hit = matrix.raycast(Vec2::new(12.5, 4.5), Vec2::Y, 100.0, RayFilter::Solid).unwrap();
+        assert_eq!((hit.pos, hit.normal, hit.distance), (IVec2::new(12, 4), IVec2::ZERO, 0.0));
+    }
+
+    #[test]
+    fn find_nearest_looks_past_the_ring_of_the_first_match() {
+        let center = IVec2::new(30, 30);
+        // 3, 3 is on ring 3 but further away (18) than 0, 4 on ring 4 (16)
+        let matrix = matrix_with(&[(center + IVec2::new(3, 3), Material::Wood), (center + IVec2::new(0, 4), Material::Wood)]);
+        assert_eq!(matrix.find_nearest(center, Material::Wood, 10), Some(center + IVec2::new(0, 4)));
+        assert_eq!(matrix.find_nearest(center, Material::Wood, 3), None);
+        assert_eq!(matrix.find_nearest(center, Material::Sand, 10), None);
+
+        let matrix = matrix_with(&[(center + IVec2::new(-2, 1), Material::Wood), (center + IVec2::new(2, 0), Material::Wood)]);
+        assert_eq!(matrix.find_nearest(center, Material::Wood, 10), Some(center + IVec2::new(2, 0)));
+        let matrix = matrix_with(&[(center, Material::Wood), (center + IVec2::X, Material::Wood)]);
+        assert_eq!(matrix.find_nearest(center, Material::Wood, 0), Some(center));
+    }
+}
@@ -1,8 +1,12 @@
+use glam::Vec2;
 use pixels::{
     check_texture_size,
     wgpu::{self, util::DeviceExt},
     TextureError,
 };
+
+use crate::{GlowLight, OcclusionMask, glow::FIRST_GLOW_LIGHT};
+
 const PAD: usize = 2;
 const PAD2: usize = 3;
 const PAD3: usize = 0;
@@ -69,6 +73,10 @@ pub struct NoiseRenderer {
     locals_buffer: wgpu::Buffer,
     light_buffer: wgpu::Buffer,
     vertex_buffer: wgpu::Buffer,
+    /// One texel per cell of the world, see OcclusionMask
+    occlusion_texture: wgpu::Texture,
+    occlusion_extent: wgpu::Extent3d,
+    occlusion_view: wgpu::TextureView,
 
     pub lights: [LightUniform; 32],
     pub locals: Locals,
@@ -154,6 +162,19 @@ impl NoiseRenderer {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
+        // The occlusion mask has the size of the pixel buffer, the world
+        let occlusion_extent = pixels.context().texture_extent;
+        let occlusion_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("NoiseRenderer occlusion mask"),
+            size: occlusion_extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+        let occlusion_view = occlusion_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
         // Create bind group
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: None,
@@ -194,6 +215,16 @@ impl NoiseRenderer {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
             ],
         });
         let bind_group = create_bind_group(
@@ -203,6 +234,7 @@ impl NoiseRenderer {
             &sampler,
             &locals_buffer,
             &light_buffer,
+            &occlusion_view,
         );
 
         // Create pipeline
@@ -246,12 +278,48 @@ impl NoiseRenderer {
             locals_buffer,
             light_buffer,
             vertex_buffer,
+            occlusion_texture,
+            occlusion_extent,
+            occlusion_view,
 
             lights,
             locals,
         })
     }
 
+    /// Fills the light slots from FIRST_GLOW_LIGHT on with the glowing cells, unused slots get switched off
+    pub fn set_glow_lights(&mut self, glow_lights: &[GlowLight], world_size: Vec2) {
+        for (i, light) in self.lights.iter_mut().enumerate().skip(FIRST_GLOW_LIGHT) {
+            match glow_lights.get(i - FIRST_GLOW_LIGHT) {
+                Some(glow) => {
+                    let pos = glow.pos / world_size;
+                    light.position = [pos.x, pos.y];
+                    light.color = [glow.color.r as f32, glow.color.g as f32, glow.color.b as f32];
+                    // Lights only brighten where their intensity is above 1 (see noise.wgsl)
+                    light.intensity = 1.0 + (glow.heat / 60.0).min(1.5);
+                    light.falloff = 0.03 + (glow.heat / 300.0).min(1.0) * 0.07;
+                },
+                None => light.intensity = 0.0,
+            };
+        };
+    }
+
+    /// Uploads how the cells absorb light, the mask needs the size of the pixel buffer
+    pub fn set_occlusion_mask(&self, queue: &wgpu::Queue, mask: &OcclusionMask) {
+        let size = self.occlusion_extent;
+        assert!(mask.get_width() == size.width as usize && mask.get_height() == size.height as usize);
+        queue.write_texture(
+            self.occlusion_texture.as_image_copy(),
+            mask.as_bytes(),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(size.width * 4),
+                rows_per_image: std::num::NonZeroU32::new(size.height),
+            },
+            size,
+        );
+    }
+
     pub fn get_texture_view(&self) -> &wgpu::TextureView {
         &self.texture_view
     }
@@ -270,6 +338,7 @@ impl NoiseRenderer {
             &self.sampler,
             &self.locals_buffer,
             &self.light_buffer,
+            &self.occlusion_view,
         );
 
         Ok(())
@@ -339,6 +408,7 @@ fn create_bind_group(
     sampler: &wgpu::Sampler,
     locals_buffer: &wgpu::Buffer,
     light_buffer: &wgpu::Buffer,
+    occlusion_view: &wgpu::TextureView,
 ) -> pixels::wgpu::BindGroup {
     device.create_bind_group(&wgpu::BindGroupDescriptor {
         label: None,
@@ -360,6 +430,10 @@ fn create_bind_group(
                 binding: 3,
                 resource: light_buffer.as_entire_binding(),
             },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::TextureView(occlusion_view),
+            },
         ],
     })
 }
\ No newline at end of file
@@ -0,0 +1,445 @@
+use std::{fs::File, io::{self, BufReader, BufWriter, Read, Write}, path::Path};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use glam::{IVec2, Vec2};
+
+use crate::save::{invalid_data, read_material, MAX_WORLD_BYTES};
+use crate::{CollapseMode, Emitter, Material, Matrix};
+
+
+/// Where the windowed app saves recordings to and plays them back from
+pub const DEFAULT_REPLAY_PATH: &str = "replay.frr";
+
+const MAGIC: &[u8; 4] = b"FRRP";
+const VERSION: u16 = 1;
+
+
+/// A single Matrix::draw_brush call together with the brush settings it used
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BrushAction {
+    /// Tick of the matrix when the brush was drawn, see Matrix::get_tick
+    pub tick: u64,
+    pub pos: IVec2,
+    pub material: Material,
+    pub size: u16,
+    pub place_fire: bool,
+    pub place_force: bool,
+    pub force: Vec2,
+    /// Configuration of placed emitter cells
+    pub emitter: Emitter,
+}
+
+pub(crate) fn write_brush_action<W: Write>(writer: &mut W, action: &BrushAction) -> io::Result<()> {
+    writer.write_u64::<LittleEndian>(action.tick)?;
+    writer.write_i32::<LittleEndian>(action.pos.x)?;
+    writer.write_i32::<LittleEndian>(action.pos.y)?;
+    writer.write_u8(action.material.get_id())?;
+    writer.write_u16::<LittleEndian>(action.size)?;
+    writer.write_u8(action.place_fire as u8)?;
+    writer.write_u8(action.place_force as u8)?;
+    writer.write_f32::<LittleEndian>(action.force.x)?;
+    writer.write_f32::<LittleEndian>(action.force.y)?;
+    writer.write_u8(action.emitter.material.get_id())?;
+    writer.write_f32::<LittleEndian>(action.emitter.rate)
+}
+
+pub(crate) fn read_brush_action<R: Read>(reader: &mut R) -> io::Result<BrushAction> {
+    Ok(BrushAction {
+        tick: reader.read_u64::<LittleEndian>()?,
+        pos: IVec2::new(reader.read_i32::<LittleEndian>()?, reader.read_i32::<LittleEndian>()?),
+        material: read_material(reader)?,
+        size: reader.read_u16::<LittleEndian>()?,
+        place_fire: reader.read_u8()? != 0,
+        place_force: reader.read_u8()? != 0,
+        force: Vec2::new(reader.read_f32::<LittleEndian>()?, reader.read_f32::<LittleEndian>()?),
+        emitter: Emitter::new(read_material(reader)?, reader.read_f32::<LittleEndian>()?),
+    })
+}
+
+/// 64 bit FNV-1a, unlike the std hashers it gives the same result with every Rust version and on every platform
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        };
+    }
+
+    fn write_vec2(&mut self, v: Vec2) {
+        self.write(&v.x.to_le_bytes());
+        self.write(&v.y.to_le_bytes());
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReplayEvent {
+    Brush(BrushAction),
+    /// A Matrix::update, all brush actions before it happened during the previous tick
+    Tick,
+}
+
+/// Everything needed to play a session back: the seed of the RNG, the world at the start and every brush action and update after it.
+///
+/// Only brush actions are recorded, changing gravity, boundaries or other settings during a recording makes the replay differ
+pub struct Replay {
+    pub seed: u64,
+    pub structural_integrity: bool,
+    pub collapse_mode: CollapseMode,
+    pub particle_detach_speed: f32,
+    /// The world at the start, written by Matrix::write_world
+    world: Vec<u8>,
+    events: Vec<ReplayEvent>,
+}
+
+impl Replay {
+    pub fn get_events(&self) -> &[ReplayEvent] {
+        &self.events
+    }
+
+    pub(crate) fn push(&mut self, event: ReplayEvent) {
+        self.events.push(event);
+    }
+
+    /// Number of recorded updates
+    pub fn get_num_ticks(&self) -> usize {
+        self.events.iter().filter(|e| **e == ReplayEvent::Tick).count()
+    }
+
+    /// Builds the world at the start of the replay and seeds the RNG
+    pub fn start(&self) -> io::Result<Matrix> {
+        let mut matrix = Matrix::read_world(&mut self.world.as_slice())?;
+        matrix.structural_integrity = self.structural_integrity;
+        matrix.collapse_mode = self.collapse_mode;
+        matrix.particle_detach_speed = self.particle_detach_speed;
+        matrix.seed_rng(self.seed);
+        Ok(matrix)
+    }
+
+    /// Replaces the world of the matrix with the start of the replay. Brush and view settings are kept
+    pub fn restart(&self, matrix: &mut Matrix) -> io::Result<()> {
+        matrix.replace_world(self.start()?)?;
+        matrix.structural_integrity = self.structural_integrity;
+        matrix.collapse_mode = self.collapse_mode;
+        matrix.particle_detach_speed = self.particle_detach_speed;
+        Ok(())
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_u16::<LittleEndian>(VERSION)?;
+        writer.write_u64::<LittleEndian>(self.seed)?;
+        writer.write_u8(self.structural_integrity as u8)?;
+        writer.write_u8(match self.collapse_mode {
+            CollapseMode::Debris => 0,
+            CollapseMode::Rigid => 1,
+        })?;
+        writer.write_f32::<LittleEndian>(self.particle_detach_speed)?;
+        writer.write_u32::<LittleEndian>(self.world.len() as u32)?;
+        writer.write_all(&self.world)?;
+
+        writer.write_u32::<LittleEndian>(self.events.len() as u32)?;
+        for event in self.events.iter() {
+            match event {
+                ReplayEvent::Tick => writer.write_u8(0)?,
+                ReplayEvent::Brush(action) => {
+                    writer.write_u8(1)?;
+                    write_brush_action(writer, action)?;
+                },
+            };
+        };
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Replay> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a replay file"));
+        };
+        if reader.read_u16::<LittleEndian>()? != VERSION {
+            return Err(invalid_data("unsupported replay file version"));
+        };
+        let seed = reader.read_u64::<LittleEndian>()?;
+        let structural_integrity = reader.read_u8()? != 0;
+        let collapse_mode = match reader.read_u8()? {
+            0 => CollapseMode::Debris,
+            1 => CollapseMode::Rigid,
+            _ => return Err(invalid_data("unknown collapse mode")),
+        };
+        let particle_detach_speed = reader.read_f32::<LittleEndian>()?;
+        let world_len = reader.read_u32::<LittleEndian>()? as usize;
+        if world_len > MAX_WORLD_BYTES {
+            return Err(invalid_data("world is too big"));
+        };
+        // Only grows with the data that actually arrives, a wrong length can't allocate more than the file holds
+        let mut world = vec![];
+        reader.by_ref().take(world_len as u64).read_to_end(&mut world)?;
+        if world.len() != world_len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+
+        let num_events = reader.read_u32::<LittleEndian>()?;
+        let mut events = vec![];
+        for _ in 0..num_events {
+            let event = match reader.read_u8()? {
+                0 => ReplayEvent::Tick,
+                1 => ReplayEvent::Brush(read_brush_action(reader)?),
+                _ => return Err(invalid_data("unknown replay event")),
+            };
+            events.push(event);
+        };
+        Ok(Replay { seed, structural_integrity, collapse_mode, particle_detach_speed, world, events })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Replay> {
+        Replay::read(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+/// Plays a replay back one tick at a time, so the windowed app can draw in between
+pub struct ReplayPlayer {
+    replay: Replay,
+    next_event: usize,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Self {
+        ReplayPlayer { replay, next_event: 0 }
+    }
+
+    /// Replaces the world of the matrix with the start of the replay, see Replay::restart
+    pub fn start(&mut self, matrix: &mut Matrix) -> io::Result<()> {
+        self.replay.restart(matrix)?;
+        self.next_event = 0;
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next_event >= self.replay.events.len()
+    }
+
+    /// Applies the brush actions of the next tick and updates the matrix. Returns false once the replay is over
+    pub fn step(&mut self, matrix: &mut Matrix) -> bool {
+        while let Some(event) = self.replay.events.get(self.next_event) {
+            self.next_event += 1;
+            match event {
+                ReplayEvent::Brush(action) => matrix.apply_brush_action(action),
+                ReplayEvent::Tick => {
+                    matrix.update();
+                    return true;
+                },
+            };
+        };
+        false
+    }
+
+    /// Plays the rest of the replay at once
+    pub fn finish(&mut self, matrix: &mut Matrix) {
+        while self.step(matrix) {};
+    }
+}
+
+impl Matrix {
+    /// Starts recording every brush action and update into a replay.
+    ///
+    /// The world gets reloaded from its saved form first and the RNG seeded, so playing the replay back starts from exactly the same state.
+    /// Particles in flight, rigid clusters and the force field aren't part of a saved world and get dropped
+    pub fn start_recording(&mut self, seed: u64) -> io::Result<()> {
+        self.recording = Some(self.restart_from_snapshot(seed)?);
+        Ok(())
+    }
+
+    /// Reloads the world from its saved form and seeds the RNG. Returns a replay without events that starts from the same state
+    pub(crate) fn restart_from_snapshot(&mut self, seed: u64) -> io::Result<Replay> {
+        let mut world = vec![];
+        self.write_world(&mut world)?;
+        let structural_integrity = self.structural_integrity;
+        let collapse_mode = self.collapse_mode;
+        let start = Matrix::read_world(&mut world.as_slice())?;
+        self.replace_world(start)?;
+        self.structural_integrity = structural_integrity;
+        self.collapse_mode = collapse_mode;
+        self.seed_rng(seed);
+        Ok(Replay {
+            seed,
+            structural_integrity,
+            collapse_mode,
+            particle_detach_speed: self.particle_detach_speed,
+            world,
+            events: vec![],
+        })
+    }
+
+    /// Ends the recording and returns it, None if nothing was being recorded. Loading another world also ends the recording
+    pub fn stop_recording(&mut self) -> Option<Replay> {
+        self.recording.take()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// What drawing the brush at pos with the current brush settings would do
+    pub fn get_brush_action(&self, pos: IVec2, material: Material) -> BrushAction {
+        BrushAction {
+            tick: self.get_tick(),
+            pos,
+            material,
+            size: self.brush.size,
+            place_fire: self.brush.place_fire,
+            place_force: self.brush.place_force,
+            force: self.brush.force,
+            emitter: Emitter::new(self.brush.emitter.material, self.brush.emitter.rate),
+        }
+    }
+
+    pub(crate) fn record_brush(&mut self, pos: IVec2, material: Material) {
+        let action = self.get_brush_action(pos, material);
+        if let Some(recording) = &mut self.recording {
+            recording.push(ReplayEvent::Brush(action));
+        };
+    }
+
+    /// Draws the brush the way the action did, the brush settings are restored afterwards
+    pub fn apply_brush_action(&mut self, action: &BrushAction) {
+        let size = self.brush.size;
+        let place_fire = self.brush.place_fire;
+        let place_force = self.brush.place_force;
+        let force = self.brush.force;
+        let emitter = self.brush.emitter;
+        self.brush.size = action.size;
+        self.brush.place_fire = action.place_fire;
+        self.brush.place_force = action.place_force;
+        self.brush.force = action.force;
+        self.brush.emitter = action.emitter;
+        self.draw_brush_now(action.pos, action.material);
+        self.brush.size = size;
+        self.brush.place_fire = place_fire;
+        self.brush.place_force = place_force;
+        self.brush.force = force;
+        self.brush.emitter = emitter;
+    }
+
+    /// Hash over all cells and particles, two matrices with the same hash are (almost certainly) in the same state
+    pub fn get_state_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+        hasher.write(&self.get_tick().to_le_bytes());
+        let cells = self.get_cell_storage();
+        for idx in 0..cells.len() {
+            hasher.write(&[cells.material[idx].get_id()]);
+            if !cells.is_occupied(idx) {
+                continue;
+            };
+            hasher.write(&cells.color[idx]);
+            hasher.write(&cells.hp[idx].to_le_bytes());
+            hasher.write(&cells.flags[idx].to_le_bytes());
+            hasher.write_vec2(cells.velocity[idx]);
+            hasher.write_vec2(cells.subpixel[idx]);
+            hasher.write(&cells.heat[idx].to_le_bytes());
+        };
+        for particle in self.get_particles() {
+            hasher.write_vec2(particle.pos);
+            hasher.write_vec2(particle.velocity);
+            hasher.write(&[particle.cell.material.get_id()]);
+        };
+        hasher.0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written_replay() -> Vec<u8> {
+        let mut matrix = Matrix::new_empty(64, 64);
+        matrix.start_recording(1).unwrap();
+        matrix.update();
+        let mut written = vec![];
+        matrix.stop_recording().unwrap().write(&mut written).unwrap();
+        written
+    }
+
+    #[test]
+    fn replaying_reproduces_the_recorded_state() {
+        let mut matrix = Matrix::new_empty(64, 64);
+        for x in 10..50 {
+            matrix.set_cell_material(IVec2::new(x, 60), Material::Wood, false);
+        };
+        matrix.start_recording(7).unwrap();
+        let start_hash = matrix.get_state_hash();
+        matrix.brush.size = 3;
+        for tick in 0..60 {
+            match tick % 20 {
+                0 => matrix.draw_brush(IVec2::new(20 + tick / 4, 10), Material::Sand),
+                5 => matrix.draw_brush(IVec2::new(40, 20), Material::Water),
+                10 => {
+                    matrix.brush.place_fire = true;
+                    matrix.draw_brush(IVec2::new(12, 60), Material::Empty);
+                    matrix.brush.place_fire = false;
+                },
+                _ => {},
+            };
+            matrix.update();
+        };
+        let recorded_hash = matrix.get_state_hash();
+        assert_ne!(recorded_hash, start_hash);
+        let mut written = vec![];
+        matrix.stop_recording().unwrap().write(&mut written).unwrap();
+
+        let replay = Replay::read(&mut written.as_slice()).unwrap();
+        assert_eq!(replay.get_num_ticks(), 60);
+        let mut replayed = replay.start().unwrap();
+        assert_eq!(replayed.get_state_hash(), start_hash);
+        let mut player = ReplayPlayer::new(replay);
+        player.finish(&mut replayed);
+        assert!(player.is_finished());
+        assert_eq!(replayed.get_tick(), matrix.get_tick());
+        assert_eq!(replayed.get_state_hash(), recorded_hash);
+
+        // Playing it again into a matrix that went elsewhere in the meantime ends in the same state
+        replayed.draw_brush(IVec2::new(5, 5), Material::Rock);
+        replayed.update();
+        player.start(&mut replayed).unwrap();
+        player.finish(&mut replayed);
+        assert_eq!(replayed.get_state_hash(), recorded_hash);
+    }
+
+    /// Offset of the world length, right after magic, version, seed and the settings
+    const WORLD_LEN_OFFSET: usize = 4 + 2 + 8 + 1 + 1 + 4;
+
+    #[test]
+    fn fnv1a_matches_the_reference() {
+        let mut hasher = Fnv1a::new();
+        assert_eq!(hasher.0, 0xcbf29ce484222325);
+        hasher.write(b"a");
+        assert_eq!(hasher.0, 0xaf63dc4c8601ec8c);
+        let mut hasher = Fnv1a::new();
+        hasher.write(b"foobar");
+        assert_eq!(hasher.0, 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn rejects_world_lengths_before_allocating() {
+        let mut written = written_replay();
+        assert!(Replay::read(&mut written.as_slice()).is_ok());
+
+        written[WORLD_LEN_OFFSET..WORLD_LEN_OFFSET + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Replay::read(&mut written.as_slice()).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let too_long = (MAX_WORLD_BYTES as u32).to_le_bytes();
+        written[WORLD_LEN_OFFSET..WORLD_LEN_OFFSET + 4].copy_from_slice(&too_long);
+        assert_eq!(Replay::read(&mut written.as_slice()).err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
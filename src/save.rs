@@ -0,0 +1,212 @@
+use std::{fs::File, io::{self, BufReader, BufWriter, Read, Write}, path::Path};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use glam::{IVec2, Vec2};
+
+use crate::{Boundaries, BoundaryMode, Emitter, Material, Matrix};
+
+
+/// Where the world gets saved to / loaded from when no other path is given
+pub const DEFAULT_WORLD_PATH: &str = "world.sav";
+
+/// Largest width and height Matrix::read_world accepts, so broken or foreign files can't allocate without limit
+pub const MAX_WORLD_SIZE: usize = 2048;
+
+const MAGIC: &[u8; 4] = b"FRWD";
+const VERSION: u16 = 1;
+
+/// Bytes written by Matrix::write_world for the header and settings, each cell and each emitter
+const HEADER_BYTES: usize = 4 + 2 + 4 + 4 + 4 + 8 + 4 + 4;
+const CELL_BYTES: usize = 4 + 4 + 1 + 8 + 1 + 4 + 4;
+const EMITTER_BYTES: usize = 4 + 4 + 1 + 4;
+/// Largest size a world of at most MAX_WORLD_SIZE cells per side can have when written by Matrix::write_world
+pub(crate) const MAX_WORLD_BYTES: usize = HEADER_BYTES + MAX_WORLD_SIZE * MAX_WORLD_SIZE * (CELL_BYTES + EMITTER_BYTES);
+
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_boundary_mode<W: Write>(writer: &mut W, mode: BoundaryMode) -> io::Result<()> {
+    writer.write_u8(match mode {
+        BoundaryMode::Wall => 0,
+        BoundaryMode::Wrap => 1,
+        BoundaryMode::Void => 2,
+    })
+}
+
+fn read_boundary_mode<R: Read>(reader: &mut R) -> io::Result<BoundaryMode> {
+    match reader.read_u8()? {
+        0 => Ok(BoundaryMode::Wall),
+        1 => Ok(BoundaryMode::Wrap),
+        2 => Ok(BoundaryMode::Void),
+        _ => Err(invalid_data("unknown boundary mode")),
+    }
+}
+
+pub(crate) fn read_material<R: Read>(reader: &mut R) -> io::Result<Material> {
+    Material::from_id(reader.read_u8()?).ok_or_else(|| invalid_data("unknown material"))
+}
+
+impl Matrix {
+    /// Writes all cells, emitters and world settings (size, boundaries, gravity)
+    pub fn write_world<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_u16::<LittleEndian>(VERSION)?;
+        writer.write_u32::<LittleEndian>(self.width as u32)?;
+        writer.write_u32::<LittleEndian>(self.height as u32)?;
+        let boundaries = self.get_boundaries();
+        for mode in [boundaries.left, boundaries.right, boundaries.top, boundaries.bottom] {
+            write_boundary_mode(writer, mode)?;
+        };
+        let gravity = self.get_gravity();
+        writer.write_f32::<LittleEndian>(gravity.x)?;
+        writer.write_f32::<LittleEndian>(gravity.y)?;
+
+        writer.write_u32::<LittleEndian>(self.iter_cells().count() as u32)?;
+        for cell in self.iter_cells() {
+            writer.write_i32::<LittleEndian>(cell.pos.x)?;
+            writer.write_i32::<LittleEndian>(cell.pos.y)?;
+            writer.write_u8(cell.material.get_id())?;
+            writer.write_u64::<LittleEndian>(cell.hp as u64)?;
+            writer.write_u8(cell.is_on_fire as u8)?;
+            writer.write_f32::<LittleEndian>(cell.velocity.x)?;
+            writer.write_f32::<LittleEndian>(cell.velocity.y)?;
+        };
+
+        writer.write_u32::<LittleEndian>(self.iter_emitters().count() as u32)?;
+        for (pos, emitter) in self.iter_emitters() {
+            writer.write_i32::<LittleEndian>(pos.x)?;
+            writer.write_i32::<LittleEndian>(pos.y)?;
+            writer.write_u8(emitter.material.get_id())?;
+            writer.write_f32::<LittleEndian>(emitter.rate)?;
+        };
+        Ok(())
+    }
+
+    /// Reads a world written by Matrix::write_world into a new matrix
+    pub fn read_world<R: Read>(reader: &mut R) -> io::Result<Matrix> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a world file"));
+        };
+        if reader.read_u16::<LittleEndian>()? != VERSION {
+            return Err(invalid_data("unsupported world file version"));
+        };
+        let width = reader.read_u32::<LittleEndian>()? as usize;
+        let height = reader.read_u32::<LittleEndian>()? as usize;
+        if width == 0 || height == 0 {
+            return Err(invalid_data("world has no size"));
+        };
+        if width > MAX_WORLD_SIZE || height > MAX_WORLD_SIZE {
+            return Err(invalid_data("world is too big"));
+        };
+        let boundaries = Boundaries {
+            left: read_boundary_mode(reader)?,
+            right: read_boundary_mode(reader)?,
+            top: read_boundary_mode(reader)?,
+            bottom: read_boundary_mode(reader)?,
+        };
+        let mut matrix = Matrix::new(width, height, boundaries);
+        let gravity = Vec2::new(reader.read_f32::<LittleEndian>()?, reader.read_f32::<LittleEndian>()?);
+        matrix.set_gravity(gravity);
+
+        let num_cells = reader.read_u32::<LittleEndian>()?;
+        for _ in 0..num_cells {
+            let pos = IVec2::new(reader.read_i32::<LittleEndian>()?, reader.read_i32::<LittleEndian>()?);
+            let material = read_material(reader)?;
+            let hp = reader.read_u64::<LittleEndian>()?;
+            let is_on_fire = reader.read_u8()? != 0;
+            let velocity = Vec2::new(reader.read_f32::<LittleEndian>()?, reader.read_f32::<LittleEndian>()?);
+            if !matrix.is_in_bounds(pos) {
+                return Err(invalid_data("cell outside of the world"));
+            };
+            matrix.set_cell_material(pos, material, false);
+            if let Some(mut cell) = matrix.get_cell_mut(pos) {
+                cell.hp = hp.min(u16::MAX as u64) as u16;
+                cell.is_on_fire = is_on_fire;
+                cell.velocity = velocity;
+            };
+        };
+
+        let num_emitters = reader.read_u32::<LittleEndian>()?;
+        for _ in 0..num_emitters {
+            let pos = IVec2::new(reader.read_i32::<LittleEndian>()?, reader.read_i32::<LittleEndian>()?);
+            let material = read_material(reader)?;
+            let rate = reader.read_f32::<LittleEndian>()?;
+            if !matrix.is_in_bounds(pos) {
+                return Err(invalid_data("emitter outside of the world"));
+            };
+            matrix.place_emitter(pos, Emitter::new(material, rate));
+        };
+        Ok(matrix)
+    }
+
+    /// Saves the world to a file
+    pub fn save_world<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_world(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Replaces the world with the one saved in the file. Brush and simulation settings are kept.
+    ///
+    /// Fails if the saved world has another size, the pixel buffer is made for the current one
+    pub fn load_world<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let loaded = Matrix::read_world(&mut BufReader::new(File::open(path)?))?;
+        self.replace_world(loaded)
+    }
+
+    /// Swaps in another world of the same size, keeping brush and simulation settings
+    pub(crate) fn replace_world(&mut self, mut loaded: Matrix) -> io::Result<()> {
+        if loaded.width != self.width || loaded.height != self.height {
+            return Err(invalid_data(&format!("world is {}x{} instead of {}x{}", loaded.width, loaded.height, self.width, self.height)));
+        };
+        loaded.brush = std::mem::take(&mut self.brush);
+        loaded.profiler = std::mem::take(&mut self.profiler);
+        loaded.scripts = std::mem::take(&mut self.scripts);
+        loaded.behaviors = std::mem::take(&mut self.behaviors);
+        loaded.debug_overlay = self.debug_overlay;
+        loaded.shading = self.shading;
+        loaded.check_consistency = self.check_consistency;
+        loaded.wait_time_after_frame = self.wait_time_after_frame;
+        loaded.particle_detach_speed = self.particle_detach_speed;
+        loaded.lockstep_outbox = self.lockstep_outbox.take();
+        *self = loaded;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved_world(width: usize, height: usize) -> Vec<u8> {
+        let mut matrix = Matrix::new_empty(width, height);
+        matrix.set_cell_material(IVec2::new(3, 4), Material::Sand, false);
+        let mut world = vec![];
+        matrix.write_world(&mut world).unwrap();
+        world
+    }
+
+    #[test]
+    fn rejects_huge_worlds_before_allocating() {
+        let mut world = saved_world(64, 64);
+        world[6..10].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Matrix::read_world(&mut world.as_slice()).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn only_replaces_worlds_of_the_same_size() {
+        let mut matrix = Matrix::new_empty(64, 64);
+        let other = Matrix::read_world(&mut saved_world(96, 64).as_slice()).unwrap();
+        assert!(matrix.replace_world(other).is_err());
+        assert_eq!(matrix.width, 64);
+
+        let same = Matrix::read_world(&mut saved_world(64, 64).as_slice()).unwrap();
+        matrix.replace_world(same).unwrap();
+        assert!(matrix.get_cell(IVec2::new(3, 4)).is_some_and(|c| c.material == Material::Sand));
+    }
+}
@@ -0,0 +1,338 @@
+use std::{path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime}};
+
+use glam::IVec2;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+
+use crate::{Cell, Emitter, Material, Matrix, SimEvent};
+
+
+/// Material scripts are looked up in here as <material name>.rhai, e.g. black_smoke.rhai
+pub const DEFAULT_MATERIAL_SCRIPT_DIR: &str = "data/scripts/materials";
+/// Scenario the windowed app loads with F10
+pub const DEFAULT_SCENARIO_PATH: &str = "data/scripts/scenario.rhai";
+
+/// How often Scripts::reload_changed looks at the files
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits of a single script call, a script running into them fails instead of hanging the simulation
+const MAX_OPERATIONS: u64 = 100_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_FUNCTION_EXPR_DEPTH: usize = 32;
+
+/// Offsets of the neighbours handed to on_step, row by row
+const NEIGHBOUR_OFFSETS: [IVec2; 8] = [
+    IVec2::new(-1, -1), IVec2::new(0, -1), IVec2::new(1, -1),
+    IVec2::new(-1, 0), IVec2::new(1, 0),
+    IVec2::new(-1, 1), IVec2::new(0, 1), IVec2::new(1, 1),
+];
+
+
+/// Changes scripts asked for, applied to the matrix once the script returned
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ScriptCommand {
+    Place { pos: IVec2, material: Material },
+    Ignite { pos: IVec2 },
+    Explode { pos: IVec2, radius: i32 },
+}
+
+/// A compiled script and the file it came from
+struct ScriptFile {
+    path: PathBuf,
+    /// Modification time when the file was loaded, None if it didn't exist
+    modified: Option<SystemTime>,
+    /// None if the file doesn't exist, didn't compile or failed while running
+    ast: Option<AST>,
+}
+
+impl ScriptFile {
+    fn load(engine: &Engine, path: &Path) -> Self {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let ast = modified.and_then(|_| match engine.compile_file(path.to_path_buf()) {
+            Ok(ast) => Some(ast),
+            Err(err) => {
+                log::error!("Could not compile {}: {}", path.display(), err);
+                None
+            },
+        });
+        ScriptFile { path: path.to_path_buf(), modified, ast }
+    }
+
+    fn has_changed(&self) -> bool {
+        std::fs::metadata(&self.path).and_then(|m| m.modified()).ok() != self.modified
+    }
+
+    fn has_function(&self, name: &str, num_params: usize) -> bool {
+        self.ast.as_ref().is_some_and(|ast| ast.iter_functions().any(|f| f.name == name && f.params.len() == num_params))
+    }
+
+    /// Calls a function of the script. A script that fails gets disabled until its file changes
+    fn call(&mut self, engine: &Engine, this: Option<&mut Dynamic>, name: &str, args: impl rhai::FuncArgs) -> Option<Dynamic> {
+        let ast = self.ast.as_ref()?;
+        let mut options = CallFnOptions::new().eval_ast(false);
+        if let Some(this) = this {
+            options = options.bind_this_ptr(this);
+        };
+        match engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), ast, name, args) {
+            Ok(result) => Some(result),
+            Err(err) => {
+                log::error!("{} failed in {}: {}", name, self.path.display(), err);
+                self.ast = None;
+                None
+            },
+        }
+    }
+}
+
+/// Rhai scripts that extend the simulation.
+///
+/// A material script defines `on_step(cell, neighbours)`, which runs for every stepped cell of its material before the
+/// built-in behaviour. `cell` is a map (x, y, material, hp, on_fire, velocity_x, velocity_y), `neighbours` holds the
+/// material names of the 8 surrounding cells row by row. Returning true skips the built-in behaviour of the material.
+///
+/// A scenario script can define `on_start()`, `on_tick(tick)` and `on_event(event)`, the latter gets called with every
+/// SimEvent of an update as a map (kind, x, y, material). The functions can keep state in `this`, which stays around
+/// between calls and reloads.
+///
+/// Both can call `place(x, y, material)`, `remove(x, y)`, `ignite(x, y)` and `explode(x, y, radius)`.
+/// Changed files are compiled again by Scripts::reload_changed. Scripts aren't part of replays
+pub struct Scripts {
+    engine: Engine,
+    commands: Arc<Mutex<Vec<ScriptCommand>>>,
+    /// Indexed by Material::get_id, empty until Scripts::load_materials
+    materials: Vec<ScriptFile>,
+    scenario: Option<ScriptFile>,
+    scenario_state: Dynamic,
+    last_reload_check: Option<Instant>,
+}
+
+impl Scripts {
+    pub fn new() -> Self {
+        let commands = Arc::new(Mutex::new(vec![]));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        engine.set_max_expr_depths(MAX_EXPR_DEPTH, MAX_FUNCTION_EXPR_DEPTH);
+        let place_commands = commands.clone();
+        engine.register_fn("place", move |x: i64, y: i64, material: &str| -> Result<(), Box<EvalAltResult>> {
+            let material = Material::from_name(material).ok_or_else(|| format!("unknown material {}", material))?;
+            place_commands.lock().unwrap().push(ScriptCommand::Place { pos: IVec2::new(x as i32, y as i32), material });
+            Ok(())
+        });
+        let remove_commands = commands.clone();
+        engine.register_fn("remove", move |x: i64, y: i64| {
+            remove_commands.lock().unwrap().push(ScriptCommand::Place { pos: IVec2::new(x as i32, y as i32), material: Material::Empty });
+        });
+        let ignite_commands = commands.clone();
+        engine.register_fn("ignite", move |x: i64, y: i64| {
+            ignite_commands.lock().unwrap().push(ScriptCommand::Ignite { pos: IVec2::new(x as i32, y as i32) });
+        });
+        let explode_commands = commands.clone();
+        engine.register_fn("explode", move |x: i64, y: i64, radius: i64| {
+            explode_commands.lock().unwrap().push(ScriptCommand::Explode { pos: IVec2::new(x as i32, y as i32), radius: radius as i32 });
+        });
+        Scripts {
+            engine,
+            commands,
+            materials: vec![],
+            scenario: None,
+            scenario_state: Dynamic::from_map(Map::new()),
+            last_reload_check: None,
+        }
+    }
+
+    /// Loads the material scripts found in dir, returns how many there are
+    pub fn load_materials<P: AsRef<Path>>(&mut self, dir: P) -> usize {
+        self.materials = Material::all().into_iter()
+            .map(|m| ScriptFile::load(&self.engine, &dir.as_ref().join(format!("{}.rhai", m.get_name()))))
+            .collect();
+        self.materials.iter().filter(|s| s.ast.is_some()).count()
+    }
+
+    pub fn has_material_script(&self, material: Material) -> bool {
+        self.materials.get(material.get_id() as usize).is_some_and(|s| s.ast.is_some())
+    }
+
+    /// Compiles the scenario, Matrix::load_scenario also runs its on_start
+    fn load_scenario(&mut self, path: &Path) -> Result<(), String> {
+        let scenario = ScriptFile::load(&self.engine, path);
+        if scenario.ast.is_none() {
+            return Err(format!("could not load {}", path.display()));
+        };
+        self.scenario = Some(scenario);
+        self.scenario_state = Dynamic::from_map(Map::new());
+        Ok(())
+    }
+
+    pub fn has_scenario(&self) -> bool {
+        self.scenario.as_ref().is_some_and(|s| s.ast.is_some())
+    }
+
+    /// Compiles all scripts again whose file changed, at most once every RELOAD_INTERVAL. Returns the reloaded files
+    pub fn reload_changed(&mut self) -> Vec<PathBuf> {
+        if self.last_reload_check.is_some_and(|t| t.elapsed() < RELOAD_INTERVAL) {
+            return vec![];
+        };
+        self.last_reload_check = Some(Instant::now());
+        let mut reloaded = vec![];
+        for script in self.materials.iter_mut().chain(self.scenario.iter_mut()) {
+            if script.has_changed() {
+                *script = ScriptFile::load(&self.engine, &script.path);
+                reloaded.push(script.path.clone());
+            };
+        };
+        reloaded
+    }
+
+    fn call_scenario(&mut self, name: &str, num_params: usize, args: impl rhai::FuncArgs) {
+        if let Some(scenario) = &mut self.scenario {
+            if scenario.has_function(name, num_params) {
+                scenario.call(&self.engine, Some(&mut self.scenario_state), name, args);
+            };
+        };
+    }
+
+    fn take_commands(&self) -> Vec<ScriptCommand> {
+        std::mem::take(&mut self.commands.lock().unwrap())
+    }
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The fields of a cell handed to on_step
+fn cell_to_map(cell: &Cell) -> Map {
+    let mut map = Map::new();
+    map.insert("x".into(), (cell.pos.x as i64).into());
+    map.insert("y".into(), (cell.pos.y as i64).into());
+    map.insert("material".into(), cell.material.get_name().into());
+    map.insert("hp".into(), (cell.hp as i64).into());
+    map.insert("on_fire".into(), cell.is_on_fire.into());
+    map.insert("velocity_x".into(), (cell.velocity.x as f64).into());
+    map.insert("velocity_y".into(), (cell.velocity.y as f64).into());
+    map
+}
+
+/// The fields of an event handed to on_event
+fn event_to_map(event: &SimEvent) -> Map {
+    let mut map = Map::new();
+    let pos = event.pos();
+    let material = match event {
+        SimEvent::CellCreated { material, .. } | SimEvent::CellDestroyed { material, .. } | SimEvent::Ignited { material, .. }
+            | SimEvent::Extinguished { material, .. } | SimEvent::MovedAcrossChunk { material, .. } | SimEvent::Impact { material, .. } => *material,
+        SimEvent::Reacted { to, .. } => *to,
+    };
+    map.insert("kind".into(), format!("{:?}", event.kind()).into());
+    map.insert("x".into(), (pos.x as i64).into());
+    map.insert("y".into(), (pos.y as i64).into());
+    map.insert("material".into(), material.get_name().into());
+    map
+}
+
+impl Matrix {
+    /// Runs the on_step script of the cells material. Returns true if the script replaces the built-in behaviour
+    pub(crate) fn run_material_script(&mut self, pos: IVec2) -> bool {
+        let (map, material) = match self.get_cell(pos) {
+            Some(cell) => (cell_to_map(&cell), cell.material),
+            None => return false,
+        };
+        let neighbours: Array = NEIGHBOUR_OFFSETS.iter().map(|o| self.get_material(pos + *o).get_name().into()).collect();
+
+        let scripts = &mut self.scripts;
+        let result = scripts.materials[material.get_id() as usize].call(&scripts.engine, None, "on_step", (map, neighbours));
+        self.apply_script_commands();
+        result.and_then(|r| r.as_bool().ok()).unwrap_or(false)
+    }
+
+    /// Uses the scenario at path from now on and runs its on_start
+    pub fn load_scenario<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        self.scripts.load_scenario(path.as_ref())?;
+        self.scripts.call_scenario("on_start", 0, ());
+        self.apply_script_commands();
+        Ok(())
+    }
+
+    /// Calls the on_tick and on_event functions of the scenario, after everything else of the update
+    pub(crate) fn run_scenario(&mut self) {
+        if !self.scripts.has_scenario() {
+            return;
+        };
+        self.scripts.call_scenario("on_tick", 1, (self.get_tick() as i64,));
+        for event in self.get_events().to_vec() {
+            self.scripts.call_scenario("on_event", 1, (event_to_map(&event),));
+        };
+        self.apply_script_commands();
+    }
+
+    fn apply_script_commands(&mut self) {
+        for command in self.scripts.take_commands() {
+            match command {
+                ScriptCommand::Place { pos, material } => {
+                    if !self.is_in_bounds(pos) || self.get_emitter(pos).is_some() {
+                        continue;
+                    };
+                    // The same events the step pushes, so the consistency check knows about the change
+                    let replaced = self.get_material(pos);
+                    if material == Material::Emitter {
+                        self.place_emitter(pos, Emitter::default());
+                    } else {
+                        self.set_cell_material(pos, material, false);
+                    };
+                    if replaced != Material::Empty {
+                        self.push_event(SimEvent::CellDestroyed { pos, material: replaced });
+                    };
+                    if material != Material::Empty {
+                        self.push_event(SimEvent::CellCreated { pos, material });
+                    };
+                },
+                ScriptCommand::Ignite { pos } => {
+                    if self.ignite(pos) {
+                        self.push_event(SimEvent::Ignited { pos, material: self.get_material(pos) });
+                    };
+                },
+                ScriptCommand::Explode { pos, radius } => self.explode(pos, radius),
+            };
+        };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endless_scripts_fail_instead_of_hanging() {
+        let path = std::env::temp_dir().join(format!("falling_rust_endless_{}.rhai", std::process::id()));
+        std::fs::write(&path, "fn on_tick(tick) { loop { tick += 1; } }").unwrap();
+        let mut matrix = Matrix::new_empty(64, 64);
+        matrix.load_scenario(&path).unwrap();
+        assert!(matrix.scripts.has_scenario());
+        matrix.update();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!matrix.scripts.has_scenario());
+    }
+
+    #[test]
+    fn script_changes_pass_the_consistency_check() {
+        let path = std::env::temp_dir().join(format!("falling_rust_placing_{}.rhai", std::process::id()));
+        std::fs::write(&path, "fn on_tick(tick) { place(5, 5, \"sand\"); place(20, 20, \"water\"); remove(30, 30); explode(40, 40, 3); }").unwrap();
+        let mut matrix = Matrix::new_empty(64, 64);
+        matrix.check_consistency = true;
+        matrix.set_cell_material(IVec2::new(30, 30), Material::Rock, false);
+        matrix.set_cell_material(IVec2::new(20, 20), Material::Dirt, false);
+        matrix.set_cell_material(IVec2::new(40, 40), Material::Wood, false);
+        matrix.load_scenario(&path).unwrap();
+        matrix.update();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matrix.get_consistency_violations().is_empty(), "{:?}", matrix.get_consistency_violations());
+        let events = matrix.get_events();
+        assert!(events.contains(&SimEvent::CellCreated { pos: IVec2::new(5, 5), material: Material::Sand }));
+        assert!(events.contains(&SimEvent::CellDestroyed { pos: IVec2::new(30, 30), material: Material::Rock }));
+        assert!(events.contains(&SimEvent::Ignited { pos: IVec2::new(40, 40), material: Material::Wood }));
+    }
+}
@@ -0,0 +1,90 @@
+use glam::{IVec2, Vec2};
+
+use crate::{Matrix, Material, MaterialType};
+
+
+/// How far around a cell is looked for neighbours when calculating how buried it is
+const OCCLUSION_RADIUS: i32 = 2;
+/// How many cells above a cell are looked at, cells deeper than this are fully buried
+const OCCLUSION_DEPTH: i32 = 16;
+
+/// How a material gets shaded when drawn, see Material::get_shading
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Shading {
+    /// How much a completely buried cell gets darkened (0 - 1)
+    pub occlusion: f32,
+    /// How much a cell with nothing above it gets brightened
+    pub highlight: f32,
+    /// Maximum random brightness change of a single cell
+    pub jitter: f32,
+}
+
+impl Shading {
+    pub const NONE: Shading = Shading { occlusion: 0.0, highlight: 0.0, jitter: 0.0 };
+
+    pub const fn new(occlusion: f32, highlight: f32, jitter: f32) -> Self {
+        Shading { occlusion, highlight, jitter }
+    }
+}
+
+/// Returns a stable pseudo random value in -1.0..1.0 for the position
+fn position_noise(pos: IVec2) -> f32 {
+    let mut h = (pos.x as u32).wrapping_mul(0x8da6b343) ^ (pos.y as u32).wrapping_mul(0xd8163841);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1e995);
+    h ^= h >> 15;
+    (h & 0xffff) as f32 / 32767.5 - 1.0
+}
+
+/// Whether a cell of that material blocks light for the cells around it
+fn occludes(material: Material) -> bool {
+    !matches!(material.get_type(), MaterialType::Gas | MaterialType::Empty)
+}
+
+impl Matrix {
+    /// Brightness multiplier of the cell at pos based on its neighbours: buried cells get darker, cells on the surface brighter
+    pub fn get_shade(&self, pos: IVec2, material: Material, up: IVec2) -> f32 {
+        let shading = material.get_shading();
+        if shading == Shading::NONE {
+            return 1.0;
+        };
+        let mut shade = 1.0 + position_noise(pos) * shading.jitter;
+
+        if shading.occlusion > 0.0 {
+            let mut occupied = 0;
+            for y in -OCCLUSION_RADIUS..=OCCLUSION_RADIUS {
+                for x in -OCCLUSION_RADIUS..=OCCLUSION_RADIUS {
+                    if (x != 0 || y != 0) && occludes(self.get_material(pos + IVec2::new(x, y))) {
+                        occupied += 1;
+                    };
+                };
+            };
+            let num_neighbours = (OCCLUSION_RADIUS * 2 + 1).pow(2) - 1;
+            // A cell lying on a flat surface has about half of its neighbours filled, that shouldn't be darkened yet
+            let enclosed = ((occupied as f32 / num_neighbours as f32 - 0.5) * 2.0).clamp(0.0, 1.0);
+
+            // The local neighbourhood only tells edges apart, the depth makes piles darker towards the bottom
+            let mut depth = 0;
+            while depth < OCCLUSION_DEPTH && occludes(self.get_material(pos + up * (depth + 1))) {
+                depth += 1;
+            };
+            let buried = (enclosed + depth as f32 / OCCLUSION_DEPTH as f32) / 2.0;
+            shade -= buried * shading.occlusion;
+        };
+
+        if shading.highlight > 0.0 && !occludes(self.get_material(pos + up)) {
+            shade += shading.highlight;
+        };
+        shade
+    }
+
+    /// Direction against gravity in whole cells, used to find the surface of piles
+    pub(crate) fn get_shading_up(&self) -> IVec2 {
+        let up = -self.get_gravity_dir();
+        if up == Vec2::ZERO {
+            IVec2::new(0, -1)
+        } else {
+            up.round().as_ivec2()
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+use glam::{IVec2, Vec2};
+use rayon::prelude::*;
+
+use crate::{cell, Cell, Color, Material, Rng, CHUNK_SIZE};
+
+
+/// Bits of CellStorage::flags
+pub const FREE_FALLING: u8 = 1;
+pub const ON_FIRE: u8 = 1 << 1;
+pub const WAS_ON_FIRE: u8 = 1 << 2;
+pub const PROCESSED: u8 = 1 << 3;
+
+/// Packs a colour into RGBA8, the format of the pixel buffer
+pub fn pack_color(color: Color) -> [u8; 4] {
+    // Adding 0.5 rounds, the cast saturates. f64::round is a lot slower and this runs for every cell view
+    [color.r, color.g, color.b, color.a].map(|c| (c * 255.0 + 0.5) as u8)
+}
+
+pub fn unpack_color(color: [u8; 4]) -> Color {
+    const SCALE: f64 = 1.0 / 255.0;
+    Color { r: color[0] as f64 * SCALE, g: color[1] as f64 * SCALE, b: color[2] as f64 * SCALE, a: color[3] as f64 * SCALE }
+}
+
+/// The cells of the grid, stored as one column per property. Every column has one entry per grid position
+/// (index = x + y * width), empty positions are Material::Empty and zeroed everywhere else.
+///
+/// Passes over the whole grid only stream the columns they need, e.g. drawing only reads the colours.
+/// Cell is only a view which gets assembled from and written back into the columns
+pub struct CellStorage {
+    width: usize,
+    pub(crate) material: Vec<Material>,
+    /// Colour the cell is drawn with, RGBA8
+    pub(crate) color: Vec<[u8; 4]>,
+    pub(crate) base_color: Vec<[u8; 4]>,
+    pub(crate) hp: Vec<u16>,
+    /// FREE_FALLING, ON_FIRE, WAS_ON_FIRE and PROCESSED
+    pub(crate) flags: Vec<u8>,
+    pub(crate) velocity: Vec<Vec2>,
+    pub(crate) subpixel: Vec<Vec2>,
+    /// Whole cells moved since the last CellStorage::start_frame (saturating)
+    pub(crate) moved: Vec<[i8; 2]>,
+    /// Glow heat scaled to 0 - u16::MAX
+    pub(crate) heat: Vec<u16>,
+    /// Glow flicker scaled to -127 - 127
+    pub(crate) flicker: Vec<i8>,
+}
+
+impl CellStorage {
+    pub fn new(width: usize, height: usize) -> Self {
+        let len = width * height;
+        CellStorage {
+            width,
+            material: vec![Material::Empty; len],
+            color: vec![[0; 4]; len],
+            base_color: vec![[0; 4]; len],
+            hp: vec![0; len],
+            flags: vec![0; len],
+            velocity: vec![Vec2::ZERO; len],
+            subpixel: vec![Vec2::ZERO; len],
+            moved: vec![[0; 2]; len],
+            heat: vec![0; len],
+            flicker: vec![0; len],
+        }
+    }
+
+    /// Number of grid positions (not cells)
+    pub fn len(&self) -> usize {
+        self.material.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.material.is_empty()
+    }
+
+    pub fn pos_of(&self, index: usize) -> IVec2 {
+        IVec2::new((index % self.width) as i32, (index / self.width) as i32)
+    }
+
+    pub fn is_occupied(&self, index: usize) -> bool {
+        self.material[index] != Material::Empty
+    }
+
+    /// Assembles the cell at index, None if the position is empty
+    pub fn get(&self, index: usize) -> Option<Cell> {
+        if !self.is_occupied(index) {
+            return None;
+        };
+        let flags = self.flags[index];
+        Some(Cell {
+            pos: self.pos_of(index),
+            velocity: self.velocity[index],
+            subpixel: self.subpixel[index],
+            hp: self.hp[index],
+            base_color: unpack_color(self.base_color[index]),
+            color: unpack_color(self.color[index]),
+            material: self.material[index],
+            processed_this_frame: flags & PROCESSED != 0,
+            is_free_falling: flags & FREE_FALLING != 0,
+            is_on_fire: flags & ON_FIRE != 0,
+            was_on_fire_last_frame: flags & WAS_ON_FIRE != 0,
+            heat: self.heat[index] as f32 / u16::MAX as f32,
+            flicker: self.flicker[index] as f32 / 127.0,
+        })
+    }
+
+    /// Writes the cell into the columns at index. The position of the cell is ignored, the index decides where it goes
+    pub fn set(&mut self, index: usize, cell: &Cell) {
+        if cell.material == Material::Empty {
+            self.clear(index);
+            return;
+        };
+        let flags = [(cell.processed_this_frame, PROCESSED), (cell.is_free_falling, FREE_FALLING), (cell.is_on_fire, ON_FIRE), (cell.was_on_fire_last_frame, WAS_ON_FIRE)]
+            .iter()
+            .filter(|(set, _)| *set)
+            .fold(0, |flags, (_, bit)| flags | bit);
+        self.material[index] = cell.material;
+        self.color[index] = pack_color(cell.color);
+        self.base_color[index] = pack_color(cell.base_color);
+        self.hp[index] = cell.hp;
+        self.flags[index] = flags;
+        self.velocity[index] = cell.velocity;
+        self.subpixel[index] = cell.subpixel;
+        self.heat[index] = (cell.heat * u16::MAX as f32 + 0.5) as u16;
+        self.flicker[index] = (cell.flicker * 127.0 + 0.5f32.copysign(cell.flicker)) as i8;
+    }
+
+    /// Empties the position
+    pub fn clear(&mut self, index: usize) {
+        self.material[index] = Material::Empty;
+        self.color[index] = [0; 4];
+        self.base_color[index] = [0; 4];
+        self.hp[index] = 0;
+        self.flags[index] = 0;
+        self.velocity[index] = Vec2::ZERO;
+        self.subpixel[index] = Vec2::ZERO;
+        self.moved[index] = [0; 2];
+        self.heat[index] = 0;
+        self.flicker[index] = 0;
+    }
+
+    /// Exchanges everything stored at both positions
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.material.swap(a, b);
+        self.color.swap(a, b);
+        self.base_color.swap(a, b);
+        self.hp.swap(a, b);
+        self.flags.swap(a, b);
+        self.velocity.swap(a, b);
+        self.subpixel.swap(a, b);
+        self.moved.swap(a, b);
+        self.heat.swap(a, b);
+        self.flicker.swap(a, b);
+    }
+
+    /// Moves the cell from one position to the other, overwriting whatever was there
+    pub fn move_cell(&mut self, from: usize, to: usize) {
+        self.swap(from, to);
+        self.clear(from);
+    }
+
+    /// Adds offset to how far the cell at index moved this frame
+    pub fn add_moved(&mut self, index: usize, offset: IVec2) {
+        let moved = &mut self.moved[index];
+        moved[0] = (moved[0] as i32 + offset.x).clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+        moved[1] = (moved[1] as i32 + offset.y).clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+    }
+
+    /// Cell::update for cells which are neither burning nor glowing, those only get accelerated.
+    /// Returns false if the cell needs the full update
+    pub fn update_cold(&mut self, index: usize, acceleration: Vec2) -> bool {
+        if self.flags[index] & (ON_FIRE | WAS_ON_FIRE) != 0 || self.heat[index] != 0 {
+            return false;
+        };
+        self.velocity[index] = cell::accelerate(self.velocity[index], acceleration, self.material[index]);
+        true
+    }
+
+    /// Cell::integrate without assembling the cell
+    pub fn integrate(&mut self, index: usize) -> IVec2 {
+        cell::integrate(self.velocity[index], &mut self.subpixel[index])
+    }
+
+    /// Cell::stop without assembling the cell
+    pub fn stop(&mut self, index: usize) {
+        self.velocity[index] = Vec2::ZERO;
+        self.subpixel[index] = Vec2::ZERO;
+    }
+
+    /// Cell::attempt_free_fall without assembling the cell
+    pub fn attempt_free_fall(&mut self, index: usize, rng: &Rng) {
+        if cell::starts_falling(self.material[index], rng) {
+            self.flags[index] |= FREE_FALLING;
+        };
+    }
+
+    /// Tells every cell that a new frame has begun: clears PROCESSED and decides whether the cell is free falling.
+    ///
+    /// The cell counts as free falling when it moved along the gravity direction (or at all, without gravity)
+    pub fn start_frame(&mut self, gravity_dir: Vec2) {
+        self.flags.par_chunks_mut(self.width * CHUNK_SIZE).zip(self.moved.par_chunks_mut(self.width * CHUNK_SIZE)).for_each(|(flags, moved)| {
+            flags.iter_mut().for_each(|flags| *flags &= !(PROCESSED | FREE_FALLING));
+            // Most cells didn't move, whole blocks of them can be skipped at once
+            for (flags, moved) in flags.chunks_mut(64).zip(moved.chunks_mut(64)) {
+                if moved.iter().all(|m| *m == [0; 2]) {
+                    continue;
+                };
+                for (flags, moved) in flags.iter_mut().zip(moved.iter_mut()) {
+                    let offset = Vec2::new(moved[0] as f32, moved[1] as f32);
+                    if offset != Vec2::ZERO && (gravity_dir == Vec2::ZERO || offset.dot(gravity_dir).abs() > 0.1) {
+                        *flags |= FREE_FALLING;
+                    };
+                    *moved = [0; 2];
+                };
+            };
+        });
+    }
+}
+
+/// Read only view of a cell, see Matrix::get_cell
+pub struct CellRef<'a> {
+    cell: Cell,
+    storage: PhantomData<&'a CellStorage>,
+}
+
+impl<'a> CellRef<'a> {
+    pub(crate) fn new(cell: Cell) -> Self {
+        CellRef { cell, storage: PhantomData }
+    }
+}
+
+impl Deref for CellRef<'_> {
+    type Target = Cell;
+
+    fn deref(&self) -> &Cell {
+        &self.cell
+    }
+}
+
+/// Mutable view of a cell, see Matrix::get_cell_mut. Changes get written back into the storage when the view is dropped.
+///
+/// Changing the position of the cell has no effect, cells get moved with Matrix::set_cell_by_pos
+pub struct CellMut<'a> {
+    storage: &'a mut CellStorage,
+    index: usize,
+    cell: Cell,
+}
+
+impl<'a> CellMut<'a> {
+    pub(crate) fn new(storage: &'a mut CellStorage, index: usize) -> Option<Self> {
+        let cell = storage.get(index)?;
+        Some(CellMut { storage, index, cell })
+    }
+}
+
+impl Deref for CellMut<'_> {
+    type Target = Cell;
+
+    fn deref(&self) -> &Cell {
+        &self.cell
+    }
+}
+
+impl DerefMut for CellMut<'_> {
+    fn deref_mut(&mut self) -> &mut Cell {
+        &mut self.cell
+    }
+}
+
+impl Drop for CellMut<'_> {
+    fn drop(&mut self) {
+        self.storage.set(self.index, &self.cell);
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::{HashSet, VecDeque};
+
+use glam::{IVec2, Vec2};
+use strum_macros::EnumIter;
+
+use crate::{BoundaryMode, Material, MaterialType, Matrix, SimEvent, gen_range};
+
+
+/// Clusters bigger than this are always treated as supported, so a single removed cell can't stall the frame
+pub const MAX_CLUSTER_SIZE: usize = 8192;
+
+const NEIGHBORS: [IVec2; 4] = [IVec2::new(1, 0), IVec2::new(-1, 0), IVec2::new(0, 1), IVec2::new(0, -1)];
+
+/// What happens to solid clusters that lost their support
+#[derive(Clone, Copy, PartialEq, Eq, EnumIter, Debug)]
+pub enum CollapseMode {
+    /// Every cell of the cluster breaks off as a free flying particle
+    Debris,
+    /// The cluster falls down as one piece
+    Rigid,
+}
+
+/// A cluster of solid cells which falls as one piece until it hits something
+pub struct RigidCluster {
+    cells: Vec<IVec2>,
+    velocity: f32,
+}
+
+impl RigidCluster {
+    pub fn new(cells: Vec<IVec2>) -> Self {
+        RigidCluster {
+            cells,
+            velocity: 0.0,
+        }
+    }
+
+    pub fn get_cells(&self) -> &[IVec2] {
+        &self.cells
+    }
+
+    /// Moves the cluster along gravity. Returns false once the cluster came to rest (or is gone)
+    pub fn step(&mut self, matrix: &mut Matrix) -> bool {
+        let gravity = matrix.get_gravity();
+        if gravity == Vec2::ZERO || self.cells.is_empty() {
+            return false;
+        };
+        self.velocity += gravity.length();
+        let step = gravity.normalize().round().as_ivec2();
+        for _ in 0..(self.velocity.floor() as i32).max(1) {
+            if !self.move_by(matrix, step) {
+                return false;
+            };
+        };
+        true
+    }
+
+    /// Moves every cell of the cluster by offset, displacing liquids and gases. Returns false if the cluster is blocked
+    fn move_by(&mut self, matrix: &mut Matrix, offset: IVec2) -> bool {
+        let own_cells: HashSet<IVec2> = self.cells.iter().copied().collect();
+        let mut falls_into_void = vec![];
+        for (i, pos) in self.cells.iter().enumerate() {
+            let target = match matrix.wrap_pos(*pos + offset) {
+                Some(target) => target,
+                None => {
+                    if matrix.get_boundary_mode(*pos + offset) != Some(BoundaryMode::Void) {
+                        return false;
+                    };
+                    falls_into_void.push(i);
+                    continue;
+                },
+            };
+            if own_cells.contains(&target) {
+                continue;
+            };
+            if let Some(cell) = matrix.get_cell(target) {
+                if matches!(cell.material.get_type(), MaterialType::Solid | MaterialType::MovableSolid) {
+                    return false;
+                };
+            };
+        };
+
+        // Move the cells at the front first, so every cell moves into a spot that has already been vacated
+        let mut order: Vec<usize> = (0..self.cells.len()).collect();
+        order.sort_by_key(|i| -(self.cells[*i].x * offset.x + self.cells[*i].y * offset.y));
+        let mut moved = Vec::with_capacity(self.cells.len());
+        for i in order {
+            let pos = self.cells[i];
+            if falls_into_void.contains(&i) {
+                if let Some(material) = matrix.get_cell(pos).map(|c| c.material) {
+                    matrix.set_cell_material(pos, Material::Empty, false);
+                    matrix.push_event(SimEvent::CellDestroyed { pos, material });
+                };
+                continue;
+            };
+            let target = matrix.wrap_pos(pos + offset).unwrap();
+            matrix.set_cell_by_pos(target, pos, true);
+            moved.push(target);
+        };
+        self.cells = moved;
+        !self.cells.is_empty()
+    }
+}
+
+/// Whether the cell at pos holds the cluster in place: it lies on the world floor (the wall edge gravity points at)
+/// or is made of an anchor material
+fn is_anchor(matrix: &Matrix, pos: IVec2, material: Material, gravity_dir: Vec2) -> bool {
+    if material.is_structural_anchor() {
+        return true;
+    };
+    let below = pos + gravity_dir.round().as_ivec2();
+    matrix.get_boundary_mode(below) == Some(BoundaryMode::Wall)
+}
+
+/// Flood fills the solid cells connected to start. Returns the cluster if it isn't supported by anything.
+///
+/// Every visited position is added to visited, so clusters touched by several removed cells are only checked once
+pub fn find_unsupported_cluster(matrix: &Matrix, start: IVec2, visited: &mut HashSet<IVec2>) -> Option<Vec<IVec2>> {
+    let gravity_dir = matrix.get_gravity_dir();
+    if gravity_dir == Vec2::ZERO || visited.contains(&start) {
+        return None;
+    };
+    match matrix.get_cell(start) {
+        Some(cell) if cell.material.get_type() == MaterialType::Solid => {},
+        _ => return None,
+    };
+
+    let mut cluster = vec![];
+    let mut queue = VecDeque::from([start]);
+    visited.insert(start);
+    while let Some(pos) = queue.pop_front() {
+        let material = matrix.get_cell(pos).unwrap().material;
+        if is_anchor(matrix, pos, material, gravity_dir) || cluster.len() >= MAX_CLUSTER_SIZE {
+            return None;
+        };
+        cluster.push(pos);
+        for offset in NEIGHBORS {
+            let n_pos = match matrix.wrap_pos(pos + offset) {
+                Some(n_pos) => n_pos,
+                None => continue,
+            };
+            if visited.contains(&n_pos) {
+                continue;
+            };
+            if let Some(n_cell) = matrix.get_cell(n_pos) {
+                if n_cell.material.get_type() == MaterialType::Solid {
+                    visited.insert(n_pos);
+                    queue.push_back(n_pos);
+                };
+            };
+        };
+    };
+    Some(cluster)
+}
+
+/// Lets every cell of the cluster break off as a particle which gets placed back into the world where it lands
+pub fn crumble(matrix: &mut Matrix, cluster: &[IVec2]) {
+    let gravity_dir = matrix.get_gravity_dir();
+    for pos in cluster {
+        let velocity = gravity_dir * gen_range(matrix.get_rng(), 0.2, 0.5) + gravity_dir.perp() * gen_range(matrix.get_rng(), -0.3, 0.6);
+        matrix.detach_cell(*pos, velocity);
+    };
+}